@@ -28,16 +28,99 @@ fn bench(c: &mut Criterion) {
             };
             let req = hiisi::executor::Request {
                 database: "test".to_string(),
+                version: hiisi::proto::Version::Hrana3,
                 req,
             };
-            hiisi::executor::execute_client_req(manager.clone(), req).unwrap();
+            let mut pipeline =
+                hiisi::pipeline::Pipeline::open(manager.clone(), req, hiisi::proto::Encoding::Json)
+                    .unwrap();
+            let mut body = bytes::BytesMut::new();
+            pipeline.write_body(&mut body, usize::MAX).unwrap();
         });
     });
 }
 
+fn bench_encoding(c: &mut Criterion) {
+    use hiisi::http::{self, StatusCode};
+    use hiisi::proto::{self, Encoding};
+
+    let mut group = c.benchmark_group("encoding");
+
+    let mut stmt = proto::Stmt::new("INSERT INTO users VALUES (?, ?, ?)", false);
+    stmt.bind(proto::Value::Integer { value: 1 });
+    stmt.bind(proto::Value::Text {
+        value: "Pekka".into(),
+    });
+    stmt.bind(proto::Value::Blob {
+        value: vec![0u8; 64].into(),
+    });
+    let req = proto::PipelineReqBody {
+        baton: None,
+        requests: vec![proto::StreamRequest::Execute(proto::ExecuteStreamReq {
+            stmt,
+        })],
+    };
+
+    let rows = (0..100)
+        .map(|i| proto::Row {
+            values: vec![
+                proto::Value::Integer { value: i },
+                proto::Value::Text {
+                    value: format!("user{}", i).into(),
+                },
+                proto::Value::Float { value: i as f64 },
+            ],
+        })
+        .collect();
+    let cols = ["id", "name", "score"]
+        .iter()
+        .map(|name| proto::Col {
+            name: Some(name.to_string()),
+            decltype: None,
+        })
+        .collect();
+    let result = proto::StmtResult {
+        cols,
+        rows,
+        ..Default::default()
+    };
+    let resp = proto::PipelineRespBody {
+        baton: None,
+        base_url: None,
+        results: vec![proto::StreamResult::Ok {
+            response: proto::StreamResponse::Execute(proto::ExecuteStreamResp { result }),
+        }],
+    };
+
+    for (name, encoding) in [("json", Encoding::Json), ("protobuf", Encoding::Protobuf)] {
+        let msg = proto::encode_msg(&req, encoding).unwrap();
+        group.bench_function(format!("parse_request_{}", name), |b| {
+            b.iter(|| proto::parse_client_req(&msg, encoding).unwrap());
+        });
+        group.bench_function(format!("format_response_{}", name), |b| {
+            b.iter(|| proto::encode_msg(&resp, encoding).unwrap());
+        });
+        // The whole HTTP response, with the body copied after the head...
+        group.bench_function(format!("format_http_response_{}", name), |b| {
+            b.iter(|| {
+                let body = proto::encode_msg(&resp, encoding).unwrap();
+                http::format_response(body, StatusCode::OK, encoding.content_type(), true)
+            });
+        });
+        // ...and with the head written in front of the body.
+        group.bench_function(format!("format_http_response_in_place_{}", name), |b| {
+            b.iter(|| {
+                let mut buf = http::response_buf();
+                proto::write_msg(&resp, encoding, &mut buf).unwrap();
+                http::format_response_in_place(buf, StatusCode::OK, encoding.content_type(), true)
+            });
+        });
+    }
+}
+
 criterion_group! {
     name = benches;
     config = Criterion::default().with_profiler(PProfProfiler::new(100, Output::Flamegraph(None)));
-    targets = bench
+    targets = bench, bench_encoding
 }
 criterion_main!(benches);
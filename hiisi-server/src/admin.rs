@@ -30,10 +30,14 @@ fn on_recv<T>(io: &mut IO<T>, sock: Rc<Socket>, buf: &[u8], n: usize) {
         return;
     }
     let resp = match execute_request(io, &buf[..n]) {
-        Ok(resp) => http::format_response(resp, http::StatusCode::OK),
+        Ok(resp) => {
+            http::format_response(resp, http::StatusCode::OK, http::TEXT_CONTENT_TYPE, true)
+        }
         Err(x) => http::format_response(
             format!("{}", x).into(),
             http::StatusCode::INTERNAL_SERVER_ERROR,
+            http::TEXT_CONTENT_TYPE,
+            true,
         ),
     };
 
@@ -0,0 +1,107 @@
+//! Batons that identify HTTP streams.
+//!
+//! A baton encodes the id of a stream and a sequence number, authenticated
+//! with a HMAC of a key that is generated when the server starts. The server
+//! issues a new baton with the next sequence number in every response, so a
+//! client that does not know the key can neither forge a baton for another
+//! stream nor replay a request with a baton that has already been used.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{HiisiError, Result};
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Length of the HMAC key in bytes.
+const KEY_LEN: usize = 32;
+
+// Length of the stream id and sequence number in bytes.
+const PAYLOAD_LEN: usize = 16;
+
+// Length of the HMAC-SHA256 tag in bytes.
+const TAG_LEN: usize = 32;
+
+/// Identifies a stream across the HTTP requests of a client.
+pub type StreamId = u64;
+
+pub struct BatonKey {
+    key: [u8; KEY_LEN],
+}
+
+impl BatonKey {
+    /// Generate a random key.
+    pub fn generate() -> Self {
+        let mut key = [0; KEY_LEN];
+        getrandom::getrandom(&mut key).expect("Failed to generate baton key");
+        Self { key }
+    }
+
+    /// Encode a baton for a stream at a sequence number.
+    pub fn encode(&self, stream_id: StreamId, seq: u64) -> String {
+        let mut baton = Vec::with_capacity(PAYLOAD_LEN + TAG_LEN);
+        baton.extend_from_slice(&stream_id.to_be_bytes());
+        baton.extend_from_slice(&seq.to_be_bytes());
+        let tag = self.mac(&baton).finalize().into_bytes();
+        baton.extend_from_slice(&tag);
+        URL_SAFE_NO_PAD.encode(baton)
+    }
+
+    /// Decode a baton into the stream id and sequence number, failing if the
+    /// baton was not issued with this key.
+    pub fn decode(&self, baton: &str) -> Result<(StreamId, u64)> {
+        let baton = URL_SAFE_NO_PAD
+            .decode(baton)
+            .map_err(|_| HiisiError::BatonInvalid("Baton is not valid base64".to_string()))?;
+        if baton.len() != PAYLOAD_LEN + TAG_LEN {
+            return Err(HiisiError::BatonInvalid(
+                "Baton has an invalid length".to_string(),
+            ));
+        }
+        let (payload, tag) = baton.split_at(PAYLOAD_LEN);
+        // The tag is compared in constant time.
+        self.mac(payload)
+            .verify_slice(tag)
+            .map_err(|_| HiisiError::BatonInvalid("Baton has an invalid signature".to_string()))?;
+        let stream_id = u64::from_be_bytes(payload[..8].try_into().unwrap());
+        let seq = u64::from_be_bytes(payload[8..].try_into().unwrap());
+        Ok((stream_id, seq))
+    }
+
+    fn mac(&self, payload: &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).unwrap();
+        mac.update(payload);
+        mac
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let key = BatonKey::generate();
+        let baton = key.encode(42, 7);
+        assert_eq!(key.decode(&baton).unwrap(), (42, 7));
+        assert_ne!(baton, key.encode(42, 8));
+    }
+
+    #[test]
+    fn reject_forged_batons() {
+        let key = BatonKey::generate();
+        let baton = key.encode(42, 7);
+        // A baton issued with another key.
+        let other_key = BatonKey::generate();
+        assert!(other_key.decode(&baton).is_err());
+        // A baton with a modified payload.
+        let mut bytes = URL_SAFE_NO_PAD.decode(&baton).unwrap();
+        bytes[7] ^= 1;
+        assert!(key.decode(&URL_SAFE_NO_PAD.encode(&bytes)).is_err());
+        // Batons that are not even well-formed.
+        assert!(key.decode("").is_err());
+        assert!(key.decode("not a baton!").is_err());
+        assert!(key.decode(&uuid::Uuid::new_v4().to_string()).is_err());
+    }
+}
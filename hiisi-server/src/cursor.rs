@@ -0,0 +1,277 @@
+//! Cursors for streaming batch results.
+
+use std::rc::Rc;
+
+use crate::baton::StreamId;
+use crate::database::{StepResult, Stmt};
+use crate::executor::{self, ChangeCounters, StepOutcome};
+use crate::manager::ResourceManager;
+use crate::proto;
+use crate::stream::Stream;
+use crate::Result;
+
+pub struct CursorRequest {
+    pub database: String,
+    pub version: proto::Version,
+    pub req: proto::CursorReqBody,
+}
+
+impl CursorRequest {
+    /// Returns the write that the request waits with if it must wait for
+    /// another stream to finish its write transaction before the cursor is
+    /// opened.
+    pub(crate) fn pending_write(
+        &self,
+        manager: &ResourceManager,
+    ) -> Option<executor::PendingWrite> {
+        let sqls = executor::batch_sqls(&self.req.batch);
+        executor::pending_write(manager, &self.database, self.req.baton.as_deref(), sqls)
+    }
+}
+
+/// A cursor executes a batch one step at a time and produces the results as
+/// a sequence of cursor entries, which lets us send rows to the client as
+/// they are produced instead of buffering the whole result.
+pub struct Cursor {
+    /// The statement of the step that is currently being executed.
+    ///
+    /// This field is declared before `stream` so that the statement is
+    /// finalized before the connection it was prepared on is dropped.
+    stmt: Option<Stmt>,
+    column_count: i32,
+    want_rows: bool,
+    /// The change counters of the connection before the current step.
+    changes: ChangeCounters,
+    stream: Rc<Stream>,
+    /// The manager and id of the HTTP stream that the cursor executes on,
+    /// which is updated when the cursor finishes, and the baton that the
+    /// client must send in its next request on the stream.
+    http_stream: Option<(Rc<ResourceManager>, StreamId, Option<String>)>,
+    batch: proto::Batch,
+    /// The index of the step that is executed next.
+    step: usize,
+    outcomes: Vec<StepOutcome>,
+    done: bool,
+}
+
+impl Cursor {
+    /// Open a cursor on the HTTP stream identified by the baton of the
+    /// request.
+    pub fn open(manager: Rc<ResourceManager>, req: CursorRequest) -> Result<Self> {
+        executor::check_batch(&req.req.batch, req.version)?;
+        let stream_id =
+            executor::request_stream(&manager, &req.database, req.req.baton.as_deref())?;
+        let stream = manager.get_stream(&req.database, stream_id)?;
+        // The baton is sent before the cursor is executed, so that the client
+        // can use the stream as soon as it has received the whole response.
+        let baton = manager.next_baton(stream_id);
+        manager.begin_response(stream_id);
+        let mut cursor = Self::new(stream, req.req.batch);
+        cursor.http_stream = Some((manager, stream_id, baton));
+        Ok(cursor)
+    }
+
+    pub fn new(stream: Rc<Stream>, batch: proto::Batch) -> Self {
+        let outcomes = Vec::with_capacity(batch.steps.len());
+        Self {
+            stmt: None,
+            column_count: 0,
+            want_rows: true,
+            changes: ChangeCounters::default(),
+            stream,
+            http_stream: None,
+            batch,
+            step: 0,
+            outcomes,
+            done: false,
+        }
+    }
+
+    /// Returns the response body that precedes the cursor entries.
+    pub fn resp_body(&self) -> proto::CursorRespBody {
+        proto::CursorRespBody {
+            baton: self
+                .http_stream
+                .as_ref()
+                .and_then(|(_, _, baton)| baton.clone()),
+            base_url: None,
+        }
+    }
+
+    /// Returns the next cursor entry, or `None` if the cursor is exhausted.
+    pub fn next_entry(&mut self) -> Option<proto::CursorEntry> {
+        if self.done {
+            return None;
+        }
+        let entry = match self.stmt {
+            Some(_) => Some(self.step_stmt()),
+            None => self.begin_step(),
+        };
+        if entry.is_none() || matches!(entry, Some(proto::CursorEntry::Error { .. })) {
+            self.finish();
+        }
+        entry
+    }
+
+    fn step_stmt(&mut self) -> proto::CursorEntry {
+        let stmt = self.stmt.as_ref().unwrap();
+        loop {
+            match stmt.step() {
+                Ok(StepResult::Row) if !self.want_rows => {}
+                Ok(StepResult::Row) => match executor::to_row(stmt, self.column_count) {
+                    Ok(row) => return proto::CursorEntry::Row { row },
+                    Err(err) => return self.end_step(Err(err)),
+                },
+                Ok(StepResult::Done) => return self.end_step(Ok(())),
+                Err(err) => return self.end_step(Err(err)),
+            }
+        }
+    }
+
+    fn begin_step(&mut self) -> Option<proto::CursorEntry> {
+        loop {
+            let step = self.batch.steps.get(self.step)?;
+            let enabled = match &step.condition {
+                Some(cond) => executor::eval_cond(&self.stream, cond, &self.outcomes),
+                None => Ok(true),
+            };
+            match enabled {
+                Ok(true) => {}
+                Ok(false) => {
+                    self.outcomes.push(StepOutcome::Skipped);
+                    self.step += 1;
+                    continue;
+                }
+                Err(err) => {
+                    return Some(proto::CursorEntry::Error {
+                        error: executor::to_proto_error(&err),
+                    })
+                }
+            }
+            let stmt = executor::prepare_stmt(&self.stream, &step.stmt)
+                .and_then(|stmt| Ok((executor::stmt_cols(&stmt)?, stmt)));
+            let want_rows = step.stmt.want_rows.unwrap_or(true);
+            return match stmt {
+                Ok((cols, stmt)) => {
+                    self.column_count = stmt.column_count();
+                    self.want_rows = want_rows;
+                    self.changes = ChangeCounters::new(&self.stream.conn);
+                    self.stmt = Some(stmt);
+                    Some(proto::CursorEntry::StepBegin(proto::StepBeginEntry {
+                        step: self.step as u32,
+                        cols,
+                    }))
+                }
+                Err(err) => Some(self.end_step(Err(err))),
+            };
+        }
+    }
+
+    fn end_step(&mut self, result: Result<()>) -> proto::CursorEntry {
+        self.stmt = None;
+        let step = self.step as u32;
+        self.step += 1;
+        match result {
+            Ok(()) => {
+                self.outcomes.push(StepOutcome::Ok);
+                let (affected_row_count, last_insert_rowid) =
+                    self.changes.stmt_changes(&self.stream.conn);
+                proto::CursorEntry::StepEnd(proto::StepEndEntry {
+                    affected_row_count,
+                    last_insert_rowid,
+                })
+            }
+            Err(err) => {
+                self.outcomes.push(StepOutcome::Error);
+                proto::CursorEntry::StepError(proto::StepErrorEntry {
+                    step,
+                    error: executor::to_proto_error(&err),
+                })
+            }
+        }
+    }
+
+    fn finish(&mut self) {
+        self.stmt = None;
+        self.done = true;
+        if let Some((manager, stream_id, _)) = &self.http_stream {
+            manager.update_stream(*stream_id);
+        }
+    }
+}
+
+impl Drop for Cursor {
+    fn drop(&mut self) {
+        if let Some((manager, stream_id, _)) = &self.http_stream {
+            manager.end_response(*stream_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::temp_manager;
+
+    fn entry_types(cursor: &mut Cursor) -> Vec<String> {
+        let mut types = Vec::new();
+        while let Some(entry) = cursor.next_entry() {
+            let entry = serde_json::to_value(&entry).unwrap();
+            types.push(entry["type"].as_str().unwrap().to_string());
+        }
+        types
+    }
+
+    #[test]
+    fn cursor_entries() {
+        let manager = temp_manager();
+        let batch = proto::Batch::from_iter([
+            proto::Stmt::new("CREATE TABLE t (x INTEGER)", false),
+            proto::Stmt::new("INSERT INTO t VALUES (1), (2)", false),
+            proto::Stmt::new("SELECT x FROM t", true),
+            proto::Stmt::new("SELECT * FROM no_such_table", true),
+            proto::Stmt::new("SELECT 1", true),
+        ]);
+        let req = CursorRequest {
+            database: "test".to_string(),
+            version: proto::Version::Hrana3,
+            req: proto::CursorReqBody { baton: None, batch },
+        };
+        let mut cursor = Cursor::open(manager.clone(), req).unwrap();
+        assert_eq!(
+            entry_types(&mut cursor),
+            vec![
+                "step_begin",
+                "step_end",
+                "step_begin",
+                "step_end",
+                "step_begin",
+                "row",
+                "row",
+                "step_end",
+                "step_error",
+            ]
+        );
+        assert!(cursor.next_entry().is_none());
+    }
+
+    #[test]
+    fn step_end_changes() {
+        let manager = temp_manager();
+        let stream = Rc::new(Stream::new(manager.connect("test").unwrap()));
+        let batch = proto::Batch::from_iter([
+            proto::Stmt::new("CREATE TABLE t (x INTEGER)", false),
+            proto::Stmt::new("INSERT INTO t VALUES (1), (2)", false),
+            proto::Stmt::new("UPDATE t SET x = 3 WHERE x = 2", false),
+            proto::Stmt::new("CREATE TABLE u (x INTEGER)", false),
+        ]);
+        let mut cursor = Cursor::new(stream, batch);
+        let mut changes = Vec::new();
+        while let Some(entry) = cursor.next_entry() {
+            if let proto::CursorEntry::StepEnd(entry) = entry {
+                changes.push((entry.affected_row_count, entry.last_insert_rowid));
+            }
+        }
+        assert_eq!(changes, vec![(0, None), (2, Some(2)), (1, None), (0, None)]);
+    }
+}
@@ -1,6 +1,7 @@
 use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
-use crate::error::HiisiError;
+use crate::error::{HiisiError, SqliteError};
 use crate::Result;
 
 pub struct Database {
@@ -12,12 +13,19 @@ impl Database {
         Self { path }
     }
 
-    pub fn connect(&self) -> Result<Connection> {
-        Connection::open(&self.path)
+    /// Open a connection to the database. The connection keeps a reference
+    /// to the database, so the number of references tells whether the
+    /// database has open connections.
+    pub fn connect(self: &Rc<Self>) -> Result<Connection> {
+        let mut conn = Connection::open(&self.path)?;
+        conn._db = Some(self.clone());
+        Ok(conn)
     }
 }
 pub struct Connection {
     conn: *mut libsql_ffi::sqlite3,
+    /// The database that the connection was opened through, if any.
+    _db: Option<Rc<Database>>,
 }
 
 impl Drop for Connection {
@@ -38,42 +46,110 @@ impl Connection {
         let rc =
             unsafe { libsql_ffi::sqlite3_open_v2(path.as_ptr(), &mut conn, flags.into(), vfs) };
         if rc != libsql_ffi::SQLITE_OK {
-            return Err(HiisiError::SqliteError(rc));
+            let err = sqlite_error(conn, rc, None);
+            // SQLite allocates a connection handle even when opening fails.
+            unsafe { libsql_ffi::sqlite3_close(conn) };
+            return Err(err);
         }
-        Ok(Self { conn })
+        unsafe { libsql_ffi::sqlite3_extended_result_codes(conn, 1) };
+        Ok(Self { conn, _db: None })
     }
 
+    /// Prepare a statement from SQL text that contains exactly one statement.
     pub fn prepare(&self, sql: &str) -> Result<Stmt> {
+        let (stmt, tail) = self.prepare_first(sql)?;
+        let stmt = stmt.ok_or(HiisiError::SqlNoStatement)?;
+        if self.prepare_first(tail)?.0.is_some() {
+            return Err(HiisiError::SqlManyStatements);
+        }
+        Ok(stmt)
+    }
+
+    /// Prepare the first statement of SQL text, returning the statement and
+    /// the rest of the text that follows it.
+    ///
+    /// The statement is `None` if the text contains only whitespace or
+    /// comments.
+    pub fn prepare_first<'a>(&self, sql: &'a str) -> Result<(Option<Stmt>, &'a str)> {
+        if sql.contains('\0') {
+            return Err(HiisiError::ProtocolError(
+                "SQL contains a NUL byte".to_string(),
+            ));
+        }
         let mut stmt = std::ptr::null_mut();
-        let sql = std::ffi::CString::new(sql).unwrap();
+        let mut tail = std::ptr::null();
+        let len = i32::try_from(sql.len())
+            .map_err(|_| HiisiError::ProtocolError("SQL text is too long".to_string()))?;
         let rc = unsafe {
             libsql_ffi::sqlite3_prepare_v2(
                 self.conn,
-                sql.as_ptr(),
-                -1,
+                sql.as_ptr() as *const std::ffi::c_char,
+                len,
                 &mut stmt,
-                std::ptr::null_mut(),
+                &mut tail,
             )
         };
         if rc != libsql_ffi::SQLITE_OK {
-            return Err(HiisiError::SqliteError(rc));
+            return Err(sqlite_error(self.conn, rc, Some(sql)));
         }
-        Ok(Stmt { stmt })
+        let consumed = if tail.is_null() {
+            sql.len()
+        } else {
+            tail as usize - sql.as_ptr() as usize
+        };
+        let stmt = if stmt.is_null() {
+            None
+        } else {
+            Some(Stmt { stmt })
+        };
+        Ok((stmt, &sql[consumed..]))
+    }
+
+    /// Returns `true` if the connection is in autocommit mode, which means
+    /// there is no transaction open.
+    pub fn is_autocommit(&self) -> bool {
+        unsafe { libsql_ffi::sqlite3_get_autocommit(self.conn) != 0 }
+    }
+
+    /// Returns `true` if the connection holds a write transaction, which
+    /// means that no other connection can write to the database until the
+    /// transaction finishes.
+    pub fn is_writing(&self) -> bool {
+        let state = unsafe { libsql_ffi::sqlite3_txn_state(self.conn, std::ptr::null()) };
+        state == libsql_ffi::SQLITE_TXN_WRITE
+    }
+
+    /// Returns the number of rows modified by the most recently completed
+    /// `INSERT`, `UPDATE`, or `DELETE` statement.
+    pub fn changes(&self) -> u64 {
+        unsafe { libsql_ffi::sqlite3_changes64(self.conn) as u64 }
+    }
+
+    /// Returns the number of rows modified by all `INSERT`, `UPDATE`, or
+    /// `DELETE` statements since the connection was opened.
+    pub fn total_changes(&self) -> u64 {
+        unsafe { libsql_ffi::sqlite3_total_changes64(self.conn) as u64 }
+    }
+
+    /// Returns the rowid of the most recent successful `INSERT`.
+    pub fn last_insert_rowid(&self) -> i64 {
+        unsafe { libsql_ffi::sqlite3_last_insert_rowid(self.conn) }
     }
 
     pub fn pragma(&self, name: &str, value: impl Into<String>) -> Result<()> {
-        let name = std::ffi::CString::new(name).unwrap();
+        let sql = format!("PRAGMA {}={}", name, value.into());
+        let c_sql = std::ffi::CString::new(sql.as_str()).unwrap();
         let rc = unsafe {
             libsql_ffi::sqlite3_exec(
                 self.conn,
-                format!("PRAGMA {}={}", name.to_str().unwrap(), value.into()).as_ptr() as *const i8,
+                c_sql.as_ptr(),
                 None,
                 std::ptr::null_mut(),
                 std::ptr::null_mut(),
             )
         };
         if rc != libsql_ffi::SQLITE_OK {
-            return Err(HiisiError::SqliteError(rc));
+            return Err(sqlite_error(self.conn, rc, Some(&sql)));
         }
         Ok(())
     }
@@ -103,13 +179,134 @@ impl Drop for Stmt {
 }
 
 impl Stmt {
+    /// Returns the index of the largest parameter in the statement.
+    pub fn bind_parameter_count(&self) -> i32 {
+        unsafe { libsql_ffi::sqlite3_bind_parameter_count(self.stmt) }
+    }
+
+    /// Returns the index of a named parameter, including its prefix
+    /// character (for example, `:name`), or `None` if there is no such
+    /// parameter.
+    pub fn bind_parameter_index(&self, name: &str) -> Option<i32> {
+        let name = std::ffi::CString::new(name).ok()?;
+        let index = unsafe { libsql_ffi::sqlite3_bind_parameter_index(self.stmt, name.as_ptr()) };
+        if index == 0 {
+            return None;
+        }
+        Some(index)
+    }
+
+    /// Returns the name of a parameter, or `None` if the parameter is
+    /// nameless (for example, `?`).
+    pub fn bind_parameter_name(&self, index: i32) -> Option<&str> {
+        let name = unsafe { libsql_ffi::sqlite3_bind_parameter_name(self.stmt, index) };
+        if name.is_null() {
+            return None;
+        }
+        let name = unsafe { std::ffi::CStr::from_ptr(name) };
+        Some(name.to_str().unwrap())
+    }
+
+    pub fn bind_null(&self, index: i32) -> Result<()> {
+        let rc = unsafe { libsql_ffi::sqlite3_bind_null(self.stmt, index) };
+        self.check(rc)
+    }
+
+    pub fn bind_int(&self, index: i32, value: i64) -> Result<()> {
+        let rc = unsafe { libsql_ffi::sqlite3_bind_int64(self.stmt, index, value) };
+        self.check(rc)
+    }
+
+    pub fn bind_float(&self, index: i32, value: f64) -> Result<()> {
+        let rc = unsafe { libsql_ffi::sqlite3_bind_double(self.stmt, index, value) };
+        self.check(rc)
+    }
+
+    pub fn bind_text(&self, index: i32, value: &str) -> Result<()> {
+        let rc = unsafe {
+            libsql_ffi::sqlite3_bind_text64(
+                self.stmt,
+                index,
+                value.as_ptr() as *const std::ffi::c_char,
+                value.len() as u64,
+                libsql_ffi::SQLITE_TRANSIENT(),
+                libsql_ffi::SQLITE_UTF8 as u8,
+            )
+        };
+        self.check(rc)
+    }
+
+    pub fn bind_blob(&self, index: i32, value: &[u8]) -> Result<()> {
+        let rc = unsafe {
+            libsql_ffi::sqlite3_bind_blob64(
+                self.stmt,
+                index,
+                value.as_ptr() as *const std::ffi::c_void,
+                value.len() as u64,
+                libsql_ffi::SQLITE_TRANSIENT(),
+            )
+        };
+        self.check(rc)
+    }
+
+    /// Returns `true` if the statement does not write to the database.
+    /// `BEGIN IMMEDIATE` and `BEGIN EXCLUSIVE` count as writes, because they
+    /// take the write lock, while a plain `BEGIN` does not.
+    pub fn is_readonly(&self) -> bool {
+        unsafe { libsql_ffi::sqlite3_stmt_readonly(self.stmt) != 0 }
+    }
+
+    /// Returns `true` if the statement is an `EXPLAIN` or `EXPLAIN QUERY
+    /// PLAN` statement.
+    pub fn is_explain(&self) -> bool {
+        unsafe { libsql_ffi::sqlite3_stmt_isexplain(self.stmt) != 0 }
+    }
+
+    /// Returns the number of rows read by the statement so far.
+    pub fn rows_read(&self) -> u64 {
+        self.status(libsql_ffi::LIBSQL_STMTSTATUS_ROWS_READ)
+    }
+
+    /// Returns the number of rows written by the statement so far.
+    pub fn rows_written(&self) -> u64 {
+        self.status(libsql_ffi::LIBSQL_STMTSTATUS_ROWS_WRITTEN)
+    }
+
+    /// Returns the number of virtual machine steps the statement has
+    /// executed so far.
+    pub fn vm_steps(&self) -> u64 {
+        self.status(libsql_ffi::SQLITE_STMTSTATUS_VM_STEP)
+    }
+
+    fn status(&self, op: i32) -> u64 {
+        unsafe { libsql_ffi::sqlite3_stmt_status(self.stmt, op, 0) as u64 }
+    }
+
     pub fn step(&self) -> Result<StepResult> {
         let rc = unsafe { libsql_ffi::sqlite3_step(self.stmt) };
         match rc {
             libsql_ffi::SQLITE_ROW => Ok(StepResult::Row),
             libsql_ffi::SQLITE_DONE => Ok(StepResult::Done),
-            _ => Err(HiisiError::SqliteError(rc)),
+            _ => Err(self.error(rc)),
+        }
+    }
+
+    fn check(&self, rc: i32) -> Result<()> {
+        if rc != libsql_ffi::SQLITE_OK {
+            return Err(self.error(rc));
         }
+        Ok(())
+    }
+
+    fn error(&self, rc: i32) -> HiisiError {
+        let conn = unsafe { libsql_ffi::sqlite3_db_handle(self.stmt) };
+        let sql = unsafe { libsql_ffi::sqlite3_sql(self.stmt) };
+        let sql = if sql.is_null() {
+            None
+        } else {
+            Some(unsafe { std::ffi::CStr::from_ptr(sql) }.to_string_lossy())
+        };
+        sqlite_error(conn, rc, sql.as_deref())
     }
 
     pub fn column_count(&self) -> i32 {
@@ -166,3 +363,171 @@ impl Stmt {
         unsafe { std::slice::from_raw_parts(blob as *const u8, len as usize) }
     }
 }
+
+/// Returns the error of a write that could not be executed because another
+/// connection holds the write transaction of the database.
+pub fn busy_error() -> HiisiError {
+    HiisiError::SqliteError(SqliteError {
+        code: libsql_ffi::SQLITE_BUSY,
+        message: "database is locked".to_string(),
+        offset: None,
+        sql: None,
+    })
+}
+
+/// Build an error from the error state of a connection.
+fn sqlite_error(conn: *mut libsql_ffi::sqlite3, rc: i32, sql: Option<&str>) -> HiisiError {
+    if conn.is_null() {
+        let msg = unsafe { std::ffi::CStr::from_ptr(libsql_ffi::sqlite3_errstr(rc)) };
+        return HiisiError::SqliteError(SqliteError {
+            code: rc,
+            message: msg.to_string_lossy().into_owned(),
+            offset: None,
+            sql: sql.map(Into::into),
+        });
+    }
+    // The connection error code is an extended one, but it is only
+    // meaningful if it refers to the same error as the returned code.
+    let extended_code = unsafe { libsql_ffi::sqlite3_extended_errcode(conn) };
+    let code = if extended_code & 0xff == rc & 0xff {
+        extended_code
+    } else {
+        rc
+    };
+    let msg = unsafe { std::ffi::CStr::from_ptr(libsql_ffi::sqlite3_errmsg(conn)) };
+    let offset = unsafe { libsql_ffi::sqlite3_error_offset(conn) };
+    HiisiError::SqliteError(SqliteError {
+        code,
+        message: msg.to_string_lossy().into_owned(),
+        offset: if offset >= 0 { Some(offset) } else { None },
+        sql: sql.map(Into::into),
+    })
+}
+
+macro_rules! error_code_names {
+    ($rc:expr, $($name:ident),* $(,)?) => {
+        match $rc {
+            $(libsql_ffi::$name => Some(stringify!($name)),)*
+            _ => None,
+        }
+    };
+}
+
+/// Returns the symbolic name of a SQLite result code, such as
+/// `SQLITE_CONSTRAINT_UNIQUE`.
+///
+/// Extended result codes that we don't know about are reported with the name
+/// of their primary result code.
+pub fn error_code_name(rc: i32) -> &'static str {
+    let name = |rc: i32| {
+        error_code_names!(
+            rc,
+            SQLITE_ERROR,
+            SQLITE_INTERNAL,
+            SQLITE_PERM,
+            SQLITE_ABORT,
+            SQLITE_BUSY,
+            SQLITE_LOCKED,
+            SQLITE_NOMEM,
+            SQLITE_READONLY,
+            SQLITE_INTERRUPT,
+            SQLITE_IOERR,
+            SQLITE_CORRUPT,
+            SQLITE_NOTFOUND,
+            SQLITE_FULL,
+            SQLITE_CANTOPEN,
+            SQLITE_PROTOCOL,
+            SQLITE_EMPTY,
+            SQLITE_SCHEMA,
+            SQLITE_TOOBIG,
+            SQLITE_CONSTRAINT,
+            SQLITE_MISMATCH,
+            SQLITE_MISUSE,
+            SQLITE_NOLFS,
+            SQLITE_AUTH,
+            SQLITE_FORMAT,
+            SQLITE_RANGE,
+            SQLITE_NOTADB,
+            SQLITE_NOTICE,
+            SQLITE_WARNING,
+            SQLITE_ERROR_MISSING_COLLSEQ,
+            SQLITE_ERROR_RETRY,
+            SQLITE_ERROR_SNAPSHOT,
+            SQLITE_IOERR_READ,
+            SQLITE_IOERR_SHORT_READ,
+            SQLITE_IOERR_WRITE,
+            SQLITE_IOERR_FSYNC,
+            SQLITE_IOERR_DIR_FSYNC,
+            SQLITE_IOERR_TRUNCATE,
+            SQLITE_IOERR_FSTAT,
+            SQLITE_IOERR_UNLOCK,
+            SQLITE_IOERR_RDLOCK,
+            SQLITE_IOERR_DELETE,
+            SQLITE_IOERR_BLOCKED,
+            SQLITE_IOERR_NOMEM,
+            SQLITE_IOERR_ACCESS,
+            SQLITE_IOERR_CHECKRESERVEDLOCK,
+            SQLITE_IOERR_LOCK,
+            SQLITE_IOERR_CLOSE,
+            SQLITE_IOERR_DIR_CLOSE,
+            SQLITE_IOERR_SHMOPEN,
+            SQLITE_IOERR_SHMSIZE,
+            SQLITE_IOERR_SHMLOCK,
+            SQLITE_IOERR_SHMMAP,
+            SQLITE_IOERR_SEEK,
+            SQLITE_IOERR_DELETE_NOENT,
+            SQLITE_IOERR_MMAP,
+            SQLITE_IOERR_GETTEMPPATH,
+            SQLITE_IOERR_CONVPATH,
+            SQLITE_IOERR_VNODE,
+            SQLITE_IOERR_AUTH,
+            SQLITE_IOERR_BEGIN_ATOMIC,
+            SQLITE_IOERR_COMMIT_ATOMIC,
+            SQLITE_IOERR_ROLLBACK_ATOMIC,
+            SQLITE_IOERR_DATA,
+            SQLITE_IOERR_CORRUPTFS,
+            SQLITE_IOERR_IN_PAGE,
+            SQLITE_LOCKED_SHAREDCACHE,
+            SQLITE_LOCKED_VTAB,
+            SQLITE_BUSY_RECOVERY,
+            SQLITE_BUSY_SNAPSHOT,
+            SQLITE_BUSY_TIMEOUT,
+            SQLITE_CANTOPEN_NOTEMPDIR,
+            SQLITE_CANTOPEN_ISDIR,
+            SQLITE_CANTOPEN_FULLPATH,
+            SQLITE_CANTOPEN_CONVPATH,
+            SQLITE_CANTOPEN_DIRTYWAL,
+            SQLITE_CANTOPEN_SYMLINK,
+            SQLITE_CORRUPT_VTAB,
+            SQLITE_CORRUPT_SEQUENCE,
+            SQLITE_CORRUPT_INDEX,
+            SQLITE_READONLY_RECOVERY,
+            SQLITE_READONLY_CANTLOCK,
+            SQLITE_READONLY_ROLLBACK,
+            SQLITE_READONLY_DBMOVED,
+            SQLITE_READONLY_CANTINIT,
+            SQLITE_READONLY_DIRECTORY,
+            SQLITE_ABORT_ROLLBACK,
+            SQLITE_CONSTRAINT_CHECK,
+            SQLITE_CONSTRAINT_COMMITHOOK,
+            SQLITE_CONSTRAINT_FOREIGNKEY,
+            SQLITE_CONSTRAINT_FUNCTION,
+            SQLITE_CONSTRAINT_NOTNULL,
+            SQLITE_CONSTRAINT_PRIMARYKEY,
+            SQLITE_CONSTRAINT_TRIGGER,
+            SQLITE_CONSTRAINT_UNIQUE,
+            SQLITE_CONSTRAINT_VTAB,
+            SQLITE_CONSTRAINT_ROWID,
+            SQLITE_CONSTRAINT_PINNED,
+            SQLITE_CONSTRAINT_DATATYPE,
+            SQLITE_NOTICE_RECOVER_WAL,
+            SQLITE_NOTICE_RECOVER_ROLLBACK,
+            SQLITE_NOTICE_RBU,
+            SQLITE_WARNING_AUTOINDEX,
+            SQLITE_AUTH_USER,
+        )
+    };
+    name(rc)
+        .or_else(|| name(rc & 0xff))
+        .unwrap_or("SQLITE_UNKNOWN")
+}
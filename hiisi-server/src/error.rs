@@ -1,17 +1,77 @@
 use thiserror::Error;
 
+use crate::database::error_code_name;
+
 #[derive(Debug, Error)]
 pub enum HiisiError {
     #[error("Protocol error: {0}")]
     ProtocolError(String),
     #[error("Parse error: {0}")]
     JsonParseError(#[from] serde_json::Error),
+    #[error("Parse error: {0}")]
+    ProtobufParseError(#[from] prost::DecodeError),
     #[error("Internal error: {0}")]
     InternalError(String),
     #[error("I/O error: {0}: {1}")]
     IOError(&'static str, std::io::Error),
     #[error("Out of memory")]
     OutOfMemory,
+    #[error("Invalid statement arguments: {0}")]
+    ArgsInvalid(String),
+    #[error("SQL text contains no statements")]
+    SqlNoStatement,
+    #[error("SQL text contains more than one statement")]
+    SqlManyStatements,
+    #[error("SQL text {0} not found")]
+    SqlNotFound(i32),
+    #[error("Too many stored SQL texts: {0}")]
+    SqlStoreFull(String),
+    #[error("Invalid baton: {0}")]
+    BatonInvalid(String),
+    #[error("Baton has already been used")]
+    BatonReused,
+    #[error("Stream of the baton has been closed")]
+    BatonStreamClosed,
+    #[error("Stream has expired")]
+    StreamExpired,
+    #[error("Resources exhausted, try again later: {0}")]
+    ResourcesExhausted(String),
+    #[error("Response is too large: {0}")]
+    ResponseTooLarge(String),
     #[error("SQLite error: {0}")]
-    SqliteError(i32),
+    SqliteError(SqliteError),
+}
+
+/// An error returned by SQLite.
+#[derive(Debug)]
+pub struct SqliteError {
+    /// The extended result code, such as `SQLITE_CONSTRAINT_UNIQUE`.
+    pub code: i32,
+    /// The error message from `sqlite3_errmsg()`.
+    pub message: String,
+    /// The byte offset of the error in the SQL text, if known.
+    pub offset: Option<i32>,
+    /// The SQL text of the statement that failed, if any.
+    pub sql: Option<String>,
+}
+
+impl SqliteError {
+    /// Returns `true` if the database was locked by another connection, which
+    /// means that the client can retry.
+    pub fn is_busy(&self) -> bool {
+        self.code & 0xff == libsql_ffi::SQLITE_BUSY
+    }
+}
+
+impl std::fmt::Display for SqliteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.message, error_code_name(self.code))?;
+        if let Some(sql) = &self.sql {
+            write!(f, " in {:?}", sql)?;
+            if let Some(offset) = self.offset {
+                write!(f, " at offset {}", offset)?;
+            }
+        }
+        Ok(())
+    }
 }
@@ -1,68 +1,235 @@
 //! Query executor.
 
-use crate::database::{StepResult, Stmt, Type};
+use crate::baton::StreamId;
+use crate::database::{self, Connection, StepResult, Stmt, Type};
 use crate::manager::ResourceManager;
 use crate::proto;
+use crate::stream::Stream;
 use crate::{HiisiError, Result};
+use std::borrow::Cow;
 use std::rc::Rc;
+use std::time::Instant;
 
 pub struct Request {
     pub database: String,
+    pub version: proto::Version,
     pub req: proto::PipelineReqBody,
 }
 
+/// Returns the stream that a request executes on, which is identified by the
+/// baton of the request or opened for a request without one.
+pub(crate) fn request_stream(
+    manager: &ResourceManager,
+    db_name: &str,
+    baton: Option<&str>,
+) -> Result<StreamId> {
+    match baton {
+        Some(baton) => manager.resume_stream(db_name, baton),
+        None => manager.open_stream(db_name),
+    }
+}
+
 impl Request {
-    fn baton(&self) -> String {
-        self.req.baton.to_owned().unwrap_or(generate_baton())
+    /// Returns the write that the request waits with if it must wait for
+    /// another stream to finish its write transaction before it is executed.
+    pub(crate) fn pending_write(&self, manager: &ResourceManager) -> Option<PendingWrite> {
+        let sqls = self.req.requests.iter().flat_map(|req| match req {
+            proto::StreamRequest::Execute(req) => vec![(&req.stmt.sql, req.stmt.sql_id)],
+            proto::StreamRequest::Batch(req) => batch_sqls(&req.batch).collect(),
+            proto::StreamRequest::Sequence(req) => vec![(&req.sql, req.sql_id)],
+            _ => vec![],
+        });
+        pending_write(manager, &self.database, self.req.baton.as_deref(), sqls)
+    }
+}
+
+/// A request that may write to a database, and so waits while another stream
+/// holds the write transaction of the database.
+///
+/// The SQL texts of the request are classified once when the request starts
+/// to wait, so checking whether it still has to wait is cheap.
+pub(crate) struct PendingWrite {
+    db_name: String,
+    /// The stream of the request, or `None` if the request opens a stream.
+    stream_id: Option<StreamId>,
+}
+
+impl PendingWrite {
+    pub fn new(db_name: &str, stream_id: Option<StreamId>) -> Self {
+        Self {
+            db_name: db_name.to_string(),
+            stream_id,
+        }
+    }
+
+    /// Returns `true` while another stream holds the write transaction.
+    pub fn must_wait(&self, manager: &ResourceManager) -> bool {
+        manager.has_other_writer(&self.db_name, self.stream_id)
+    }
+}
+
+/// Returns the SQL texts or the ids of stored SQL texts of the steps of a
+/// batch.
+pub(crate) fn batch_sqls(
+    batch: &proto::Batch,
+) -> impl Iterator<Item = (&Option<String>, Option<i32>)> {
+    batch
+        .steps
+        .iter()
+        .map(|step| (&step.stmt.sql, step.stmt.sql_id))
+}
+
+/// Returns the write that a request waits with if another stream holds the
+/// write transaction of the database and any of the SQL texts that the
+/// request executes may write to it.
+///
+/// SQL text that refers to a stored SQL text which cannot be found is
+/// assumed to write, and SQL text that fails to prepare is assumed not to,
+/// so that the request fails right away.
+pub(crate) fn pending_write<'a>(
+    manager: &ResourceManager,
+    db_name: &str,
+    baton: Option<&str>,
+    sqls: impl Iterator<Item = (&'a Option<String>, Option<i32>)>,
+) -> Option<PendingWrite> {
+    let stream_id = baton.and_then(|baton| manager.baton_stream_id(baton));
+    let write = PendingWrite::new(db_name, stream_id);
+    if !write.must_wait(manager) {
+        return None;
+    }
+    // The statements are prepared on the stream of the request, or on a new
+    // connection if the request opens a stream.
+    let stream = match stream_id {
+        Some(stream_id) => manager.get_stream(db_name, stream_id),
+        None => manager
+            .connect(db_name)
+            .map(|conn| Rc::new(Stream::new(conn))),
+    };
+    match stream {
+        Ok(stream) if any_may_write(&stream, sqls) => Some(write),
+        _ => None,
+    }
+}
+
+/// Returns `true` if any of the SQL texts may write to the database when
+/// executed on a stream.
+pub(crate) fn any_may_write<'a>(
+    stream: &Stream,
+    mut sqls: impl Iterator<Item = (&'a Option<String>, Option<i32>)>,
+) -> bool {
+    sqls.any(|(sql, sql_id)| match resolve_sql(stream, sql, sql_id) {
+        Ok(sql) => may_write(&stream.conn, &sql),
+        Err(HiisiError::SqlNotFound(_)) => true,
+        Err(_) => false,
+    })
+}
+
+/// Returns `true` if any statement of SQL text may write to the database,
+/// including `BEGIN IMMEDIATE` and `BEGIN EXCLUSIVE`, which take the write
+/// lock.
+fn may_write(conn: &Connection, sql: &str) -> bool {
+    let mut sql = sql;
+    loop {
+        match conn.prepare_first(sql) {
+            Ok((Some(stmt), tail)) => {
+                if !stmt.is_readonly() {
+                    return true;
+                }
+                sql = tail;
+            }
+            Ok((None, _)) | Err(_) => return false,
+        }
     }
 }
 
-fn generate_baton() -> String {
-    // NOTE: This is different from the baton generation in libSQL server.
-    uuid::Uuid::new_v4().to_string()
+/// Execute a statement of a stateless Hrana 1 request on a fresh connection.
+pub fn execute_v1_stmt(
+    manager: Rc<ResourceManager>,
+    db_name: &str,
+    req: proto::v1::ExecuteReqBody,
+) -> Result<proto::v1::ExecuteRespBody> {
+    log::trace!("Executing SQL statement: {:?} on {}", req.stmt, db_name);
+    let stream = Stream::new(manager.connect(db_name)?);
+    let result = execute_stmt(&stream, &req.stmt)?;
+    Ok(proto::v1::ExecuteRespBody {
+        result: result.into(),
+    })
+}
+
+/// Execute a batch of a stateless Hrana 1 request on a fresh connection.
+///
+/// The connection is closed after the batch, so a transaction that the batch
+/// leaves open is rolled back.
+pub fn execute_v1_batch(
+    manager: Rc<ResourceManager>,
+    db_name: &str,
+    req: proto::v1::BatchReqBody,
+) -> Result<proto::v1::BatchRespBody> {
+    log::trace!(
+        "Executing batch of {} steps on {}",
+        req.batch.steps.len(),
+        db_name
+    );
+    check_batch(&req.batch, proto::Version::Hrana1)?;
+    let stream = Stream::new(manager.connect(db_name)?);
+    let result = execute_batch(&stream, &req.batch)?;
+    Ok(proto::v1::BatchRespBody {
+        result: result.into(),
+    })
 }
 
-pub fn execute_client_req(
+/// Execute a request of a pipeline. Errors are reported per request so that
+/// the rest of the pipeline is still executed.
+pub(crate) fn exec_stream_request(
     manager: Rc<ResourceManager>,
-    req: Request,
-) -> Result<proto::PipelineRespBody> {
-    let db_name = &req.database;
-    let baton = &req.baton();
-    let req = &req.req;
-    let mut responses = Vec::new();
-    responses
-        .try_reserve(req.requests.len())
-        .map_err(|_| HiisiError::OutOfMemory)?;
-    for req in &req.requests {
-        let resp = match req {
-            proto::StreamRequest::None => todo!(),
-            proto::StreamRequest::Close(_) => exec_close(manager.clone(), db_name, baton)?,
-            proto::StreamRequest::Execute(req) => {
-                exec_execute(manager.clone(), &req, db_name, baton)?
+    req: &proto::StreamRequest,
+    version: proto::Version,
+    db_name: &str,
+    stream_id: StreamId,
+) -> proto::StreamResult {
+    match exec_request(manager, req, version, db_name, stream_id) {
+        Ok(resp) => resp,
+        Err(err) => {
+            log::debug!("Stream request failed: {}", err);
+            proto::StreamResult::Error {
+                error: to_proto_error(&err),
             }
-            proto::StreamRequest::Batch(_) => todo!(),
-            proto::StreamRequest::Sequence(_) => todo!(),
-            proto::StreamRequest::Describe(_) => todo!(),
-            proto::StreamRequest::StoreSql(_) => todo!(),
-            proto::StreamRequest::CloseSql(_) => todo!(),
-            proto::StreamRequest::GetAutocommit(_) => todo!(),
-        };
-        responses.push(resp);
+        }
+    }
+}
+
+fn exec_request(
+    manager: Rc<ResourceManager>,
+    req: &proto::StreamRequest,
+    version: proto::Version,
+    db_name: &str,
+    stream_id: StreamId,
+) -> Result<proto::StreamResult> {
+    match req {
+        proto::StreamRequest::None => Err(HiisiError::ProtocolError(
+            "Unknown stream request".to_string(),
+        )),
+        proto::StreamRequest::Close(_) => exec_close(manager, db_name, stream_id),
+        proto::StreamRequest::Execute(req) => exec_execute(manager, req, db_name, stream_id),
+        proto::StreamRequest::Batch(req) => exec_batch(manager, req, version, db_name, stream_id),
+        proto::StreamRequest::Sequence(req) => exec_sequence(manager, req, db_name, stream_id),
+        proto::StreamRequest::Describe(req) => exec_describe(manager, req, db_name, stream_id),
+        proto::StreamRequest::StoreSql(req) => exec_store_sql(manager, req, db_name, stream_id),
+        proto::StreamRequest::CloseSql(req) => exec_close_sql(manager, req, db_name, stream_id),
+        proto::StreamRequest::GetAutocommit(_) => {
+            check_version(version, proto::Version::Hrana3, "get_autocommit request")?;
+            exec_get_autocommit(manager, db_name, stream_id)
+        }
     }
-    return Ok(proto::PipelineRespBody {
-        baton: Some(baton.to_owned()),
-        base_url: None,
-        results: responses,
-    });
 }
 
 fn exec_close(
     manager: Rc<ResourceManager>,
     db_name: &str,
-    baton: &str,
+    stream_id: StreamId,
 ) -> Result<proto::StreamResult> {
-    log::trace!("Closing connection: {} (baton = {})", db_name, baton);
-    manager.drop_conn(db_name, baton)?;
+    log::trace!("Closing connection: {} (stream = {})", db_name, stream_id);
+    manager.drop_stream(db_name, stream_id)?;
     Ok(proto::StreamResult::Ok {
         response: proto::StreamResponse::Close(proto::CloseStreamResp {}),
     })
@@ -72,24 +239,114 @@ fn exec_execute(
     manager: Rc<ResourceManager>,
     req: &proto::ExecuteStreamReq,
     db_name: &str,
-    baton: &str,
+    stream_id: StreamId,
 ) -> Result<proto::StreamResult> {
     log::trace!(
-        "Executing SQL statement: {:?} on {} (baton = {}",
+        "Executing SQL statement: {:?} on {} (stream = {})",
         req.stmt,
         db_name,
-        baton
+        stream_id
+    );
+    let stream = manager.get_stream(db_name, stream_id)?;
+    let result = execute_stmt(&stream, &req.stmt)?;
+    let resp = proto::ExecuteStreamResp { result };
+    Ok(proto::StreamResult::Ok {
+        response: proto::StreamResponse::Execute(resp),
+    })
+}
+
+fn exec_batch(
+    manager: Rc<ResourceManager>,
+    req: &proto::BatchStreamReq,
+    version: proto::Version,
+    db_name: &str,
+    stream_id: StreamId,
+) -> Result<proto::StreamResult> {
+    log::trace!(
+        "Executing batch of {} steps on {} (stream = {})",
+        req.batch.steps.len(),
+        db_name,
+        stream_id
+    );
+    check_batch(&req.batch, version)?;
+    let stream = manager.get_stream(db_name, stream_id)?;
+    let result = execute_batch(&stream, &req.batch)?;
+    let resp = proto::BatchStreamResp { result };
+    Ok(proto::StreamResult::Ok {
+        response: proto::StreamResponse::Batch(resp),
+    })
+}
+
+fn exec_sequence(
+    manager: Rc<ResourceManager>,
+    req: &proto::SequenceStreamReq,
+    db_name: &str,
+    stream_id: StreamId,
+) -> Result<proto::StreamResult> {
+    log::trace!(
+        "Executing SQL sequence: {:?} on {} (stream = {})",
+        req,
+        db_name,
+        stream_id
     );
-    let conn = manager.get_conn(db_name, baton)?;
-    let sql = req.stmt.sql.as_ref().ok_or(HiisiError::InternalError(
-        "No SQL statement found".to_string(),
-    ))?;
-    let stmt = conn.prepare(sql)?;
-    let result = make_execute_result(stmt)?;
-    Ok(result)
+    let stream = manager.get_stream(db_name, stream_id)?;
+    let sql = resolve_sql(&stream, &req.sql, req.sql_id)?;
+    execute_sequence(&stream.conn, &sql)?;
+    Ok(proto::StreamResult::Ok {
+        response: proto::StreamResponse::Sequence(proto::SequenceStreamResp {}),
+    })
 }
 
-fn make_execute_result(stmt: Stmt) -> Result<proto::StreamResult> {
+/// Execute every statement in SQL text, discarding their results.
+///
+/// Execution stops at the first statement that fails; the statements
+/// before it are not rolled back.
+pub(crate) fn execute_sequence(conn: &Connection, sql: &str) -> Result<()> {
+    let mut sql = sql;
+    loop {
+        let (stmt, tail) = conn.prepare_first(sql)?;
+        let stmt = match stmt {
+            Some(stmt) => stmt,
+            None => break,
+        };
+        while let StepResult::Row = stmt.step()? {}
+        sql = tail;
+    }
+    Ok(())
+}
+
+fn exec_describe(
+    manager: Rc<ResourceManager>,
+    req: &proto::DescribeStreamReq,
+    db_name: &str,
+    stream_id: StreamId,
+) -> Result<proto::StreamResult> {
+    log::trace!(
+        "Describing SQL statement: {:?} on {} (stream = {})",
+        req,
+        db_name,
+        stream_id
+    );
+    let stream = manager.get_stream(db_name, stream_id)?;
+    let sql = resolve_sql(&stream, &req.sql, req.sql_id)?;
+    let result = describe_stmt(&stream.conn.prepare(&sql)?)?;
+    let resp = proto::DescribeStreamResp { result };
+    Ok(proto::StreamResult::Ok {
+        response: proto::StreamResponse::Describe(resp),
+    })
+}
+
+/// Describe the parameters and result columns of a statement without
+/// executing it.
+pub(crate) fn describe_stmt(stmt: &Stmt) -> Result<proto::DescribeResult> {
+    let param_count = stmt.bind_parameter_count();
+    let mut params = Vec::with_capacity(param_count as usize);
+    for i in 1..=param_count {
+        let name = stmt.bind_parameter_name(i);
+        params.push(proto::DescribeParam {
+            name: name.map(Into::into),
+        });
+    }
     let column_count = stmt.column_count();
     let mut cols = Vec::with_capacity(column_count as usize);
     for i in 0..column_count {
@@ -99,41 +356,463 @@ fn make_execute_result(stmt: Stmt) -> Result<proto::StreamResult> {
                 "No column name found for column {}",
                 i
             )))?;
-        let decltype = stmt.column_decltype(i);
-        let col = proto::Col {
-            name: Some(name.into()),
-            decltype: decltype.map(Into::into),
+        cols.push(proto::DescribeCol {
+            name: name.into(),
+            decltype: stmt.column_decltype(i).map(Into::into),
+        });
+    }
+    Ok(proto::DescribeResult {
+        params,
+        cols,
+        is_explain: stmt.is_explain(),
+        is_readonly: stmt.is_readonly(),
+    })
+}
+
+fn exec_get_autocommit(
+    manager: Rc<ResourceManager>,
+    db_name: &str,
+    stream_id: StreamId,
+) -> Result<proto::StreamResult> {
+    let stream = manager.get_stream(db_name, stream_id)?;
+    let resp = proto::GetAutocommitStreamResp {
+        is_autocommit: stream.is_autocommit(),
+    };
+    Ok(proto::StreamResult::Ok {
+        response: proto::StreamResponse::GetAutocommit(resp),
+    })
+}
+
+fn exec_store_sql(
+    manager: Rc<ResourceManager>,
+    req: &proto::StoreSqlStreamReq,
+    db_name: &str,
+    stream_id: StreamId,
+) -> Result<proto::StreamResult> {
+    log::trace!(
+        "Storing SQL text {} on {} (stream = {})",
+        req.sql_id,
+        db_name,
+        stream_id
+    );
+    let stream = manager.get_stream(db_name, stream_id)?;
+    stream.store_sql(req.sql_id, req.sql.clone())?;
+    Ok(proto::StreamResult::Ok {
+        response: proto::StreamResponse::StoreSql(proto::StoreSqlStreamResp {}),
+    })
+}
+
+fn exec_close_sql(
+    manager: Rc<ResourceManager>,
+    req: &proto::CloseSqlStreamReq,
+    db_name: &str,
+    stream_id: StreamId,
+) -> Result<proto::StreamResult> {
+    log::trace!(
+        "Closing SQL text {} on {} (stream = {})",
+        req.sql_id,
+        db_name,
+        stream_id
+    );
+    let stream = manager.get_stream(db_name, stream_id)?;
+    stream.close_sql(req.sql_id);
+    Ok(proto::StreamResult::Ok {
+        response: proto::StreamResponse::CloseSql(proto::CloseSqlStreamResp {}),
+    })
+}
+
+/// Resolve the SQL text of a request, which is either passed inline or
+/// refers to a SQL text stored on the stream.
+pub(crate) fn resolve_sql<'a>(
+    stream: &Stream,
+    sql: &'a Option<String>,
+    sql_id: Option<i32>,
+) -> Result<Cow<'a, str>> {
+    match (sql, sql_id) {
+        (Some(sql), None) => Ok(Cow::Borrowed(sql)),
+        (None, Some(sql_id)) => Ok(Cow::Owned(stream.get_sql(sql_id)?)),
+        (Some(_), Some(_)) => Err(HiisiError::ProtocolError(
+            "Both sql and sql_id were specified".to_string(),
+        )),
+        (None, None) => Err(HiisiError::ProtocolError(
+            "Neither sql nor sql_id was specified".to_string(),
+        )),
+    }
+}
+
+/// Execute the steps of a batch in order.
+///
+/// A step whose condition evaluates to false is skipped, which leaves both
+/// its result and error empty. Errors from executing a step are reported in
+/// the batch result, but errors in the batch itself (for example, a condition
+/// that refers to a step that has not been executed yet) fail the request.
+/// The rows of all the steps together are limited to `MAX_RESULT_SIZE`.
+pub(crate) fn execute_batch(stream: &Stream, batch: &proto::Batch) -> Result<proto::BatchResult> {
+    let mut size_left = MAX_RESULT_SIZE;
+    let mut step_results = Vec::with_capacity(batch.steps.len());
+    let mut step_errors = Vec::with_capacity(batch.steps.len());
+    let mut outcomes = Vec::with_capacity(batch.steps.len());
+    for step in &batch.steps {
+        let enabled = match &step.condition {
+            Some(cond) => eval_cond(stream, cond, &outcomes)?,
+            None => true,
         };
-        cols.push(col);
+        let (result, error, outcome) = if enabled {
+            match execute_stmt_within(stream, &step.stmt, &mut size_left) {
+                Ok(result) => (Some(result), None, StepOutcome::Ok),
+                Err(err) => (None, Some(to_proto_error(&err)), StepOutcome::Error),
+            }
+        } else {
+            (None, None, StepOutcome::Skipped)
+        };
+        step_results.push(result);
+        step_errors.push(error);
+        outcomes.push(outcome);
+    }
+    Ok(proto::BatchResult {
+        step_results,
+        step_errors,
+        replication_index: None,
+    })
+}
+
+/// Check that a batch uses only features of the given protocol version.
+pub(crate) fn check_batch(batch: &proto::Batch, version: proto::Version) -> Result<()> {
+    for step in &batch.steps {
+        if let Some(cond) = &step.condition {
+            check_cond(cond, version)?;
+        }
+    }
+    Ok(())
+}
+
+fn check_cond(cond: &proto::BatchCond, version: proto::Version) -> Result<()> {
+    match cond {
+        proto::BatchCond::None | proto::BatchCond::Ok { .. } | proto::BatchCond::Error { .. } => {
+            Ok(())
+        }
+        proto::BatchCond::Not { cond } => check_cond(cond, version),
+        proto::BatchCond::And(list) | proto::BatchCond::Or(list) => {
+            for cond in &list.conds {
+                check_cond(cond, version)?;
+            }
+            Ok(())
+        }
+        proto::BatchCond::IsAutocommit {} => {
+            check_version(version, proto::Version::Hrana3, "is_autocommit condition")
+        }
+    }
+}
+
+pub(crate) fn check_version(
+    version: proto::Version,
+    required: proto::Version,
+    what: &str,
+) -> Result<()> {
+    if version < required {
+        return Err(HiisiError::ProtocolError(format!(
+            "{} requires {} but the client uses {}",
+            what, required, version
+        )));
+    }
+    Ok(())
+}
+
+/// The outcome of an executed batch step, which batch conditions of later
+/// steps refer to.
+pub(crate) enum StepOutcome {
+    Skipped,
+    Ok,
+    Error,
+}
+
+pub(crate) fn eval_cond(
+    stream: &Stream,
+    cond: &proto::BatchCond,
+    outcomes: &[StepOutcome],
+) -> Result<bool> {
+    let outcome = |step: u32| -> Result<&StepOutcome> {
+        outcomes.get(step as usize).ok_or_else(|| {
+            HiisiError::ProtocolError(format!(
+                "Batch condition refers to step {} that has not been executed",
+                step
+            ))
+        })
+    };
+    let ret = match cond {
+        proto::BatchCond::None => {
+            return Err(HiisiError::ProtocolError(
+                "Invalid batch condition".to_string(),
+            ))
+        }
+        proto::BatchCond::Ok { step } => matches!(outcome(*step)?, StepOutcome::Ok),
+        proto::BatchCond::Error { step } => matches!(outcome(*step)?, StepOutcome::Error),
+        proto::BatchCond::Not { cond } => !eval_cond(stream, cond, outcomes)?,
+        proto::BatchCond::And(list) => {
+            for cond in &list.conds {
+                if !eval_cond(stream, cond, outcomes)? {
+                    return Ok(false);
+                }
+            }
+            true
+        }
+        proto::BatchCond::Or(list) => {
+            for cond in &list.conds {
+                if eval_cond(stream, cond, outcomes)? {
+                    return Ok(true);
+                }
+            }
+            false
+        }
+        proto::BatchCond::IsAutocommit {} => stream.is_autocommit(),
+    };
+    Ok(ret)
+}
+
+/// Convert an error into a Hrana error.
+pub(crate) fn to_proto_error(err: &HiisiError) -> proto::Error {
+    let code = match err {
+        HiisiError::ProtocolError(_)
+        | HiisiError::JsonParseError(_)
+        | HiisiError::ProtobufParseError(_) => "PROTOCOL_ERROR",
+        HiisiError::ArgsInvalid(_) => "ARGS_INVALID",
+        HiisiError::SqlNoStatement => "SQL_NO_STATEMENT",
+        HiisiError::SqlManyStatements => "SQL_MANY_STATEMENTS",
+        HiisiError::SqlNotFound(_) => "SQL_NOT_FOUND",
+        HiisiError::SqlStoreFull(_) => "SQL_STORE_TOO_MANY",
+        HiisiError::BatonInvalid(_) => "BATON_INVALID",
+        HiisiError::BatonReused => "BATON_REUSED",
+        HiisiError::BatonStreamClosed => "BATON_STREAM_CLOSED",
+        HiisiError::StreamExpired => "STREAM_EXPIRED",
+        HiisiError::ResourcesExhausted(_) => "RESOURCES_EXHAUSTED",
+        HiisiError::ResponseTooLarge(_) => "RESPONSE_TOO_LARGE",
+        HiisiError::SqliteError(err) => database::error_code_name(err.code),
+        HiisiError::InternalError(_) | HiisiError::IOError(..) | HiisiError::OutOfMemory => {
+            "INTERNAL_ERROR"
+        }
+    };
+    // SQLite errors are reported with just the SQLite error message because
+    // the client already knows the SQL and the code is reported separately.
+    let message = match err {
+        HiisiError::SqliteError(err) => format!("SQLite error: {}", err.message),
+        _ => err.to_string(),
+    };
+    proto::Error {
+        message,
+        code: code.to_string(),
+    }
+}
+
+/// The largest size of the rows in a result that is buffered whole, which are
+/// the results of batches, of Protobuf pipelines and of WebSocket requests.
+/// The size of a row is estimated from the size of its values.
+const MAX_RESULT_SIZE: usize = 10 * 1024 * 1024;
+
+pub(crate) fn execute_stmt(stream: &Stream, stmt: &proto::Stmt) -> Result<proto::StmtResult> {
+    let mut size_left = MAX_RESULT_SIZE;
+    execute_stmt_within(stream, stmt, &mut size_left)
+}
+
+/// Execute a statement whose rows may take up at most `size_left` bytes, which
+/// is reduced by the size of the rows.
+fn execute_stmt_within(
+    stream: &Stream,
+    stmt: &proto::Stmt,
+    size_left: &mut usize,
+) -> Result<proto::StmtResult> {
+    let start = Instant::now();
+    let prepared = prepare_stmt(stream, stmt)?;
+    let want_rows = stmt.want_rows.unwrap_or(true);
+    make_stmt_result(&stream.conn, prepared, want_rows, start, size_left)
+}
+
+/// Prepare a statement and bind its arguments.
+pub(crate) fn prepare_stmt(stream: &Stream, stmt: &proto::Stmt) -> Result<Stmt> {
+    let sql = resolve_sql(stream, &stmt.sql, stmt.sql_id)?;
+    let prepared = stream.conn.prepare(&sql)?;
+    bind_args(&prepared, stmt)?;
+    Ok(prepared)
+}
+
+/// Bind positional and named arguments of a statement.
+///
+/// Positional arguments are bound in order starting from the first
+/// parameter. Named arguments may be given with or without their prefix
+/// character; without one, we look up the `:`, `@`, and `$` forms.
+fn bind_args(prepared: &Stmt, stmt: &proto::Stmt) -> Result<()> {
+    let param_count = prepared.bind_parameter_count() as usize;
+    if stmt.args.len() > param_count {
+        return Err(HiisiError::ArgsInvalid(format!(
+            "Statement expects {} arguments, but {} were given",
+            param_count,
+            stmt.args.len()
+        )));
+    }
+    if stmt.named_args.is_empty() && stmt.args.len() < param_count {
+        return Err(HiisiError::ArgsInvalid(format!(
+            "Statement expects {} arguments, but {} were given",
+            param_count,
+            stmt.args.len()
+        )));
+    }
+    // A parameter that is not bound would silently be NULL.
+    let mut bound = vec![false; param_count];
+    for (i, value) in stmt.args.iter().enumerate() {
+        bind_value(prepared, i as i32 + 1, value)?;
+        bound[i] = true;
+    }
+    for arg in &stmt.named_args {
+        let index = named_param_index(prepared, &arg.name).ok_or_else(|| {
+            HiisiError::ArgsInvalid(format!("Statement has no parameter named {}", arg.name))
+        })?;
+        bind_value(prepared, index, &arg.value)?;
+        bound[index as usize - 1] = true;
     }
+    if let Some(i) = bound.iter().position(|bound| !bound) {
+        let index = i as i32 + 1;
+        let param = match prepared.bind_parameter_name(index) {
+            Some(name) => name.to_string(),
+            None => index.to_string(),
+        };
+        return Err(HiisiError::ArgsInvalid(format!(
+            "Missing argument for parameter {}",
+            param
+        )));
+    }
+    Ok(())
+}
+
+fn named_param_index(prepared: &Stmt, name: &str) -> Option<i32> {
+    if name.starts_with([':', '@', '$']) {
+        return prepared.bind_parameter_index(name);
+    }
+    [':', '@', '$']
+        .iter()
+        .find_map(|prefix| prepared.bind_parameter_index(&format!("{}{}", prefix, name)))
+}
+
+fn bind_value(prepared: &Stmt, index: i32, value: &proto::Value) -> Result<()> {
+    match value {
+        proto::Value::None => Err(HiisiError::ArgsInvalid(format!(
+            "Missing value for argument {}",
+            index
+        ))),
+        proto::Value::Null => prepared.bind_null(index),
+        proto::Value::Integer { value } => prepared.bind_int(index, *value),
+        proto::Value::Float { value } => prepared.bind_float(index, *value),
+        proto::Value::Text { value } => prepared.bind_text(index, value),
+        proto::Value::Blob { value } => prepared.bind_blob(index, value),
+    }
+}
+
+fn make_stmt_result(
+    conn: &Connection,
+    stmt: Stmt,
+    want_rows: bool,
+    start: Instant,
+    size_left: &mut usize,
+) -> Result<proto::StmtResult> {
+    let column_count = stmt.column_count();
+    let cols = stmt_cols(&stmt)?;
+    let changes = ChangeCounters::new(conn);
     let mut rows = Vec::new();
     loop {
         match stmt.step()? {
+            // If the client does not want the rows, we still need to step
+            // the statement to completion, but we don't convert them.
+            StepResult::Row if !want_rows => {}
             StepResult::Row => {
                 let row = to_row(&stmt, column_count)?;
+                *size_left = size_left.checked_sub(row_size(&row)).ok_or_else(|| {
+                    HiisiError::ResponseTooLarge(format!("rows exceed {} bytes", MAX_RESULT_SIZE))
+                })?;
                 rows.push(row);
             }
             StepResult::Done => break,
         }
     }
-    let resp = proto::ExecuteStreamResp {
-        result: proto::StmtResult {
-            cols,
-            rows,
-            affected_row_count: 0,
-            last_insert_rowid: None,
-            replication_index: None,
-            rows_read: 0,
-            rows_written: 0,
-            query_duration_ms: 0.0,
-        },
-    };
-    Ok(proto::StreamResult::Ok {
-        response: proto::StreamResponse::Execute(resp),
+    let (affected_row_count, last_insert_rowid) = changes.stmt_changes(conn);
+    log::trace!("Statement executed in {} VM steps", stmt.vm_steps());
+    Ok(proto::StmtResult {
+        cols,
+        rows,
+        affected_row_count,
+        last_insert_rowid,
+        replication_index: None,
+        rows_read: stmt.rows_read(),
+        rows_written: stmt.rows_written(),
+        query_duration_ms: start.elapsed().as_secs_f64() * 1000.0,
     })
 }
 
-fn to_row(stmt: &Stmt, column_count: i32) -> Result<proto::Row> {
+pub(crate) fn stmt_cols(stmt: &Stmt) -> Result<Vec<proto::Col>> {
+    let column_count = stmt.column_count();
+    let mut cols = Vec::with_capacity(column_count as usize);
+    for i in 0..column_count {
+        let name = stmt
+            .column_name(i)
+            .ok_or(HiisiError::InternalError(format!(
+                "No column name found for column {}",
+                i
+            )))?;
+        let decltype = stmt.column_decltype(i);
+        let col = proto::Col {
+            name: Some(name.into()),
+            decltype: decltype.map(Into::into),
+        };
+        cols.push(col);
+    }
+    Ok(cols)
+}
+
+/// The change counters of a connection, taken before a statement is stepped
+/// to tell what the statement changed.
+///
+/// SQLite reports the affected row count and last insert rowid of the most
+/// recent `INSERT`, `UPDATE`, or `DELETE` on the connection, which may be an
+/// earlier statement than the one that just ran.
+#[derive(Default)]
+pub(crate) struct ChangeCounters {
+    total_changes: u64,
+    last_insert_rowid: i64,
+}
+
+impl ChangeCounters {
+    pub fn new(conn: &Connection) -> Self {
+        Self {
+            total_changes: conn.total_changes(),
+            last_insert_rowid: conn.last_insert_rowid(),
+        }
+    }
+
+    /// Returns the affected row count and last insert rowid of a statement
+    /// that was stepped to completion after the counters were taken.
+    pub fn stmt_changes(&self, conn: &Connection) -> (u64, Option<i64>) {
+        let affected_row_count = if conn.total_changes() != self.total_changes {
+            conn.changes()
+        } else {
+            0
+        };
+        let last_insert_rowid = conn.last_insert_rowid();
+        let last_insert_rowid =
+            (last_insert_rowid != self.last_insert_rowid).then_some(last_insert_rowid);
+        (affected_row_count, last_insert_rowid)
+    }
+}
+
+/// Returns the estimated size of a row in a response.
+fn row_size(row: &proto::Row) -> usize {
+    row.values
+        .iter()
+        .map(|value| match value {
+            proto::Value::Text { value } => value.len(),
+            proto::Value::Blob { value } => value.len(),
+            _ => 8,
+        })
+        .sum()
+}
+
+pub(crate) fn to_row(stmt: &Stmt, column_count: i32) -> Result<proto::Row> {
     let mut values = Vec::new();
     for i in 0..column_count {
         let value = match stmt.column_type(i) {
@@ -161,3 +840,666 @@ fn to_row(stmt: &Stmt, column_count: i32) -> Result<proto::Row> {
     }
     Ok(proto::Row { values })
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::manager::StreamTimeouts;
+    use crate::test_util::{execute, execute_pipeline, temp_manager, temp_manager_with_timeouts};
+    use std::path::Path;
+    use std::time::Duration;
+
+    fn execute_req(
+        manager: &Rc<ResourceManager>,
+        baton: Option<String>,
+        requests: Vec<proto::StreamRequest>,
+    ) -> proto::PipelineRespBody {
+        let req = Request {
+            database: "test".to_string(),
+            version: proto::Version::Hrana3,
+            req: proto::PipelineReqBody { baton, requests },
+        };
+        execute_pipeline(manager, req).unwrap()
+    }
+
+    fn memory_stream() -> Stream {
+        Stream::new(Connection::open(Path::new(":memory:")).unwrap())
+    }
+
+    fn stmt(sql: &str) -> proto::Stmt {
+        proto::Stmt::new(sql, true)
+    }
+
+    #[test]
+    fn batch_transactional_commit() {
+        let stream = memory_stream();
+        let batch = proto::Batch::transactional([
+            stmt("CREATE TABLE t (x INTEGER)"),
+            stmt("INSERT INTO t VALUES (1)"),
+        ]);
+        let result = execute_batch(&stream, &batch).unwrap();
+        let executed: Vec<bool> = result.step_results.iter().map(Option::is_some).collect();
+        assert_eq!(executed, vec![true, true, true, true, false]);
+        assert!(result.step_errors.iter().all(Option::is_none));
+        assert!(stream.is_autocommit());
+    }
+
+    #[test]
+    fn batch_transactional_rollback() {
+        let stream = memory_stream();
+        let batch = proto::Batch::transactional([
+            stmt("CREATE TABLE t (x INTEGER)"),
+            stmt("INSERT INTO no_such_table VALUES (1)"),
+            stmt("INSERT INTO t VALUES (1)"),
+        ]);
+        let result = execute_batch(&stream, &batch).unwrap();
+        let executed: Vec<bool> = result.step_results.iter().map(Option::is_some).collect();
+        assert_eq!(executed, vec![true, true, false, false, false, true]);
+        assert!(result.step_errors[2].is_some());
+        assert!(stream.is_autocommit());
+    }
+
+    #[test]
+    fn batch_cond_is_autocommit() {
+        let stream = memory_stream();
+        let batch = proto::Batch {
+            steps: vec![
+                proto::BatchStep {
+                    condition: Some(proto::BatchCond::IsAutocommit {}),
+                    stmt: stmt("BEGIN"),
+                },
+                proto::BatchStep {
+                    condition: Some(proto::BatchCond::IsAutocommit {}),
+                    stmt: stmt("SELECT 1"),
+                },
+                proto::BatchStep {
+                    condition: Some(proto::BatchCond::Or(proto::BatchCondList {
+                        conds: vec![
+                            proto::BatchCond::Error { step: 0 },
+                            proto::BatchCond::Not {
+                                cond: Box::new(proto::BatchCond::IsAutocommit {}),
+                            },
+                        ],
+                    })),
+                    stmt: stmt("ROLLBACK"),
+                },
+            ],
+            replication_index: None,
+        };
+        let result = execute_batch(&stream, &batch).unwrap();
+        let executed: Vec<bool> = result.step_results.iter().map(Option::is_some).collect();
+        assert_eq!(executed, vec![true, false, true]);
+    }
+
+    #[test]
+    fn batch_cond_future_step() {
+        let stream = memory_stream();
+        let batch = proto::Batch {
+            steps: vec![proto::BatchStep {
+                condition: Some(proto::BatchCond::Ok { step: 0 }),
+                stmt: stmt("SELECT 1"),
+            }],
+            replication_index: None,
+        };
+        assert!(execute_batch(&stream, &batch).is_err());
+    }
+
+    #[test]
+    fn bind_positional_and_named_args() {
+        let stream = memory_stream();
+        let mut query = stmt("SELECT ?, ?3, :a, @b, $c");
+        query.bind(proto::Value::Integer { value: 1 });
+        query.bind(proto::Value::Null);
+        query.bind(proto::Value::Text { value: "x".into() });
+        query.bind_named(":a".to_string(), proto::Value::Float { value: 0.5 });
+        query.bind_named("b".to_string(), proto::Value::Integer { value: 2 });
+        query.bind_named(
+            "c".to_string(),
+            proto::Value::Blob {
+                value: vec![1u8, 2].into(),
+            },
+        );
+        let result = execute_stmt(&stream, &query).unwrap();
+        let row = &result.rows[0].values;
+        assert!(matches!(row[0], proto::Value::Integer { value: 1 }));
+        assert!(matches!(&row[1], proto::Value::Text { value } if &**value == "x"));
+        assert!(matches!(row[2], proto::Value::Float { value } if value == 0.5));
+        assert!(matches!(row[3], proto::Value::Integer { value: 2 }));
+        assert!(matches!(&row[4], proto::Value::Blob { value } if value[..] == [1, 2]));
+    }
+
+    #[test]
+    fn bind_mismatched_args() {
+        let stream = memory_stream();
+        let mut query = stmt("SELECT ?");
+        query.bind(proto::Value::Null);
+        query.bind(proto::Value::Null);
+        let err = execute_stmt(&stream, &query).unwrap_err();
+        assert!(matches!(err, HiisiError::ArgsInvalid(_)));
+
+        let query = stmt("SELECT ?");
+        let err = execute_stmt(&stream, &query).unwrap_err();
+        assert!(matches!(err, HiisiError::ArgsInvalid(_)));
+
+        let mut query = stmt("SELECT :a");
+        query.bind_named("b".to_string(), proto::Value::Null);
+        let err = execute_stmt(&stream, &query).unwrap_err();
+        assert!(matches!(err, HiisiError::ArgsInvalid(_)));
+
+        // Positional and named parameters that are not given are missing
+        // even if other parameters are named.
+        let mut query = stmt("SELECT ?, :a");
+        query.bind_named("a".to_string(), proto::Value::Null);
+        let err = execute_stmt(&stream, &query).unwrap_err();
+        assert!(matches!(err, HiisiError::ArgsInvalid(_)));
+
+        let mut query = stmt("SELECT :a, :b");
+        query.bind_named("a".to_string(), proto::Value::Null);
+        let err = execute_stmt(&stream, &query).unwrap_err();
+        assert!(matches!(err, HiisiError::ArgsInvalid(msg) if msg.contains(":b")));
+
+        let mut query = stmt("SELECT ?, :a");
+        query.bind(proto::Value::Integer { value: 1 });
+        query.bind_named("a".to_string(), proto::Value::Integer { value: 2 });
+        let result = execute_stmt(&stream, &query).unwrap();
+        assert_eq!(result.rows[0].values.len(), 2);
+    }
+
+    #[test]
+    fn stmt_result_stats() {
+        let stream = memory_stream();
+        execute_stmt(&stream, &stmt("CREATE TABLE t (x INTEGER)")).unwrap();
+        let result = execute_stmt(&stream, &stmt("INSERT INTO t VALUES (1), (2), (3)")).unwrap();
+        assert_eq!(result.affected_row_count, 3);
+        assert_eq!(result.last_insert_rowid, Some(3));
+        assert_eq!(result.rows_written, 3);
+
+        let result = execute_stmt(&stream, &stmt("SELECT * FROM t")).unwrap();
+        assert_eq!(result.affected_row_count, 0);
+        assert_eq!(result.last_insert_rowid, None);
+        assert_eq!(result.rows_read, 3);
+
+        let result = execute_stmt(&stream, &stmt("DELETE FROM t WHERE x > 1")).unwrap();
+        assert_eq!(result.affected_row_count, 2);
+        assert_eq!(result.last_insert_rowid, None);
+    }
+
+    #[test]
+    fn stmt_result_changes_of_stmt_only() {
+        let stream = memory_stream();
+        execute_stmt(&stream, &stmt("CREATE TABLE t (x INTEGER)")).unwrap();
+        execute_stmt(&stream, &stmt("INSERT INTO t VALUES (1), (2), (3)")).unwrap();
+
+        // The counters of the connection still describe the INSERT.
+        let result = execute_stmt(&stream, &stmt("CREATE TABLE u (x INTEGER)")).unwrap();
+        assert_eq!(result.affected_row_count, 0);
+        assert_eq!(result.last_insert_rowid, None);
+
+        let result = execute_stmt(&stream, &stmt("UPDATE t SET x = x + 1 WHERE x > 1")).unwrap();
+        assert_eq!(result.affected_row_count, 2);
+        assert_eq!(result.last_insert_rowid, None);
+
+        let result = execute_stmt(&stream, &stmt("INSERT INTO u VALUES (1)")).unwrap();
+        assert_eq!(result.affected_row_count, 1);
+        assert_eq!(result.last_insert_rowid, Some(1));
+    }
+
+    #[test]
+    fn stmt_error_code() {
+        let stream = memory_stream();
+        execute_stmt(&stream, &stmt("CREATE TABLE t (x INTEGER UNIQUE)")).unwrap();
+        execute_stmt(&stream, &stmt("INSERT INTO t VALUES (1)")).unwrap();
+        let err = execute_stmt(&stream, &stmt("INSERT INTO t VALUES (1)")).unwrap_err();
+        let error = to_proto_error(&err);
+        assert_eq!(error.code, "SQLITE_CONSTRAINT_UNIQUE");
+        assert_eq!(error.message, "SQLite error: UNIQUE constraint failed: t.x");
+
+        let err = execute_stmt(&stream, &stmt("SELECT no_such_column FROM t")).unwrap_err();
+        assert_eq!(to_proto_error(&err).code, "SQLITE_ERROR");
+        match err {
+            HiisiError::SqliteError(err) => {
+                assert_eq!(err.offset, Some(7));
+                assert_eq!(err.sql.as_deref(), Some("SELECT no_such_column FROM t"));
+            }
+            _ => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn stored_sql() {
+        let stream = memory_stream();
+        stream.store_sql(1, "SELECT ?".to_string()).unwrap();
+        assert!(stream.store_sql(1, "SELECT 1".to_string()).is_err());
+        let mut query = proto::Stmt {
+            sql: None,
+            sql_id: Some(1),
+            args: vec![proto::Value::Integer { value: 42 }],
+            named_args: vec![],
+            want_rows: Some(true),
+            replication_index: None,
+        };
+        let result = execute_stmt(&stream, &query).unwrap();
+        assert!(matches!(
+            result.rows[0].values[0],
+            proto::Value::Integer { value: 42 }
+        ));
+
+        stream.close_sql(1);
+        let err = execute_stmt(&stream, &query).unwrap_err();
+        assert_eq!(to_proto_error(&err).code, "SQL_NOT_FOUND");
+
+        query.sql = Some("SELECT ?".to_string());
+        query.sql_id = None;
+        execute_stmt(&stream, &query).unwrap();
+    }
+
+    #[test]
+    fn describe() {
+        let stream = memory_stream();
+        execute_stmt(&stream, &stmt("CREATE TABLE t (x INTEGER, y TEXT)")).unwrap();
+        let prepared = stream
+            .conn
+            .prepare("SELECT x, y FROM t WHERE x = ? AND y = :y")
+            .unwrap();
+        let result = describe_stmt(&prepared).unwrap();
+        let params: Vec<_> = result.params.iter().map(|p| p.name.as_deref()).collect();
+        assert_eq!(params, vec![None, Some(":y")]);
+        let cols: Vec<_> = result
+            .cols
+            .iter()
+            .map(|c| (c.name.as_str(), c.decltype.as_deref()))
+            .collect();
+        assert_eq!(cols, vec![("x", Some("INTEGER")), ("y", Some("TEXT"))]);
+        assert!(result.is_readonly);
+        assert!(!result.is_explain);
+
+        let prepared = stream.conn.prepare("EXPLAIN DELETE FROM t").unwrap();
+        let result = describe_stmt(&prepared).unwrap();
+        assert!(result.is_explain);
+
+        let prepared = stream.conn.prepare("DELETE FROM t").unwrap();
+        let result = describe_stmt(&prepared).unwrap();
+        assert!(!result.is_readonly);
+    }
+
+    #[test]
+    fn sequence() {
+        let stream = memory_stream();
+        let script = "
+            -- Create a table.
+            CREATE TABLE t (x INTEGER UNIQUE);
+            INSERT INTO t VALUES (1);
+            INSERT INTO t VALUES (2); -- Trailing comment
+        ";
+        execute_sequence(&stream.conn, script).unwrap();
+        let result = execute_stmt(&stream, &stmt("SELECT count(*) FROM t")).unwrap();
+        assert!(matches!(
+            result.rows[0].values[0],
+            proto::Value::Integer { value: 2 }
+        ));
+
+        let script = "INSERT INTO t VALUES (3); INSERT INTO t VALUES (1); INSERT INTO t VALUES (4)";
+        let err = execute_sequence(&stream.conn, script).unwrap_err();
+        assert_eq!(to_proto_error(&err).code, "SQLITE_CONSTRAINT_UNIQUE");
+        let result = execute_stmt(&stream, &stmt("SELECT max(x) FROM t")).unwrap();
+        assert!(matches!(
+            result.rows[0].values[0],
+            proto::Value::Integer { value: 3 }
+        ));
+    }
+
+    #[test]
+    fn execute_single_stmt() {
+        let stream = memory_stream();
+        execute_stmt(&stream, &stmt("SELECT 1; -- comment")).unwrap();
+        let err = execute_stmt(&stream, &stmt("SELECT 1; SELECT 2")).unwrap_err();
+        assert_eq!(to_proto_error(&err).code, "SQL_MANY_STATEMENTS");
+        let err = execute_stmt(&stream, &stmt("  ")).unwrap_err();
+        assert_eq!(to_proto_error(&err).code, "SQL_NO_STATEMENT");
+    }
+
+    #[test]
+    fn transaction_state() {
+        let manager = temp_manager();
+        let get_autocommit =
+            || proto::StreamRequest::GetAutocommit(proto::GetAutocommitStreamReq {});
+        let resp = execute_req(&manager, None, vec![execute("BEGIN"), get_autocommit()]);
+        let baton = resp.baton.unwrap();
+        assert!(matches!(
+            resp.results[1],
+            proto::StreamResult::Ok {
+                response: proto::StreamResponse::GetAutocommit(proto::GetAutocommitStreamResp {
+                    is_autocommit: false
+                })
+            }
+        ));
+        assert!(manager.in_transaction(&baton));
+
+        let resp = execute_req(
+            &manager,
+            Some(baton.clone()),
+            vec![execute("COMMIT"), get_autocommit()],
+        );
+        assert!(matches!(
+            resp.results[1],
+            proto::StreamResult::Ok {
+                response: proto::StreamResponse::GetAutocommit(proto::GetAutocommitStreamResp {
+                    is_autocommit: true
+                })
+            }
+        ));
+        assert!(!manager.in_transaction(&baton));
+    }
+
+    #[test]
+    fn rotate_batons() {
+        let manager = temp_manager();
+        let resp = execute_req(&manager, None, vec![execute("SELECT 1")]);
+        let baton = resp.baton.unwrap();
+        let resp = execute_req(&manager, Some(baton.clone()), vec![execute("SELECT 1")]);
+        let next_baton = resp.baton.unwrap();
+        assert_ne!(baton, next_baton);
+
+        // A baton that has already been used cannot be replayed.
+        let req = Request {
+            database: "test".to_string(),
+            version: proto::Version::Hrana3,
+            req: proto::PipelineReqBody {
+                baton: Some(baton),
+                requests: vec![execute("SELECT 1")],
+            },
+        };
+        let err = execute_pipeline(&manager, req).unwrap_err();
+        assert_eq!(to_proto_error(&err).code, "BATON_REUSED");
+
+        // Closing the stream invalidates its baton.
+        let close = proto::StreamRequest::Close(proto::CloseStreamReq {});
+        let resp = execute_req(&manager, Some(next_baton.clone()), vec![close]);
+        assert!(resp.baton.is_none());
+        let err = manager.resume_stream("test", &next_baton).unwrap_err();
+        assert_eq!(to_proto_error(&err).code, "BATON_STREAM_CLOSED");
+    }
+
+    #[test]
+    fn reject_forged_batons() {
+        let manager = temp_manager();
+        for baton in ["", "not a baton", &uuid::Uuid::new_v4().to_string()] {
+            let err = manager.resume_stream("test", baton).unwrap_err();
+            assert_eq!(to_proto_error(&err).code, "BATON_INVALID");
+        }
+        // A baton issued by another server.
+        let resp = execute_req(&temp_manager(), None, vec![execute("SELECT 1")]);
+        let err = manager
+            .resume_stream("test", &resp.baton.unwrap())
+            .unwrap_err();
+        assert_eq!(to_proto_error(&err).code, "BATON_INVALID");
+    }
+
+    #[test]
+    fn reject_batons_of_other_namespaces() {
+        let manager = temp_manager();
+        manager.create_database("other").unwrap();
+        let resp = execute_req(&manager, None, vec![execute("CREATE TABLE t (x)")]);
+        let baton = resp.baton.unwrap();
+        let req = Request {
+            database: "other".to_string(),
+            version: proto::Version::Hrana3,
+            req: proto::PipelineReqBody {
+                baton: Some(baton.clone()),
+                requests: vec![execute("INSERT INTO t VALUES (1)")],
+            },
+        };
+        let err = execute_pipeline(&manager, req).unwrap_err();
+        assert_eq!(to_proto_error(&err).code, "BATON_INVALID");
+        let stream_id = manager.resume_stream("test", &baton).unwrap();
+        assert!(manager.get_stream("other", stream_id).is_err());
+        assert!(manager.drop_stream("other", stream_id).is_err());
+
+        // The refused request did not use up the baton or close the stream.
+        let resp = execute_req(&manager, Some(baton), vec![execute("SELECT * FROM t")]);
+        assert!(matches!(resp.results[0], proto::StreamResult::Ok { .. }));
+    }
+
+    fn expect_error(manager: &Rc<ResourceManager>, baton: String, code: &str) {
+        let req = Request {
+            database: "test".to_string(),
+            version: proto::Version::Hrana3,
+            req: proto::PipelineReqBody {
+                baton: Some(baton),
+                requests: vec![execute("SELECT 1")],
+            },
+        };
+        let err = execute_pipeline(manager, req).unwrap_err();
+        assert_eq!(to_proto_error(&err).code, code);
+    }
+
+    #[test]
+    fn expire_idle_streams() {
+        let manager = temp_manager_with_timeouts(StreamTimeouts {
+            idle: Duration::ZERO,
+            transaction: Duration::from_secs(60),
+            ..StreamTimeouts::default()
+        });
+        execute_req(&manager, None, vec![execute("CREATE TABLE t (x)")]);
+        let requests = vec![execute("BEGIN"), execute("INSERT INTO t VALUES (1)")];
+        let resp = execute_req(&manager, None, requests);
+        let baton = resp.baton.unwrap();
+        assert!(manager.in_transaction(&baton));
+
+        manager.expire_streams();
+        assert!(!manager.in_transaction(&baton));
+        expect_error(&manager, baton, "STREAM_EXPIRED");
+        // The transaction was rolled back and no longer holds the write lock.
+        let requests = vec![
+            execute("INSERT INTO t VALUES (2)"),
+            execute("SELECT * FROM t"),
+        ];
+        let resp = execute_req(&manager, None, requests);
+        match &resp.results[1] {
+            proto::StreamResult::Ok {
+                response: proto::StreamResponse::Execute(resp),
+            } => assert_eq!(resp.result.rows.len(), 1),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn expire_transactions() {
+        let manager = temp_manager_with_timeouts(StreamTimeouts {
+            idle: Duration::from_secs(60),
+            transaction: Duration::ZERO,
+            ..StreamTimeouts::default()
+        });
+        // Streams without a transaction do not expire.
+        let resp = execute_req(&manager, None, vec![execute("SELECT 1")]);
+        let resp = execute_req(&manager, resp.baton, vec![execute("BEGIN")]);
+        // A stream expires when it is used after its transaction timed out,
+        // even if the streams have not been checked for expiry.
+        expect_error(&manager, resp.baton.unwrap(), "STREAM_EXPIRED");
+    }
+
+    fn pipeline(baton: Option<String>, requests: Vec<proto::StreamRequest>) -> Request {
+        Request {
+            database: "test".to_string(),
+            version: proto::Version::Hrana3,
+            req: proto::PipelineReqBody { baton, requests },
+        }
+    }
+
+    #[test]
+    fn queue_writes_behind_writer() {
+        let manager = temp_manager();
+        execute_req(&manager, None, vec![execute("CREATE TABLE t (x)")]);
+        let requests = vec![execute("BEGIN"), execute("INSERT INTO t VALUES (1)")];
+        let writer = execute_req(&manager, None, requests).baton;
+
+        // Readers do not wait, and read the last committed snapshot.
+        let read = pipeline(None, vec![execute("SELECT * FROM t")]);
+        assert!(read.pending_write(&manager).is_none());
+        let resp = execute_pipeline(&manager, read).unwrap();
+        match &resp.results[0] {
+            proto::StreamResult::Ok {
+                response: proto::StreamResponse::Execute(resp),
+            } => assert!(resp.result.rows.is_empty()),
+            result => panic!("unexpected result {:?}", result),
+        }
+        // Writes of other streams wait, but the writer itself does not.
+        let reader = resp.baton;
+        let write = |baton: &Option<String>| {
+            let requests = vec![execute("SELECT 1; INSERT INTO t VALUES (2)")];
+            pipeline(baton.clone(), requests)
+        };
+        assert!(write(&None).pending_write(&manager).is_some());
+        assert!(write(&reader).pending_write(&manager).is_some());
+        assert!(write(&writer).pending_write(&manager).is_none());
+        // So do transactions that take the write lock when they begin.
+        let begin = |sql: &str| pipeline(None, vec![execute(sql)]);
+        assert!(begin("BEGIN IMMEDIATE").pending_write(&manager).is_some());
+        assert!(begin("/* lock */ begin exclusive transaction")
+            .pending_write(&manager)
+            .is_some());
+        assert!(begin("BEGIN").pending_write(&manager).is_none());
+
+        let resp = execute_req(&manager, writer, vec![execute("COMMIT")]);
+        assert!(write(&reader).pending_write(&manager).is_none());
+        // Closing a stream releases its write transaction.
+        let resp = execute_req(&manager, resp.baton, vec![execute("BEGIN IMMEDIATE")]);
+        assert!(write(&reader).pending_write(&manager).is_some());
+        let requests = vec![proto::StreamRequest::Close(proto::CloseStreamReq {})];
+        execute_req(&manager, resp.baton, requests);
+        assert!(write(&reader).pending_write(&manager).is_none());
+    }
+
+    #[test]
+    fn hrana2_rejects_v3_features() {
+        let manager = temp_manager();
+        let mut batch = proto::Batch::single(stmt("SELECT 1"));
+        batch.steps[0].condition = Some(proto::BatchCond::Not {
+            cond: Box::new(proto::BatchCond::IsAutocommit {}),
+        });
+        let requests = vec![
+            proto::StreamRequest::GetAutocommit(proto::GetAutocommitStreamReq {}),
+            proto::StreamRequest::Batch(proto::BatchStreamReq { batch }),
+            execute("SELECT 1"),
+        ];
+        let req = Request {
+            database: "test".to_string(),
+            version: proto::Version::Hrana2,
+            req: proto::PipelineReqBody {
+                baton: None,
+                requests,
+            },
+        };
+        let resp = execute_pipeline(&manager, req).unwrap();
+        for result in &resp.results[..2] {
+            match result {
+                proto::StreamResult::Error { error } => assert_eq!(error.code, "PROTOCOL_ERROR"),
+                _ => panic!("expected an error, got {:?}", result),
+            }
+        }
+        assert!(matches!(resp.results[2], proto::StreamResult::Ok { .. }));
+    }
+
+    #[test]
+    fn v1_requests_are_stateless() {
+        let manager = temp_manager();
+        let execute_v1 = |sql: &str| {
+            let req = proto::v1::ExecuteReqBody { stmt: stmt(sql) };
+            execute_v1_stmt(manager.clone(), "test", req).unwrap()
+        };
+        execute_v1("CREATE TABLE t (x INTEGER)");
+        // The transaction that the batch leaves open is rolled back when its
+        // connection is closed.
+        let mut batch = proto::Batch::single(stmt("BEGIN"));
+        batch.steps.push(proto::BatchStep {
+            condition: None,
+            stmt: stmt("INSERT INTO t VALUES (1)"),
+        });
+        let req = proto::v1::BatchReqBody { batch };
+        let resp = execute_v1_batch(manager.clone(), "test", req).unwrap();
+        assert!(resp.result.step_errors.iter().all(|error| error.is_none()));
+        let resp = execute_v1("SELECT count(*) AS n FROM t");
+        let json = serde_json::to_value(&resp).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "result": {
+                    "cols": [{"name": "n"}],
+                    "rows": [[{"type": "integer", "value": "0"}]],
+                    "affected_row_count": 0,
+                    "last_insert_rowid": null,
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn v1_batch_and_or_conditions() {
+        let manager = temp_manager();
+        let step = |condition, sql| proto::BatchStep {
+            condition: Some(condition),
+            stmt: stmt(sql),
+        };
+        let ok = |step| proto::BatchCond::Ok { step };
+        let mut batch = proto::Batch::single(stmt("SELECT 1"));
+        batch.steps.push(step(
+            proto::BatchCond::And(proto::BatchCondList {
+                conds: vec![ok(0), ok(0)],
+            }),
+            "SELECT 2",
+        ));
+        batch.steps.push(step(
+            proto::BatchCond::Or(proto::BatchCondList {
+                conds: vec![proto::BatchCond::Error { step: 0 }],
+            }),
+            "SELECT 3",
+        ));
+        let req = proto::v1::BatchReqBody { batch };
+        let resp = execute_v1_batch(manager.clone(), "test", req).unwrap();
+        assert!(resp.result.step_errors.iter().all(|error| error.is_none()));
+        let executed: Vec<bool> = resp
+            .result
+            .step_results
+            .iter()
+            .map(|result| result.is_some())
+            .collect();
+        assert_eq!(executed, vec![true, true, false]);
+    }
+
+    #[test]
+    fn want_rows() {
+        let stream = memory_stream();
+        execute_stmt(&stream, &stmt("CREATE TABLE t (x INTEGER)")).unwrap();
+        let query = proto::Stmt::new("INSERT INTO t VALUES (1), (2) RETURNING x", false);
+        let result = execute_stmt(&stream, &query).unwrap();
+        assert!(result.rows.is_empty());
+        assert_eq!(result.cols.len(), 1);
+        assert_eq!(result.affected_row_count, 2);
+        assert_eq!(result.last_insert_rowid, Some(2));
+
+        let query = proto::Stmt::new("SELECT x FROM t", false);
+        let result = execute_stmt(&stream, &query).unwrap();
+        assert!(result.rows.is_empty());
+        assert_eq!(result.rows_read, 2);
+    }
+
+    #[test]
+    fn limit_buffered_results() {
+        let stream = memory_stream();
+        let blob = |mib: usize| stmt(&format!("SELECT zeroblob({} * 1024 * 1024)", mib));
+        execute_stmt(&stream, &blob(6)).unwrap();
+        let err = execute_stmt(&stream, &blob(11)).unwrap_err();
+        assert_eq!(to_proto_error(&err).code, "RESPONSE_TOO_LARGE");
+        // Rows that the client does not want are not limited.
+        let mut query = blob(11);
+        query.want_rows = Some(false);
+        execute_stmt(&stream, &query).unwrap();
+
+        // The steps of a batch share the limit.
+        let batch = proto::Batch::transactional([blob(6), blob(6)]);
+        let result = execute_batch(&stream, &batch).unwrap();
+        assert!(result.step_results[1].is_some());
+        let error = result.step_errors[2].as_ref().unwrap();
+        assert_eq!(error.code, "RESPONSE_TOO_LARGE");
+    }
+}
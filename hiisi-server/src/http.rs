@@ -1,30 +1,678 @@
-use bytes::{Bytes, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 
 pub use http::StatusCode;
 
-pub fn format_response(body: Bytes, status: http::StatusCode) -> Bytes {
-    let n = body.len();
+/// Content type of responses whose body is plain text, such as errors.
+pub const TEXT_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
 
-    let response = http::Response::builder().status(status).body(body).unwrap();
+/// Maximum number of headers in a request.
+pub const MAX_HEADERS: usize = 64;
 
-    let mut response_bytes = BytesMut::new();
-    response_bytes.extend_from_slice(
-        format!(
-            "HTTP/1.1 {} {}\r\n",
-            response.status().as_u16(),
-            response.status().canonical_reason().unwrap_or("")
-        )
-        .as_bytes(),
-    );
+// Maximum size of the request line and headers of a request in bytes.
+const MAX_HEAD_SIZE: usize = 16 * 1024;
 
-    for (key, value) in response.headers() {
-        response_bytes.extend_from_slice(
-            format!("{}: {}\r\n", key.as_str(), value.to_str().unwrap()).as_bytes(),
+// Maximum size of the body of a request in bytes.
+const MAX_BODY_SIZE: usize = 10 * 1024 * 1024;
+
+// Maximum length of the line that precedes a chunk of a chunked body, which
+// consists of the size of the chunk and its extensions.
+const MAX_CHUNK_LINE_SIZE: usize = 1024;
+
+/// An error in the framing of a request.
+///
+/// The error is fatal to the connection, because we can no longer tell where
+/// the next request starts.
+#[derive(Debug)]
+pub struct FramingError {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+impl FramingError {
+    fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, message)
+    }
+}
+
+/// A request that has been received completely.
+#[derive(Debug)]
+pub struct RawRequest {
+    /// The request line and headers, including the empty line that ends them.
+    pub head: Bytes,
+    /// The body, which has been decoded if it was sent in chunked transfer
+    /// encoding.
+    pub body: Bytes,
+    /// Whether the client wants to keep the connection open after the
+    /// response.
+    pub keep_alive: bool,
+}
+
+/// Parses requests from the data received on a connection.
+///
+/// The parser keeps its state between calls, so a request can be received
+/// in any number of pieces.
+#[derive(Default)]
+pub struct RequestParser {
+    state: ParserState,
+}
+
+#[derive(Default)]
+enum ParserState {
+    /// Waiting for the request line and headers.
+    #[default]
+    Head,
+    /// Waiting for a body of a known length.
+    Body { req: RawRequest, len: usize },
+    /// Decoding a body in chunked transfer encoding.
+    Chunked {
+        req: RawRequest,
+        decoder: ChunkedDecoder,
+    },
+}
+
+impl RequestParser {
+    /// Take the next request from the received data, or return `None` if
+    /// the request has not been received completely yet.
+    pub fn parse(&mut self, buf: &mut BytesMut) -> Result<Option<RawRequest>, FramingError> {
+        if let ParserState::Head = self.state {
+            let head = match parse_head(buf)? {
+                Some(head) => head,
+                None => return Ok(None),
+            };
+            let req = RawRequest {
+                head: buf.split_to(head.len).freeze(),
+                body: Bytes::new(),
+                keep_alive: head.keep_alive,
+            };
+            self.state = match head.framing {
+                Framing::Length(len) => ParserState::Body { req, len },
+                Framing::Chunked => ParserState::Chunked {
+                    req,
+                    decoder: ChunkedDecoder::default(),
+                },
+            };
+        }
+        match &mut self.state {
+            ParserState::Head => unreachable!(),
+            ParserState::Body { len, .. } if buf.len() < *len => return Ok(None),
+            ParserState::Body { req, len } => req.body = buf.split_to(*len).freeze(),
+            ParserState::Chunked { req, decoder } => {
+                if !decoder.decode(buf)? {
+                    return Ok(None);
+                }
+                req.body = std::mem::take(&mut decoder.body).freeze();
+            }
+        }
+        match std::mem::take(&mut self.state) {
+            ParserState::Body { req, .. } | ParserState::Chunked { req, .. } => Ok(Some(req)),
+            ParserState::Head => unreachable!(),
+        }
+    }
+}
+
+/// The request line and headers of a request.
+struct Head {
+    /// The length of the request line and headers in bytes.
+    len: usize,
+    framing: Framing,
+    keep_alive: bool,
+}
+
+/// How the end of the body of a request is determined.
+enum Framing {
+    /// The body has a known length, which is zero if the request has no body.
+    Length(usize),
+    /// The body is sent in chunked transfer encoding.
+    Chunked,
+}
+
+/// Parse the request line and headers at the start of a buffer.
+fn parse_head(buf: &[u8]) -> Result<Option<Head>, FramingError> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut req = httparse::Request::new(&mut headers);
+    let head_len = match req.parse(buf) {
+        Ok(httparse::Status::Complete(head_len)) => head_len,
+        Ok(httparse::Status::Partial) => {
+            if buf.len() > MAX_HEAD_SIZE {
+                return Err(head_too_large());
+            }
+            return Ok(None);
+        }
+        Err(httparse::Error::TooManyHeaders) => {
+            return Err(FramingError::new(
+                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                format!("Request has more than {} headers", MAX_HEADERS),
+            ))
+        }
+        Err(err) => {
+            return Err(FramingError::bad_request(format!(
+                "Malformed request: {}",
+                err
+            )))
+        }
+    };
+    if head_len > MAX_HEAD_SIZE {
+        return Err(head_too_large());
+    }
+    let len = content_length(&req)?;
+    let framing = if is_chunked(&req)? {
+        // A request with both headers is a vector for request smuggling.
+        if len.is_some() {
+            return Err(FramingError::bad_request(
+                "Request has both Content-Length and Transfer-Encoding",
+            ));
+        }
+        Framing::Chunked
+    } else {
+        let len = len.unwrap_or(0);
+        if len > MAX_BODY_SIZE {
+            return Err(body_too_large());
+        }
+        Framing::Length(len)
+    };
+    Ok(Some(Head {
+        len: head_len,
+        framing,
+        keep_alive: is_keep_alive(&req),
+    }))
+}
+
+/// Returns `true` if the client wants to keep the connection open after the
+/// response. HTTP/1.1 connections are persistent unless the client asks to
+/// close them, and HTTP/1.0 connections are not unless the client asks to
+/// keep them open.
+fn is_keep_alive(req: &httparse::Request) -> bool {
+    let mut close = false;
+    let mut keep_alive = false;
+    for header in req.headers.iter() {
+        if !header.name.eq_ignore_ascii_case("Connection") {
+            continue;
+        }
+        let value = std::str::from_utf8(header.value).unwrap_or_default();
+        for option in value.split(',').map(str::trim) {
+            close |= option.eq_ignore_ascii_case("close");
+            keep_alive |= option.eq_ignore_ascii_case("keep-alive");
+        }
+    }
+    !close && (req.version == Some(1) || keep_alive)
+}
+
+fn head_too_large() -> FramingError {
+    FramingError::new(
+        StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+        format!("Request head exceeds the limit of {} bytes", MAX_HEAD_SIZE),
+    )
+}
+
+fn body_too_large() -> FramingError {
+    FramingError::new(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        format!("Request body exceeds the limit of {} bytes", MAX_BODY_SIZE),
+    )
+}
+
+/// Returns the length of the body of a request from its `Content-Length`
+/// header, if it has one.
+fn content_length(req: &httparse::Request) -> Result<Option<usize>, FramingError> {
+    let invalid = || FramingError::bad_request("Invalid Content-Length");
+    let mut len = None;
+    for header in req.headers.iter() {
+        if !header.name.eq_ignore_ascii_case("Content-Length") {
+            continue;
+        }
+        // Unlike `str::parse`, we do not accept a sign.
+        if header.value.is_empty() || !header.value.iter().all(u8::is_ascii_digit) {
+            return Err(invalid());
+        }
+        let value: usize = std::str::from_utf8(header.value)
+            .unwrap()
+            .parse()
+            .map_err(|_| invalid())?;
+        // Requests with conflicting lengths are a vector for request
+        // smuggling, so we reject them.
+        if len.is_some_and(|len| len != value) {
+            return Err(invalid());
+        }
+        len = Some(value);
+    }
+    Ok(len)
+}
+
+/// Returns `true` if the body of a request is sent in chunked transfer
+/// encoding, which is the only transfer coding we support.
+fn is_chunked(req: &httparse::Request) -> Result<bool, FramingError> {
+    let mut codings = Vec::new();
+    for header in req.headers.iter() {
+        if !header.name.eq_ignore_ascii_case("Transfer-Encoding") {
+            continue;
+        }
+        let value = std::str::from_utf8(header.value)
+            .map_err(|_| FramingError::bad_request("Invalid Transfer-Encoding"))?;
+        codings.extend(value.split(',').map(str::trim).filter(|c| !c.is_empty()));
+    }
+    match codings.as_slice() {
+        [] => Ok(false),
+        [coding] if coding.eq_ignore_ascii_case("chunked") => Ok(true),
+        // Without chunked as the final coding, the body would extend until
+        // the connection is closed.
+        [.., last] if !last.eq_ignore_ascii_case("chunked") => Err(FramingError::bad_request(
+            "Transfer-Encoding must end with chunked",
+        )),
+        _ => Err(FramingError::new(
+            StatusCode::NOT_IMPLEMENTED,
+            "Only chunked Transfer-Encoding is supported",
+        )),
+    }
+}
+
+/// Decodes a body in chunked transfer encoding as it is received.
+///
+/// Chunk extensions and trailer fields are parsed but ignored, because they
+/// carry nothing that we need.
+#[derive(Default)]
+struct ChunkedDecoder {
+    /// The data of the chunks decoded so far.
+    body: BytesMut,
+    state: ChunkedState,
+    /// The size of the trailer fields received so far.
+    trailers_len: usize,
+}
+
+#[derive(Default)]
+enum ChunkedState {
+    /// Waiting for the line with the size of the next chunk.
+    #[default]
+    Size,
+    /// Receiving the data of a chunk, with the number of bytes left.
+    Data(usize),
+    /// Waiting for the CRLF that follows the data of a chunk.
+    DataEnd,
+    /// Receiving the trailer fields that follow the last chunk.
+    Trailers,
+}
+
+impl ChunkedDecoder {
+    /// Decode the received data, consuming it from the buffer. Returns
+    /// `true` when the whole body has been decoded.
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<bool, FramingError> {
+        loop {
+            match self.state {
+                ChunkedState::Size => {
+                    let line = match take_line(buf, MAX_CHUNK_LINE_SIZE)? {
+                        Some(line) => line,
+                        None => return Ok(false),
+                    };
+                    let size = parse_chunk_size(&line)?;
+                    if size == 0 {
+                        self.state = ChunkedState::Trailers;
+                    } else if size > MAX_BODY_SIZE - self.body.len() {
+                        return Err(body_too_large());
+                    } else {
+                        self.state = ChunkedState::Data(size);
+                    }
+                }
+                ChunkedState::Data(left) => {
+                    if buf.is_empty() {
+                        return Ok(false);
+                    }
+                    let n = left.min(buf.len());
+                    self.body.extend_from_slice(&buf.split_to(n));
+                    self.state = if n == left {
+                        ChunkedState::DataEnd
+                    } else {
+                        ChunkedState::Data(left - n)
+                    };
+                }
+                ChunkedState::DataEnd => {
+                    if buf.len() < 2 {
+                        return Ok(false);
+                    }
+                    if &buf[..2] != b"\r\n" {
+                        return Err(FramingError::bad_request(
+                            "Chunk data is not followed by CRLF",
+                        ));
+                    }
+                    let _ = buf.split_to(2);
+                    self.state = ChunkedState::Size;
+                }
+                ChunkedState::Trailers => {
+                    let limit = MAX_HEAD_SIZE.saturating_sub(self.trailers_len);
+                    let line = match take_line(buf, limit) {
+                        Ok(Some(line)) => line,
+                        Ok(None) => return Ok(false),
+                        Err(_) => return Err(head_too_large()),
+                    };
+                    // An empty line ends the trailer fields and the body.
+                    if line.is_empty() {
+                        return Ok(true);
+                    }
+                    if !line.contains(&b':') {
+                        return Err(FramingError::bad_request("Malformed trailer field"));
+                    }
+                    self.trailers_len += line.len() + 2;
+                }
+            }
+        }
+    }
+}
+
+/// Take a line that ends with CRLF from the start of a buffer, returning the
+/// line without the CRLF, or `None` if the line is not complete yet.
+fn take_line(buf: &mut BytesMut, max_len: usize) -> Result<Option<BytesMut>, FramingError> {
+    let end = match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(end) => end,
+        None if buf.len() > max_len => {
+            return Err(FramingError::bad_request(
+                "Line in chunked body is too long",
+            ))
+        }
+        None => return Ok(None),
+    };
+    if end > max_len {
+        return Err(FramingError::bad_request(
+            "Line in chunked body is too long",
+        ));
+    }
+    let line = buf.split_to(end);
+    let _ = buf.split_to(2);
+    Ok(Some(line))
+}
+
+/// Parse the size of a chunk, which is in hexadecimal and may be followed by
+/// chunk extensions that start with a semicolon.
+fn parse_chunk_size(line: &[u8]) -> Result<usize, FramingError> {
+    let invalid = || FramingError::bad_request("Invalid chunk size");
+    let digits = line
+        .iter()
+        .position(|b| !b.is_ascii_hexdigit())
+        .unwrap_or(line.len());
+    let (size, rest) = line.split_at(digits);
+    // Whitespace is allowed before the extensions.
+    let rest = rest.trim_ascii_start();
+    if size.is_empty() || !(rest.is_empty() || rest[0] == b';') {
+        return Err(invalid());
+    }
+    let size = std::str::from_utf8(size).unwrap();
+    usize::from_str_radix(size, 16).map_err(|_| invalid())
+}
+
+/// Returns the value of a header of a request, ignoring the case of its name.
+pub fn find_header<'b>(req: &httparse::Request<'_, 'b>, name: &str) -> Option<&'b [u8]> {
+    req.headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case(name))
+        .map(|header| header.value)
+}
+
+/// Space reserved for the head at the start of a buffer from `response_buf`,
+/// which fits the head of any response that we send.
+pub const RESPONSE_HEAD_SPACE: usize = 256;
+
+/// Returns a buffer to write the body of a response to, with space reserved
+/// for the head at the start. The body is written after the reserved space,
+/// and `format_response_in_place` then writes the head right in front of
+/// it, so that the body is not copied.
+pub fn response_buf() -> BytesMut {
+    let mut buf = BytesMut::with_capacity(RESPONSE_HEAD_SPACE + 1024);
+    buf.resize(RESPONSE_HEAD_SPACE, 0);
+    buf
+}
+
+/// Format a response whose body has been written to a buffer from
+/// `response_buf`.
+pub fn format_response_in_place(
+    mut buf: BytesMut,
+    status: StatusCode,
+    content_type: &str,
+    keep_alive: bool,
+) -> Bytes {
+    let body_len = buf.len() - RESPONSE_HEAD_SPACE;
+    let mut head = [0; RESPONSE_HEAD_SPACE];
+    let mut w = &mut head[..];
+    if write_head(&mut w, status, content_type, keep_alive, Some(body_len)).is_err() {
+        // The head does not fit in the reserved space, which takes a content
+        // type that we never send.
+        let body = buf.freeze().slice(RESPONSE_HEAD_SPACE..);
+        return format_response(body, status, content_type, keep_alive);
+    }
+    let start = w.len();
+    buf[start..RESPONSE_HEAD_SPACE].copy_from_slice(&head[..RESPONSE_HEAD_SPACE - start]);
+    buf.freeze().slice(start..)
+}
+
+/// Format a response with a body that has been serialized already.
+pub fn format_response(
+    body: Bytes,
+    status: StatusCode,
+    content_type: &str,
+    keep_alive: bool,
+) -> Bytes {
+    let mut buf = BytesMut::with_capacity(RESPONSE_HEAD_SPACE + body.len()).writer();
+    // Writing to a `BytesMut` does not fail.
+    write_head(&mut buf, status, content_type, keep_alive, Some(body.len())).unwrap();
+    let mut buf = buf.into_inner();
+    buf.extend_from_slice(&body);
+    buf.freeze()
+}
+
+/// Format the head of a response whose body is sent with chunked transfer
+/// encoding.
+pub fn format_chunked_response_head(
+    status: StatusCode,
+    content_type: &str,
+    keep_alive: bool,
+) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(RESPONSE_HEAD_SPACE).writer();
+    write_head(&mut buf, status, content_type, keep_alive, None).unwrap();
+    buf.into_inner()
+}
+
+/// Write the status line and headers of a response. A response without a
+/// content length is sent with chunked transfer encoding.
+fn write_head<W: std::io::Write>(
+    w: &mut W,
+    status: StatusCode,
+    content_type: &str,
+    keep_alive: bool,
+    content_length: Option<usize>,
+) -> std::io::Result<()> {
+    write!(
+        w,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nConnection: {}\r\n",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or(""),
+        content_type,
+        connection_option(keep_alive)
+    )?;
+    match content_length {
+        Some(n) => write!(w, "Content-Length: {}\r\n\r\n", n),
+        None => w.write_all(b"Transfer-Encoding: chunked\r\n\r\n"),
+    }
+}
+
+/// Returns the value of the `Connection` header of a response. We send it
+/// even when the connection is kept open, because HTTP/1.0 clients close the
+/// connection otherwise.
+fn connection_option(keep_alive: bool) -> &'static str {
+    if keep_alive {
+        "keep-alive"
+    } else {
+        "close"
+    }
+}
+
+/// Append a chunk of body data in chunked transfer encoding to a buffer.
+pub fn format_chunk(buf: &mut BytesMut, data: &[u8]) {
+    if data.is_empty() {
+        // An empty chunk would terminate the body.
+        return;
+    }
+    buf.extend_from_slice(format!("{:x}\r\n", data.len()).as_bytes());
+    buf.extend_from_slice(data);
+    buf.extend_from_slice(b"\r\n");
+}
+
+/// Append the last chunk, which terminates a body in chunked transfer
+/// encoding, to a buffer.
+pub fn format_last_chunk(buf: &mut BytesMut) {
+    buf.extend_from_slice(b"0\r\n\r\n");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Parse requests that are received in pieces of a given size.
+    fn parse_in_pieces(data: &[u8], piece: usize) -> Result<Vec<RawRequest>, FramingError> {
+        let mut parser = RequestParser::default();
+        let mut buf = BytesMut::new();
+        let mut reqs = Vec::new();
+        for piece in data.chunks(piece) {
+            buf.extend_from_slice(piece);
+            while let Some(req) = parser.parse(&mut buf)? {
+                reqs.push(req);
+            }
+        }
+        assert!(buf.is_empty());
+        Ok(reqs)
+    }
+
+    fn parse_err(data: &[u8]) -> StatusCode {
+        parse_in_pieces(data, data.len()).unwrap_err().status
+    }
+
+    #[test]
+    fn frame_requests() {
+        let mut data = b"POST /v3/pipeline HTTP/1.1\r\nContent-Length: 4\r\n\r\nbody".to_vec();
+        data.extend_from_slice(b"GET /v3 HTTP/1.1\r\n\r\n");
+        for piece in [1, 7, data.len()] {
+            let reqs = parse_in_pieces(&data, piece).unwrap();
+            assert_eq!(reqs.len(), 2);
+            assert!(reqs[0].head.starts_with(b"POST /v3/pipeline"));
+            assert_eq!(&reqs[0].body[..], b"body");
+            assert!(reqs[1].head.starts_with(b"GET /v3"));
+            assert!(reqs[1].body.is_empty());
+        }
+    }
+
+    #[test]
+    fn keep_alive() {
+        let keep_alive = |head: &[u8]| parse_in_pieces(head, head.len()).unwrap()[0].keep_alive;
+        assert!(keep_alive(b"GET / HTTP/1.1\r\n\r\n"));
+        assert!(!keep_alive(b"GET / HTTP/1.1\r\nConnection: Close\r\n\r\n"));
+        assert!(!keep_alive(b"GET / HTTP/1.0\r\n\r\n"));
+        assert!(keep_alive(
+            b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n"
+        ));
+        assert!(!keep_alive(
+            b"GET / HTTP/1.0\r\nConnection: keep-alive\r\nConnection: TE, close\r\n\r\n"
+        ));
+    }
+
+    #[test]
+    fn format_responses_in_place() {
+        let status = StatusCode::SERVICE_UNAVAILABLE;
+        let body = b"{\"message\":\"database is locked\"}";
+        let mut buf = response_buf();
+        buf.extend_from_slice(body);
+        let resp = format_response_in_place(buf, status, TEXT_CONTENT_TYPE, false);
+        assert_eq!(
+            &resp[..],
+            b"HTTP/1.1 503 Service Unavailable\r\n\
+              Content-Type: text/plain; charset=utf-8\r\n\
+              Connection: close\r\n\
+              Content-Length: 32\r\n\r\n\
+              {\"message\":\"database is locked\"}"
         );
+        let copied = format_response(Bytes::from_static(body), status, TEXT_CONTENT_TYPE, false);
+        assert_eq!(resp, copied);
+        // A head that does not fit in the reserved space is still formatted.
+        let content_type = "x".repeat(RESPONSE_HEAD_SPACE);
+        let mut buf = response_buf();
+        buf.extend_from_slice(body);
+        let resp = format_response_in_place(buf, status, &content_type, true);
+        let copied = format_response(Bytes::from_static(body), status, &content_type, true);
+        assert_eq!(resp, copied);
     }
 
-    response_bytes.extend_from_slice(format!("Content-Length: {}\r\n\r\n", n).as_bytes());
-    response_bytes.extend_from_slice(response.into_body().as_ref());
+    #[test]
+    fn decode_chunked_bodies() {
+        let data = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            4\r\nWiki\r\n\
+            7;name=value;flag\r\npedia i\r\n\
+            B \t; ext\r\nn \r\nchunks.\r\n\
+            0\r\nExpires: never\r\n\r\n\
+            GET / HTTP/1.1\r\n\r\n";
+        for piece in [1, 3, data.len()] {
+            let reqs = parse_in_pieces(data, piece).unwrap();
+            assert_eq!(reqs.len(), 2);
+            assert_eq!(&reqs[0].body[..], b"Wikipedia in \r\nchunks.");
+            assert!(reqs[1].body.is_empty());
+        }
+    }
+
+    #[test]
+    fn reject_invalid_framing() {
+        assert_eq!(
+            parse_err(b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n"),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            parse_err(b"POST / HTTP/1.1\r\nContent-Length: 1\r\ncontent-length: 2\r\n\r\n"),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            parse_err(b"POST / HTTP/1.1\r\nContent-Length: 99999999999\r\n\r\n"),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        let mut head = b"GET / HTTP/1.1\r\nX-Padding: ".to_vec();
+        head.resize(MAX_HEAD_SIZE + 1, b'a');
+        assert_eq!(
+            parse_err(&head),
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+        );
+        assert_eq!(parse_err(b"\x00\r\n\r\n"), StatusCode::BAD_REQUEST);
+    }
 
-    response_bytes.into()
+    #[test]
+    fn reject_invalid_chunked_bodies() {
+        let chunked = |body: &[u8]| {
+            let mut data = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+            data.extend_from_slice(body);
+            parse_err(&data)
+        };
+        assert_eq!(chunked(b"x\r\n"), StatusCode::BAD_REQUEST);
+        assert_eq!(chunked(b"\r\n"), StatusCode::BAD_REQUEST);
+        assert_eq!(chunked(b"-1\r\n"), StatusCode::BAD_REQUEST);
+        assert_eq!(chunked(b"4 x\r\n"), StatusCode::BAD_REQUEST);
+        assert_eq!(chunked(b"fffffffffffffffff\r\n"), StatusCode::BAD_REQUEST);
+        assert_eq!(chunked(b"2\r\nabc\r\n"), StatusCode::BAD_REQUEST);
+        assert_eq!(chunked(b"0\r\nno colon\r\n\r\n"), StatusCode::BAD_REQUEST);
+        assert_eq!(chunked(b"ffffffff\r\n"), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(
+            chunked(&[b'1'; MAX_CHUNK_LINE_SIZE + 1]),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            parse_err(
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 1\r\n\r\n"
+            ),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            parse_err(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n"),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            parse_err(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n"),
+            StatusCode::NOT_IMPLEMENTED
+        );
+    }
 }
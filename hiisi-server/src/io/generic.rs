@@ -1,8 +1,9 @@
 use bytes::{Bytes, BytesMut};
 use polling::{Event, Events, Poller};
 
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 pub struct IO<C> {
     poller: Poller,
@@ -10,17 +11,37 @@ pub struct IO<C> {
     key_seq: usize,
     submissions: HashMap<usize, Completion<C>>,
     completions: VecDeque<Completion<C>>,
+    /// Pending timeouts ordered by their deadline. The key sequence number
+    /// orders timeouts with the same deadline.
+    timeouts: BTreeMap<(Instant, usize), TimeoutCallback<C>>,
+    clock: Clock,
     context: C,
 }
 
+/// The clock that timeouts are measured with, which is the system clock.
+#[derive(Clone, Default)]
+pub struct Clock;
+
+impl Clock {
+    pub fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
 impl<C> IO<C> {
     pub fn new(context: C) -> Self {
+        Self::with_clock(context, Clock)
+    }
+
+    pub fn with_clock(context: C, clock: Clock) -> Self {
         Self {
             poller: Poller::new().unwrap(),
             events: Events::new(),
             key_seq: 0,
             submissions: HashMap::new(),
             completions: VecDeque::new(),
+            timeouts: BTreeMap::new(),
+            clock,
             context,
         }
     }
@@ -29,6 +50,11 @@ impl<C> IO<C> {
         &self.context
     }
 
+    /// Returns the current time of the clock that timeouts are measured with.
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
     pub fn run_once(&mut self) {
         log::debug!("Running IO loop");
         self.events.clear();
@@ -38,6 +64,7 @@ impl<C> IO<C> {
         );
         self.flush_submissions();
         self.flush_completions();
+        self.flush_timeouts();
     }
 
     fn flush_submissions(&mut self) {
@@ -76,6 +103,22 @@ impl<C> IO<C> {
         }
     }
 
+    fn flush_timeouts(&mut self) {
+        // Timeouts that the callbacks set are flushed on the next run.
+        let pending = self.timeouts.split_off(&(self.now(), usize::MAX));
+        let expired = std::mem::replace(&mut self.timeouts, pending);
+        for cb in expired.into_values() {
+            cb(self);
+        }
+    }
+
+    /// Call a callback once a duration has elapsed.
+    pub fn timeout(&mut self, duration: Duration, cb: TimeoutCallback<C>) {
+        log::debug!("Setting timeout of {:?}", duration);
+        let key = self.get_key();
+        self.timeouts.insert((self.now() + duration, key), cb);
+    }
+
     pub fn accept(
         &mut self,
         server_sock: Rc<socket2::Socket>,
@@ -105,6 +148,12 @@ impl<C> IO<C> {
         drop(sock);
     }
 
+    /// Shut down a socket, which completes its pending receive with no data.
+    pub fn shutdown(&mut self, sock: Rc<socket2::Socket>) {
+        log::debug!("Shutting down sockfd {:?}", sock);
+        let _ = sock.shutdown(std::net::Shutdown::Both);
+    }
+
     pub fn recv(&mut self, sock: Rc<socket2::Socket>, cb: RecvCallback<C>) {
         log::debug!("Receiving on sockfd {:?}", sock);
         let c = Completion::Recv { sock, cb };
@@ -204,14 +253,37 @@ impl<C> Completion<C> {
             Completion::Recv { sock, cb } => {
                 let mut buf = BytesMut::with_capacity(4096);
                 let uninit = buf.spare_capacity_mut();
-                let n = sock.recv(uninit).unwrap();
+                let n = match sock.recv(uninit) {
+                    Ok(n) => n,
+                    Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {
+                        return io.recv(sock, cb);
+                    }
+                    // A receive that fails, for example because the client
+                    // reset the connection, completes like a closed
+                    // connection.
+                    Err(err) => {
+                        log::debug!("Receiving on sockfd {:?} failed: {}", sock, err);
+                        0
+                    }
+                };
                 unsafe {
                     buf.set_len(n);
                 }
                 cb(io, sock, &buf[..], n);
             }
             Completion::Send { sock, buf, n, cb } => {
-                let n = sock.send(&buf[..n]).unwrap();
+                let n = match sock.send(&buf[..n]) {
+                    Ok(n) => n,
+                    Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {
+                        return io.send(sock, buf, n, cb);
+                    }
+                    // A send that fails completes with nothing sent, which
+                    // tells the callback that the connection is closed.
+                    Err(err) => {
+                        log::debug!("Sending on sockfd {:?} failed: {}", sock, err);
+                        0
+                    }
+                };
                 cb(io, sock, n);
             }
         }
@@ -226,3 +298,67 @@ type AcceptCallback<C> =
 type RecvCallback<C> = fn(&mut IO<C>, Rc<socket2::Socket>, &[u8], usize);
 
 type SendCallback<C> = fn(&mut IO<C>, Rc<socket2::Socket>, usize);
+
+type TimeoutCallback<C> = fn(&mut IO<C>);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use socket2::{Domain, Socket, Type};
+    use std::cell::Cell;
+
+    /// The number of bytes that the last receive and send completed with.
+    #[derive(Default)]
+    struct Completed {
+        recv: Cell<Option<usize>>,
+        send: Cell<Option<usize>>,
+    }
+
+    fn on_recv(io: &mut IO<Completed>, _sock: Rc<Socket>, _buf: &[u8], n: usize) {
+        io.context().recv.set(Some(n));
+    }
+
+    fn on_send(io: &mut IO<Completed>, _sock: Rc<Socket>, n: usize) {
+        io.context().send.set(Some(n));
+    }
+
+    /// Returns the client and server sockets of a connection.
+    fn connect() -> (Socket, Socket) {
+        let listener = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        let addr: std::net::SocketAddr = "127.0.0.1:0".parse().unwrap();
+        listener.bind(&addr.into()).unwrap();
+        listener.listen(1).unwrap();
+        let client = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        client.connect(&listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    fn run_until(io: &mut IO<Completed>, done: impl Fn(&Completed) -> bool) {
+        for _ in 0..1000 {
+            if done(io.context()) {
+                return;
+            }
+            io.run_once();
+        }
+        panic!("the completion did not happen");
+    }
+
+    #[test]
+    fn complete_reset_connections_as_closed() {
+        let (client, server) = connect();
+        // Closing a socket without lingering resets the connection.
+        client.set_linger(Some(Duration::ZERO)).unwrap();
+        drop(client);
+        let server = Rc::new(server);
+        let mut io = IO::new(Completed::default());
+
+        io.recv(server.clone(), on_recv);
+        run_until(&mut io, |completed| completed.recv.get().is_some());
+        assert_eq!(io.context().recv.get(), Some(0));
+
+        io.send(server, Bytes::from_static(b"data"), 4, on_send);
+        run_until(&mut io, |completed| completed.send.get().is_some());
+        assert_eq!(io.context().send.get(), Some(0));
+    }
+}
@@ -2,10 +2,10 @@
 mod generic;
 
 #[cfg(not(feature = "simulation"))]
-pub use generic::IO;
+pub use generic::{Clock, IO};
 
 #[cfg(feature = "simulation")]
 mod simulation;
 
 #[cfg(feature = "simulation")]
-pub use simulation::IO;
+pub use simulation::{Clock, IO};
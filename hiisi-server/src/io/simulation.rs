@@ -1,14 +1,20 @@
 use bytes::Bytes;
 
-use std::cell::RefCell;
-use std::collections::{HashMap, VecDeque};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::os::fd::AsRawFd;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 struct Socket {
     local_sock: Rc<socket2::Socket>,
     remote_sock: Rc<socket2::Socket>,
+    /// Data that is sent to the remote socket. An empty buffer marks the end
+    /// of the stream, which the remote socket receives as zero bytes.
     xmit_queue: RefCell<VecDeque<Bytes>>,
+    /// Whether the socket has been closed. A closed socket is removed once
+    /// the data that was sent before closing it has been received.
+    closed: bool,
 }
 
 pub struct IO<C> {
@@ -18,10 +24,49 @@ pub struct IO<C> {
     conn_sockets: HashMap<i32, Socket>,
     accept_listeners: HashMap<socket2::SockAddr, (Rc<socket2::Socket>, AcceptCallback<C>)>,
     recv_listeners: HashMap<i32, (Rc<socket2::Socket>, RecvCallback<C>)>,
+    /// Pending timeouts ordered by their deadline. The sequence number orders
+    /// timeouts with the same deadline.
+    timeouts: BTreeMap<(Instant, usize), TimeoutCallback<C>>,
+    timeout_seq: usize,
+    clock: Clock,
+    /// The most bytes that a send transmits, beyond which it completes
+    /// partially.
+    max_send_size: usize,
+}
+
+/// The clock that timeouts are measured with. The clock is virtual and only
+/// moves when the simulator advances it, so that timeouts fire at the same
+/// points of a simulation however fast it runs.
+#[derive(Clone)]
+pub struct Clock {
+    now: Rc<Cell<Instant>>,
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self {
+            now: Rc::new(Cell::new(Instant::now())),
+        }
+    }
+}
+
+impl Clock {
+    pub fn now(&self) -> Instant {
+        self.now.get()
+    }
+
+    /// Move the clock forward.
+    pub fn advance(&self, duration: Duration) {
+        self.now.set(self.now.get() + duration);
+    }
 }
 
 impl<C> IO<C> {
     pub fn new(context: C) -> Self {
+        Self::with_clock(context, Clock::default())
+    }
+
+    pub fn with_clock(context: C, clock: Clock) -> Self {
         let completions = RefCell::new(VecDeque::new());
         let listener_sockets = HashMap::new();
         let conn_sockets = HashMap::new();
@@ -34,6 +79,10 @@ impl<C> IO<C> {
             conn_sockets,
             accept_listeners,
             recv_listeners,
+            timeouts: BTreeMap::new(),
+            timeout_seq: 0,
+            clock,
+            max_send_size: usize::MAX,
         }
     }
 
@@ -41,9 +90,43 @@ impl<C> IO<C> {
         &self.context
     }
 
+    /// Returns the current time of the virtual clock.
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Returns the virtual clock, which the simulator advances.
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    /// Limit how many bytes a send transmits, so that larger sends complete
+    /// partially like they do when the send buffer of a socket is full.
+    pub fn set_max_send_size(&mut self, max_send_size: usize) {
+        self.max_send_size = max_send_size;
+    }
+
     pub fn run_once(&mut self) {
         self.flush_xmit_queues();
         self.flush_completions();
+        self.flush_timeouts();
+    }
+
+    fn flush_timeouts(&mut self) {
+        // Timeouts that the callbacks set are flushed on the next run.
+        let pending = self.timeouts.split_off(&(self.now(), usize::MAX));
+        let expired = std::mem::replace(&mut self.timeouts, pending);
+        for cb in expired.into_values() {
+            cb(self);
+        }
+    }
+
+    /// Call a callback once a duration has elapsed.
+    pub fn timeout(&mut self, duration: Duration, cb: TimeoutCallback<C>) {
+        log::trace!("IO -> timeout(duration={:?})", duration);
+        let seq = self.timeout_seq;
+        self.timeout_seq += 1;
+        self.timeouts.insert((self.now() + duration, seq), cb);
     }
 
     fn flush_xmit_queues(&mut self) {
@@ -63,19 +146,33 @@ impl<C> IO<C> {
                 local_sockfd,
                 remote_sockfd
             );
+            // A receive completes with the data of one send, like a
+            // receive from a real socket may return less than was sent.
             let (recv_socket, cb) = self.recv_listeners.remove(&remote_sockfd).unwrap();
-            while let Some(buf) = xmit_queue.pop_front() {
-                let c = Completion::Recv {
-                    sock: recv_socket.clone(),
-                    buf,
-                    cb,
-                };
-                completions.push(c);
-            }
+            let buf = xmit_queue.pop_front().unwrap();
+            let c = Completion::Recv {
+                sock: recv_socket,
+                buf,
+                cb,
+            };
+            completions.push(c);
         }
         for c in completions {
             self.enqueue(c);
         }
+        // A closed socket is kept until the remote socket has received its
+        // data, unless the remote socket is closed too.
+        let open: HashSet<i32> = self
+            .conn_sockets
+            .iter()
+            .filter(|(_, socket)| !socket.closed)
+            .map(|(sockfd, _)| *sockfd)
+            .collect();
+        self.conn_sockets.retain(|_, socket| {
+            !socket.closed
+                || (!socket.xmit_queue.borrow().is_empty()
+                    && open.contains(&socket.remote_sock.as_raw_fd()))
+        });
     }
 
     fn flush_completions(&mut self) {
@@ -151,6 +248,7 @@ impl<C> IO<C> {
                 local_sock: local_sock.clone(),
                 remote_sock: remote_sock.clone(),
                 xmit_queue: RefCell::new(VecDeque::new()),
+                closed: false,
             },
         );
     }
@@ -170,7 +268,25 @@ impl<C> IO<C> {
     pub fn close(&mut self, sock: Rc<socket2::Socket>) {
         let sockfd = sock.as_raw_fd();
         log::trace!("IO -> close(sockfd={})", sockfd);
-        self.conn_sockets.remove(&sockfd);
+        self.recv_listeners.remove(&sockfd);
+        if let Some(socket) = self.conn_sockets.get_mut(&sockfd) {
+            socket.xmit_queue.borrow_mut().push_back(Bytes::new());
+            socket.closed = true;
+        }
+    }
+
+    /// Shut down a socket, which completes its pending receive with no data
+    /// and ends the stream that the remote socket receives.
+    pub fn shutdown(&mut self, sock: Rc<socket2::Socket>) {
+        let sockfd = sock.as_raw_fd();
+        log::trace!("IO -> shutdown(sockfd={})", sockfd);
+        if let Some((sock, cb)) = self.recv_listeners.remove(&sockfd) {
+            let buf = Bytes::new();
+            self.enqueue(Completion::Recv { sock, buf, cb });
+        }
+        if let Some(socket) = self.conn_sockets.get(&sockfd) {
+            socket.xmit_queue.borrow_mut().push_back(Bytes::new());
+        }
     }
 
     pub fn recv(&mut self, sock: Rc<socket2::Socket>, cb: RecvCallback<C>) {
@@ -185,7 +301,8 @@ impl<C> IO<C> {
         let socket = self.conn_sockets.get(&sockfd).unwrap();
         let localfd = socket.local_sock.as_raw_fd();
         assert!(localfd == sockfd);
-        socket.xmit_queue.borrow_mut().push_back(buf.clone());
+        let n = n.min(buf.len()).min(self.max_send_size);
+        socket.xmit_queue.borrow_mut().push_back(buf.slice(..n));
         let c = Completion::Send { sock, buf, n, cb };
         self.enqueue(c);
     }
@@ -294,3 +411,5 @@ type AcceptCallback<C> =
 type RecvCallback<C> = fn(&mut IO<C>, Rc<socket2::Socket>, &[u8], usize);
 
 type SendCallback<C> = fn(&mut IO<C>, Rc<socket2::Socket>, usize);
+
+type TimeoutCallback<C> = fn(&mut IO<C>);
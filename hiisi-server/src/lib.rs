@@ -1,15 +1,24 @@
 pub mod admin;
+pub mod baton;
+pub mod cursor;
 pub mod database;
 pub mod error;
 pub mod executor;
 pub mod http;
 pub mod io;
 pub mod manager;
+pub mod pipeline;
 pub mod proto;
 pub mod server;
+pub mod session;
+pub mod stream;
+pub mod websocket;
+
+#[cfg(test)]
+mod test_util;
 
 pub type Result<T> = std::result::Result<T, error::HiisiError>;
 
-pub use error::HiisiError;
+pub use error::{HiisiError, SqliteError};
 pub use manager::ResourceManager;
 pub use server::{serve, Context, IO};
@@ -9,8 +9,10 @@ use std::sync::{
 };
 
 use std::path::PathBuf;
+use std::time::Duration;
 
 use ctrlc;
+use hiisi::manager::StreamTimeouts;
 use hiisi::{Context, HiisiError, ResourceManager, Result, IO};
 
 #[derive(Parser)]
@@ -25,6 +27,24 @@ struct Cli {
     /// The address and port for the admin HTTP API.
     #[clap(long, env = "SQLD_ADMIN_LISTEN_ADDR")]
     admin_listen_addr: Option<SocketAddr>,
+
+    /// How long a stream can be idle between requests, in seconds.
+    #[clap(long, default_value = "10", env = "SQLD_STREAM_IDLE_TIMEOUT")]
+    stream_idle_timeout: u64,
+
+    /// How long a stream can hold a transaction open, in seconds.
+    #[clap(long, default_value = "5", env = "SQLD_TXN_TIMEOUT")]
+    txn_timeout: u64,
+
+    /// How long a write waits for the write transaction of another stream to
+    /// finish before it fails with `SQLITE_BUSY`, in seconds.
+    #[clap(long, default_value = "5", env = "SQLD_BUSY_TIMEOUT")]
+    busy_timeout: u64,
+
+    /// How long a keep-alive connection can be idle between requests, in
+    /// seconds.
+    #[clap(long, default_value = "60", env = "SQLD_KEEP_ALIVE_TIMEOUT")]
+    keep_alive_timeout: u64,
 }
 
 fn main() {
@@ -55,8 +75,14 @@ fn server_loop(cli: Cli) -> Result<()> {
         None => None,
     };
 
-    let manager = Rc::new(ResourceManager::new(&cli.db_path));
-    let ctx = Context::<()>::new(manager, ());
+    let timeouts = StreamTimeouts {
+        idle: Duration::from_secs(cli.stream_idle_timeout),
+        transaction: Duration::from_secs(cli.txn_timeout),
+        busy: Duration::from_secs(cli.busy_timeout),
+    };
+    let manager = Rc::new(ResourceManager::with_timeouts(&cli.db_path, timeouts));
+    let keep_alive_timeout = Duration::from_secs(cli.keep_alive_timeout);
+    let ctx = Context::<()>::with_keep_alive_timeout(manager, (), keep_alive_timeout);
     let mut io = IO::new(ctx);
 
     let running = Arc::new(AtomicBool::new(true));
@@ -1,50 +1,208 @@
-use sieve_cache::SieveCache;
-
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
+use crate::baton::{BatonKey, StreamId};
 use crate::database::{Connection, Database};
-use crate::Result;
+use crate::io::Clock;
+use crate::stream::Stream;
+use crate::{HiisiError, Result};
 
 // Maximum per database page cache size in kibi-bytes.
 const MAX_PAGE_CACHE_SIZE: i64 = 1000;
 
-// Maximum number of resident connections to keep in the cache.
+// Maximum number of memory resident databases.
 const MAX_MEMORY_RESIDENT_DBS: usize = 10;
 
-// Maximum concurrent connections.
-const MAX_CONCURRENT_CONNS: usize = 100;
+// Maximum concurrent streams.
+const MAX_CONCURRENT_STREAMS: usize = 100;
+
+// Maximum number of expired streams to remember, so that clients that come
+// back after their stream expired get a meaningful error.
+const MAX_EXPIRED_STREAMS: usize = 1024;
+
+/// Timeouts after which streams expire or requests fail.
+#[derive(Debug, Clone, Copy)]
+pub struct StreamTimeouts {
+    /// How long a stream can be idle between requests.
+    pub idle: Duration,
+    /// How long a stream can hold a transaction open. When the transaction
+    /// times out, it is rolled back and the stream expires.
+    pub transaction: Duration,
+    /// How long a write waits for another stream to finish its write
+    /// transaction on the same database before it fails with `SQLITE_BUSY`.
+    pub busy: Duration,
+}
+
+impl Default for StreamTimeouts {
+    fn default() -> Self {
+        Self {
+            idle: Duration::from_secs(10),
+            transaction: Duration::from_secs(5),
+            busy: Duration::from_secs(5),
+        }
+    }
+}
 
 /// The resource manager is responsible for managing connections to databases,
 /// transactions, and more.
 pub struct ResourceManager {
     db_path: PathBuf,
 
-    /// A cache of memory resident databases.
+    /// Memory resident databases, keyed by namespace.
     ///
-    /// We keep a tuple of database and connection in the cache because we
-    /// need at least one connection to SQLite to keep the database in memory.
-    memory_resident_dbs: RefCell<SieveCache<String, (Rc<Database>, Rc<Connection>)>>,
+    /// When there are too many of them, we evict the least recently used
+    /// database that has no open connections other than its placeholder.
+    memory_resident_dbs: RefCell<HashMap<String, ResidentDatabase>>,
 
-    /// Open connections to databases.
+    /// Open streams to databases.
     ///
-    /// This is map from batons to connections. We use batons to identify a
-    /// session. SQL statements executed with the same baton are guaranteed
-    /// to be executed with the same SQLite connection, ensuring transaction
-    /// and isolation guarantees.
-    conns: RefCell<SieveCache<String, Rc<Connection>>>,
+    /// This is map from stream ids to streams. Clients identify a stream
+    /// with a baton that encodes its id. SQL statements executed on the same
+    /// stream are guaranteed to be executed with the same SQLite connection,
+    /// ensuring transaction and isolation guarantees.
+    ///
+    /// When there are too many streams, we evict the least recently used
+    /// stream that does not hold a transaction, because evicting a stream
+    /// closes its connection and would roll back the transaction.
+    streams: RefCell<HashMap<StreamId, HttpStream>>,
+
+    /// The stream that holds the write transaction of a database, keyed by
+    /// namespace.
+    ///
+    /// SQLite allows only one writer per database, so writes of other
+    /// streams must wait until the transaction finishes. Readers are not
+    /// blocked, because they read from WAL snapshots.
+    writers: RefCell<HashMap<String, StreamId>>,
+
+    /// Ids of the most recently expired streams.
+    expired_streams: RefCell<VecDeque<StreamId>>,
+
+    timeouts: StreamTimeouts,
+
+    /// The clock that streams expire by, which is the clock of the IO loop.
+    clock: Clock,
+
+    /// The key that batons are signed with.
+    baton_key: BatonKey,
+
+    /// The id of the next stream to open.
+    next_stream_id: Cell<StreamId>,
+}
+
+/// A database that is kept memory resident.
+struct ResidentDatabase {
+    db: Rc<Database>,
+    /// We need at least one connection to SQLite to keep the database in
+    /// memory. The placeholder connection is not opened through `db`, so
+    /// that `db` is shared only by the connections of clients.
+    _placeholder_conn: Connection,
+    last_used: Instant,
+}
+
+impl ResidentDatabase {
+    /// Returns `true` if clients have connections open to the database.
+    fn is_live(&self) -> bool {
+        Rc::strong_count(&self.db) > 1
+    }
+}
+
+/// A stream that is used over HTTP.
+struct HttpStream {
+    /// The namespace of the database that the stream is connected to.
+    db_name: String,
+    stream: Rc<Stream>,
+    /// The sequence number of the baton that the client must send in its
+    /// next request.
+    baton_seq: u64,
+    /// The time of the last request on the stream.
+    last_used: Instant,
+    /// The time the stream opened the transaction it holds, if any.
+    transaction_started: Option<Instant>,
+    /// Whether a response is being sent on the stream, during which the
+    /// stream neither expires nor is evicted.
+    responding: bool,
+}
+
+impl HttpStream {
+    /// Check that the stream belongs to the namespace of a request, so that a
+    /// baton cannot be used to execute SQL on another namespace.
+    fn check_namespace(&self, db_name: &str) -> Result<()> {
+        if self.db_name != db_name {
+            return Err(HiisiError::BatonInvalid(
+                "Baton belongs to another namespace".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn is_expired(&self, timeouts: &StreamTimeouts, now: Instant) -> bool {
+        if self.responding {
+            return false;
+        }
+        let idle_expired = now.duration_since(self.last_used) >= timeouts.idle;
+        let transaction_expired = match self.transaction_started {
+            Some(started) => now.duration_since(started) >= timeouts.transaction,
+            None => false,
+        };
+        idle_expired || transaction_expired
+    }
+
+    /// Returns `true` if the stream can be evicted without rolling back a
+    /// transaction or closing a connection that a cursor still uses.
+    fn is_evictable(&self) -> bool {
+        self.transaction_started.is_none()
+            && !self.responding
+            && self.stream.is_autocommit()
+            && Rc::strong_count(&self.stream) == 1
+    }
+}
+
+/// Returns the key of the least recently used entry of a map that can be
+/// evicted. Entries that were last used at the same time are ordered by key,
+/// so that the choice does not depend on the order of the map, which keeps
+/// simulations deterministic.
+fn eviction_candidate<K: Clone + Ord, V>(
+    entries: &HashMap<K, V>,
+    last_used: impl Fn(&V) -> Instant,
+    is_evictable: impl Fn(&V) -> bool,
+) -> Option<K> {
+    entries
+        .iter()
+        .filter(|(_, entry)| is_evictable(entry))
+        .min_by(|(a_key, a), (b_key, b)| {
+            last_used(a)
+                .cmp(&last_used(b))
+                .then_with(|| a_key.cmp(b_key))
+        })
+        .map(|(key, _)| key.clone())
 }
 
 impl ResourceManager {
     pub fn new(db_path: &Path) -> Self {
-        let memory_resident_dbs = SieveCache::new(MAX_MEMORY_RESIDENT_DBS).unwrap();
-        let conns = SieveCache::new(MAX_CONCURRENT_CONNS).unwrap();
+        Self::with_timeouts(db_path, StreamTimeouts::default())
+    }
+
+    pub fn with_timeouts(db_path: &Path, timeouts: StreamTimeouts) -> Self {
+        Self::with_clock(db_path, timeouts, Clock::default())
+    }
+
+    /// Create a manager whose streams expire by a clock that is shared with
+    /// the IO loop, such as the virtual clock of a simulation.
+    pub fn with_clock(db_path: &Path, timeouts: StreamTimeouts, clock: Clock) -> Self {
         std::fs::create_dir_all(db_path).unwrap();
         ResourceManager {
             db_path: db_path.to_owned(),
-            memory_resident_dbs: RefCell::new(memory_resident_dbs),
-            conns: RefCell::new(conns),
+            memory_resident_dbs: RefCell::new(HashMap::new()),
+            streams: RefCell::new(HashMap::new()),
+            writers: RefCell::new(HashMap::new()),
+            expired_streams: RefCell::new(VecDeque::new()),
+            timeouts,
+            clock,
+            baton_key: BatonKey::generate(),
+            next_stream_id: Cell::new(0),
         }
     }
 
@@ -54,38 +212,414 @@ impl ResourceManager {
         Ok(())
     }
 
-    pub fn get_conn(&self, db_name: &str, baton: &str) -> Result<Rc<Connection>> {
-        let mut conns = self.conns.borrow_mut();
-        if let Some(conn) = conns.get(baton) {
-            return Ok(conn.clone());
+    /// Open a new stream to a database.
+    ///
+    /// Fails with a retriable error if the maximum number of streams is open
+    /// and all of them hold a transaction.
+    pub fn open_stream(&self, db_name: &str) -> Result<StreamId> {
+        if self.streams.borrow().len() >= MAX_CONCURRENT_STREAMS {
+            self.evict_stream()?;
+        }
+        let stream = Rc::new(Stream::new(self.connect(db_name)?));
+        let stream_id = self.allocate_stream_id();
+        let stream = HttpStream {
+            db_name: db_name.to_string(),
+            stream,
+            baton_seq: 0,
+            last_used: self.clock.now(),
+            transaction_started: None,
+            responding: false,
+        };
+        self.streams.borrow_mut().insert(stream_id, stream);
+        Ok(stream_id)
+    }
+
+    /// Returns a new stream id. Streams that are not used over HTTP, such as
+    /// the streams of WebSocket sessions, are given an id so that they can be
+    /// tracked as writers.
+    pub fn allocate_stream_id(&self) -> StreamId {
+        let stream_id = self.next_stream_id.get();
+        self.next_stream_id.set(stream_id + 1);
+        stream_id
+    }
+
+    fn evict_stream(&self) -> Result<()> {
+        let stream_id = eviction_candidate(
+            &self.streams.borrow(),
+            |stream| stream.last_used,
+            HttpStream::is_evictable,
+        );
+        match stream_id {
+            Some(stream_id) => {
+                log::debug!("Evicting idle stream {}", stream_id);
+                self.expire_stream(stream_id);
+                Ok(())
+            }
+            None => Err(HiisiError::ResourcesExhausted(
+                "All streams hold an open transaction".to_string(),
+            )),
+        }
+    }
+
+    /// Returns the id of the stream that a baton identifies.
+    ///
+    /// The baton must be the latest one that was issued for the stream, so
+    /// that a request cannot be replayed, and the stream must be connected
+    /// to the namespace of the request.
+    pub fn resume_stream(&self, db_name: &str, baton: &str) -> Result<StreamId> {
+        let (stream_id, seq) = self.baton_key.decode(baton)?;
+        let now = self.clock.now();
+        // The stream may have expired since the streams were last checked.
+        let expired = match self.streams.borrow().get(&stream_id) {
+            Some(stream) => stream.is_expired(&self.timeouts, now),
+            None => false,
+        };
+        if expired {
+            self.expire_stream(stream_id);
+        }
+        let mut streams = self.streams.borrow_mut();
+        let stream = match streams.get_mut(&stream_id) {
+            Some(stream) => stream,
+            None if self.expired_streams.borrow().contains(&stream_id) => {
+                return Err(HiisiError::StreamExpired)
+            }
+            None => return Err(HiisiError::BatonStreamClosed),
+        };
+        stream.check_namespace(db_name)?;
+        if seq != stream.baton_seq {
+            return Err(HiisiError::BatonReused);
+        }
+        stream.last_used = now;
+        Ok(stream_id)
+    }
+
+    pub fn get_stream(&self, db_name: &str, stream_id: StreamId) -> Result<Rc<Stream>> {
+        let streams = self.streams.borrow();
+        let stream = streams
+            .get(&stream_id)
+            .ok_or(HiisiError::BatonStreamClosed)?;
+        stream.check_namespace(db_name)?;
+        Ok(stream.stream.clone())
+    }
+
+    /// Issue the baton that the client must send in its next request on a
+    /// stream, which invalidates the previous baton. Returns `None` if the
+    /// stream has been closed.
+    pub fn next_baton(&self, stream_id: StreamId) -> Option<String> {
+        let mut streams = self.streams.borrow_mut();
+        let stream = streams.get_mut(&stream_id)?;
+        stream.baton_seq += 1;
+        Some(self.baton_key.encode(stream_id, stream.baton_seq))
+    }
+
+    /// Mark a stream as sending a response, which keeps it from expiring
+    /// however long the client takes to receive the response.
+    pub fn begin_response(&self, stream_id: StreamId) {
+        if let Some(stream) = self.streams.borrow_mut().get_mut(&stream_id) {
+            stream.responding = true;
+        }
+    }
+
+    /// Mark the response on a stream as finished, after which the stream is
+    /// idle until the next request.
+    pub fn end_response(&self, stream_id: StreamId) {
+        if let Some(stream) = self.streams.borrow_mut().get_mut(&stream_id) {
+            stream.responding = false;
+            stream.last_used = self.clock.now();
         }
+    }
+
+    /// Open a new connection to a database, which is kept memory resident.
+    ///
+    /// A statement that needs a lock that another connection holds fails
+    /// right away with `SQLITE_BUSY`, because waiting for the lock would
+    /// block the event loop. Writes wait in the write queue instead.
+    ///
+    /// Fails with a retriable error if the maximum number of databases is
+    /// memory resident and all of them have open connections.
+    pub fn connect(&self, db_name: &str) -> Result<Connection> {
+        self.resident_db(db_name)?.connect()
+    }
+
+    /// Returns a memory resident database, opening it if necessary.
+    fn resident_db(&self, db_name: &str) -> Result<Rc<Database>> {
         let mut memory_resident_dbs = self.memory_resident_dbs.borrow_mut();
-        if let Some((db, _)) = memory_resident_dbs.get(db_name) {
-            let conn = Rc::new(db.connect()?);
-            conns.insert(baton.to_string(), conn.clone());
-            return Ok(conn);
+        if let Some(resident) = memory_resident_dbs.get_mut(db_name) {
+            resident.last_used = self.clock.now();
+            return Ok(resident.db.clone());
+        }
+        if memory_resident_dbs.len() >= MAX_MEMORY_RESIDENT_DBS {
+            let victim = eviction_candidate(
+                &memory_resident_dbs,
+                |resident| resident.last_used,
+                |resident| !resident.is_live(),
+            );
+            match victim {
+                Some(victim) => {
+                    log::debug!("Evicting memory resident database {}", victim);
+                    memory_resident_dbs.remove(&victim);
+                }
+                None => {
+                    return Err(HiisiError::ResourcesExhausted(
+                        "All memory resident databases are in use".to_string(),
+                    ))
+                }
+            }
         }
-        let (db, placeholder_conn) = self.open_conn(db_name)?;
-        memory_resident_dbs.insert(db_name.to_string(), (db.clone(), placeholder_conn));
-        let conn = Rc::new(db.connect()?);
-        conns.insert(baton.to_string(), conn.clone());
-        Ok(conn)
+        let resident = self.open_db(db_name)?;
+        let db = resident.db.clone();
+        memory_resident_dbs.insert(db_name.to_string(), resident);
+        Ok(db)
     }
 
-    fn open_conn(&self, db_name: &str) -> Result<(Rc<Database>, Rc<Connection>)> {
+    fn open_db(&self, db_name: &str) -> Result<ResidentDatabase> {
         let db_dir = self.db_path.join(db_name);
         let db_path = db_dir.join(format!("{}.db", db_name));
-        let db = Database::new(db_path.into());
-        let conn = db.connect()?;
+        let conn = Connection::open(&db_path)?;
         conn.pragma("journal_mode", "WAL")?;
         conn.pragma("cache_size", format!("-{}", MAX_PAGE_CACHE_SIZE))?;
-        conn.pragma("locking_mode", "EXCLUSIVE")?;
-        Ok((Rc::new(db), Rc::new(conn)))
+        Ok(ResidentDatabase {
+            db: Rc::new(Database::new(db_path)),
+            _placeholder_conn: conn,
+            last_used: self.clock.now(),
+        })
     }
 
-    pub fn drop_conn(&self, _db_name: &str, baton: &str) -> Result<()> {
-        let mut conns = self.conns.borrow_mut();
-        conns.remove(baton);
+    pub fn drop_stream(&self, db_name: &str, stream_id: StreamId) -> Result<()> {
+        let mut streams = self.streams.borrow_mut();
+        if let Some(stream) = streams.get(&stream_id) {
+            stream.check_namespace(db_name)?;
+        }
+        if let Some(stream) = streams.remove(&stream_id) {
+            if !stream.stream.is_autocommit() {
+                log::debug!("Rolling back transaction of closed stream {}", stream_id);
+            }
+            self.release_writer(&stream.db_name, stream_id);
+        }
         Ok(())
     }
+
+    /// Update the transaction state of a stream after executing requests on
+    /// it.
+    pub fn update_stream(&self, stream_id: StreamId) {
+        let mut streams = self.streams.borrow_mut();
+        let stream = match streams.get_mut(&stream_id) {
+            Some(stream) => stream,
+            None => return,
+        };
+        let now = self.clock.now();
+        if stream.stream.is_autocommit() {
+            stream.transaction_started = None;
+        } else if stream.transaction_started.is_none() {
+            stream.transaction_started = Some(now);
+        }
+        stream.last_used = now;
+        self.update_writer(&stream.db_name, stream_id, &stream.stream);
+    }
+
+    /// Record whether a stream holds the write transaction of a database.
+    pub fn update_writer(&self, db_name: &str, stream_id: StreamId, stream: &Stream) {
+        if stream.conn.is_writing() {
+            self.writers
+                .borrow_mut()
+                .insert(db_name.to_string(), stream_id);
+        } else {
+            self.release_writer(db_name, stream_id);
+        }
+    }
+
+    /// Forget that a stream holds the write transaction of a database.
+    pub fn release_writer(&self, db_name: &str, stream_id: StreamId) {
+        let mut writers = self.writers.borrow_mut();
+        if writers.get(db_name) == Some(&stream_id) {
+            writers.remove(db_name);
+        }
+    }
+
+    /// Returns `true` if a stream other than the given one holds the write
+    /// transaction of a database.
+    pub fn has_other_writer(&self, db_name: &str, stream_id: Option<StreamId>) -> bool {
+        match self.writers.borrow().get(db_name) {
+            Some(writer) => Some(*writer) != stream_id,
+            None => false,
+        }
+    }
+
+    /// Expire the streams that have been idle or have held a transaction for
+    /// too long, rolling back their transactions and closing their
+    /// connections.
+    pub fn expire_streams(&self) {
+        let now = self.clock.now();
+        let expired: Vec<StreamId> = self
+            .streams
+            .borrow()
+            .iter()
+            .filter(|(_, stream)| stream.is_expired(&self.timeouts, now))
+            .map(|(stream_id, _)| *stream_id)
+            .collect();
+        for stream_id in expired {
+            log::debug!("Stream {} expired", stream_id);
+            self.expire_stream(stream_id);
+        }
+    }
+
+    /// Remove a stream, so that a client that comes back to it is told that
+    /// it has expired.
+    fn expire_stream(&self, stream_id: StreamId) {
+        let stream = match self.streams.borrow_mut().remove(&stream_id) {
+            Some(stream) => stream,
+            None => return,
+        };
+        // A cursor may still hold the stream, so we roll back explicitly
+        // instead of relying on the connection being closed.
+        if let Err(err) = stream.stream.rollback() {
+            log::error!("Failed to roll back expired stream {}: {}", stream_id, err);
+        }
+        self.release_writer(&stream.db_name, stream_id);
+        let mut expired_streams = self.expired_streams.borrow_mut();
+        if expired_streams.len() >= MAX_EXPIRED_STREAMS {
+            expired_streams.pop_front();
+        }
+        expired_streams.push_back(stream_id);
+    }
+
+    /// Returns the timeouts after which streams expire or requests fail.
+    pub fn timeouts(&self) -> StreamTimeouts {
+        self.timeouts
+    }
+
+    /// Returns the current time of the clock that timeouts are measured with.
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Returns `true` if the stream identified by the baton holds an open
+    /// transaction.
+    pub fn in_transaction(&self, baton: &str) -> bool {
+        let stream_id = match self.baton_stream_id(baton) {
+            Some(stream_id) => stream_id,
+            None => return false,
+        };
+        match self.streams.borrow().get(&stream_id) {
+            Some(stream) => stream.transaction_started.is_some(),
+            None => false,
+        }
+    }
+
+    /// Returns the id of the stream that a baton identifies, without checking
+    /// that the baton is the latest one or that the stream is still open.
+    pub fn baton_stream_id(&self, baton: &str) -> Option<StreamId> {
+        self.baton_key
+            .decode(baton)
+            .ok()
+            .map(|(stream_id, _)| stream_id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::executor;
+    use crate::test_util::temp_manager;
+
+    fn execute(manager: &ResourceManager, stream_id: StreamId, sql: &str) {
+        let stream = manager.get_stream("test", stream_id).unwrap();
+        executor::execute_sequence(&stream.conn, sql).unwrap();
+        manager.update_stream(stream_id);
+    }
+
+    fn is_open(manager: &ResourceManager, stream_id: StreamId) -> bool {
+        manager.get_stream("test", stream_id).is_ok()
+    }
+
+    #[test]
+    fn evict_idle_autocommit_streams() {
+        let manager = temp_manager();
+        let streams: Vec<StreamId> = (0..MAX_CONCURRENT_STREAMS)
+            .map(|_| manager.open_stream("test").unwrap())
+            .collect();
+        // The least recently used stream holds a transaction, so the next
+        // one is evicted instead.
+        execute(&manager, streams[0], "BEGIN");
+        for stream_id in &streams[2..] {
+            execute(&manager, *stream_id, "SELECT 1");
+        }
+        manager.open_stream("test").unwrap();
+        assert!(is_open(&manager, streams[0]));
+        assert!(!is_open(&manager, streams[1]));
+        assert!(manager.in_transaction(&manager.baton_key.encode(streams[0], 0)));
+    }
+
+    #[test]
+    fn never_evict_transactions() {
+        let manager = temp_manager();
+        let streams: Vec<StreamId> = (0..MAX_CONCURRENT_STREAMS)
+            .map(|_| manager.open_stream("test").unwrap())
+            .collect();
+        for stream_id in &streams {
+            execute(&manager, *stream_id, "BEGIN");
+        }
+        let err = manager.open_stream("test").unwrap_err();
+        assert!(matches!(err, HiisiError::ResourcesExhausted(_)));
+        assert!(streams
+            .iter()
+            .all(|stream_id| is_open(&manager, *stream_id)));
+
+        execute(&manager, streams[42], "ROLLBACK");
+        manager.open_stream("test").unwrap();
+        assert!(!is_open(&manager, streams[42]));
+    }
+
+    #[test]
+    fn keep_live_databases_resident() {
+        let manager = temp_manager();
+        let mut conns = Vec::new();
+        for i in 0..MAX_MEMORY_RESIDENT_DBS {
+            let db_name = format!("db{}", i);
+            manager.create_database(&db_name).unwrap();
+            conns.push(manager.connect(&db_name).unwrap());
+        }
+        manager.create_database("other").unwrap();
+        assert!(matches!(
+            manager.connect("other"),
+            Err(HiisiError::ResourcesExhausted(_))
+        ));
+
+        // A database without open connections can be evicted.
+        conns.remove(3);
+        manager.connect("other").unwrap();
+        let memory_resident_dbs = manager.memory_resident_dbs.borrow();
+        assert!(!memory_resident_dbs.contains_key("db3"));
+        assert!(memory_resident_dbs.contains_key("db4"));
+    }
+
+    #[test]
+    fn fail_locked_writes_without_waiting() {
+        let manager = temp_manager();
+        let writer = manager.connect("test").unwrap();
+        writer.prepare("BEGIN IMMEDIATE").unwrap().step().unwrap();
+        let conn = manager.connect("test").unwrap();
+        let start = Instant::now();
+        let result = conn.prepare("BEGIN IMMEDIATE").unwrap().step();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(matches!(result, Err(HiisiError::SqliteError(err)) if err.is_busy()));
+    }
+
+    #[cfg(feature = "simulation")]
+    #[test]
+    fn expire_streams_by_virtual_clock() {
+        let clock = Clock::default();
+        let timeouts = StreamTimeouts {
+            idle: Duration::from_secs(10),
+            ..StreamTimeouts::default()
+        };
+        let manager = crate::test_util::temp_manager_with_clock(timeouts, clock.clone());
+        let stream_id = manager.open_stream("test").unwrap();
+        clock.advance(Duration::from_secs(9));
+        manager.expire_streams();
+        assert!(manager.get_stream("test", stream_id).is_ok());
+        clock.advance(Duration::from_secs(1));
+        manager.expire_streams();
+        assert!(manager.get_stream("test", stream_id).is_err());
+    }
 }
@@ -0,0 +1,418 @@
+//! Pipelines whose responses are streamed.
+
+use std::rc::Rc;
+use std::time::Instant;
+
+use bytes::BytesMut;
+
+use crate::baton::StreamId;
+use crate::database::{StepResult, Stmt};
+use crate::executor::{self, Request};
+use crate::manager::ResourceManager;
+use crate::proto;
+use crate::stream::Stream;
+use crate::Result;
+
+/// A pipeline executes its requests as the response body is written, so that
+/// the response can be sent in pieces instead of being buffered whole. In
+/// JSON, the rows of an execute request are written as they are stepped.
+/// Other results, such as those of batches and of Protobuf pipelines, are
+/// buffered whole, so the size of their rows is limited.
+pub struct Pipeline {
+    /// The execute request whose rows are being written.
+    execute: Option<Execute>,
+    manager: Rc<ResourceManager>,
+    db_name: String,
+    version: proto::Version,
+    stream_id: StreamId,
+    requests: std::vec::IntoIter<proto::StreamRequest>,
+    writer: proto::PipelineRespWriter,
+    started: bool,
+    done: bool,
+}
+
+/// An execute request whose rows are written as they are stepped.
+struct Execute {
+    /// This field is declared before `stream` so that the statement is
+    /// finalized before the connection it was prepared on is dropped.
+    stmt: Stmt,
+    stream: Rc<Stream>,
+    column_count: i32,
+    want_rows: bool,
+    changes: executor::ChangeCounters,
+    start: Instant,
+    /// The offset in the body buffer at which the result starts, or `None`
+    /// if part of the result has been sent already.
+    offset: Option<usize>,
+}
+
+impl Pipeline {
+    /// Open a pipeline on the HTTP stream identified by the baton of the
+    /// request.
+    pub fn open(
+        manager: Rc<ResourceManager>,
+        req: Request,
+        encoding: proto::Encoding,
+    ) -> Result<Self> {
+        let stream_id =
+            executor::request_stream(&manager, &req.database, req.req.baton.as_deref())?;
+        // The baton of the response is only known at the end of the body, so
+        // we invalidate the baton of the request now, which keeps other
+        // requests off the stream while the response is being sent.
+        manager.next_baton(stream_id);
+        manager.begin_response(stream_id);
+        Ok(Self {
+            execute: None,
+            manager,
+            db_name: req.database,
+            version: req.version,
+            stream_id,
+            requests: req.req.requests.into_iter(),
+            writer: proto::PipelineRespWriter::new(encoding),
+            started: false,
+            done: false,
+        })
+    }
+
+    /// Write the response body to a buffer until the buffer holds at least
+    /// `limit` bytes, returning `true` if the body is complete.
+    ///
+    /// The data must be sent before the rest of the body is written. If a
+    /// statement fails after part of its rows have been sent, the error
+    /// cannot be reported in the body, so it is returned instead.
+    pub fn write_body(&mut self, buf: &mut BytesMut, limit: usize) -> Result<bool> {
+        if self.done {
+            return Ok(true);
+        }
+        if !self.started {
+            self.writer.begin(buf);
+            self.started = true;
+        }
+        while buf.len() < limit {
+            if self.execute.is_some() {
+                self.step_execute(buf)?;
+                continue;
+            }
+            match self.requests.next() {
+                Some(req) => self.begin_request(req, buf)?,
+                None => {
+                    self.finish(buf)?;
+                    return Ok(true);
+                }
+            }
+        }
+        if let Some(execute) = &mut self.execute {
+            execute.offset = None;
+        }
+        Ok(false)
+    }
+
+    fn begin_request(&mut self, req: proto::StreamRequest, buf: &mut BytesMut) -> Result<()> {
+        let result = match req {
+            proto::StreamRequest::Execute(req) if self.writer.streams_rows() => {
+                match self.begin_execute(&req.stmt) {
+                    Ok((mut execute, cols)) => {
+                        execute.offset = Some(buf.len());
+                        self.writer.begin_execute_result(&cols, buf)?;
+                        self.execute = Some(execute);
+                        return Ok(());
+                    }
+                    Err(err) => {
+                        log::debug!("Stream request failed: {}", err);
+                        proto::StreamResult::Error {
+                            error: executor::to_proto_error(&err),
+                        }
+                    }
+                }
+            }
+            req => executor::exec_stream_request(
+                self.manager.clone(),
+                &req,
+                self.version,
+                &self.db_name,
+                self.stream_id,
+            ),
+        };
+        self.writer.write_result(result, buf)?;
+        // The request may have started or finished a write transaction.
+        self.manager.update_stream(self.stream_id);
+        Ok(())
+    }
+
+    fn begin_execute(&self, stmt: &proto::Stmt) -> Result<(Execute, Vec<proto::Col>)> {
+        log::trace!(
+            "Executing SQL statement: {:?} on {} (stream = {})",
+            stmt,
+            self.db_name,
+            self.stream_id
+        );
+        let start = Instant::now();
+        let stream = self.manager.get_stream(&self.db_name, self.stream_id)?;
+        let prepared = executor::prepare_stmt(&stream, stmt)?;
+        let cols = executor::stmt_cols(&prepared)?;
+        let changes = executor::ChangeCounters::new(&stream.conn);
+        let execute = Execute {
+            column_count: prepared.column_count(),
+            stmt: prepared,
+            stream,
+            want_rows: stmt.want_rows.unwrap_or(true),
+            changes,
+            start,
+            offset: None,
+        };
+        Ok((execute, cols))
+    }
+
+    /// Step the statement of the current execute request until it produces a
+    /// row or finishes.
+    fn step_execute(&mut self, buf: &mut BytesMut) -> Result<()> {
+        let execute = self.execute.as_ref().unwrap();
+        let result = loop {
+            match execute.stmt.step() {
+                Ok(StepResult::Row) if !execute.want_rows => {}
+                Ok(StepResult::Row) => {
+                    match executor::to_row(&execute.stmt, execute.column_count) {
+                        Ok(row) => return self.writer.write_row(&row, buf),
+                        Err(err) => break Err(err),
+                    }
+                }
+                Ok(StepResult::Done) => break Ok(()),
+                Err(err) => break Err(err),
+            }
+        };
+        let execute = self.execute.take().unwrap();
+        match result {
+            Ok(()) => {
+                let (affected_row_count, last_insert_rowid) =
+                    execute.changes.stmt_changes(&execute.stream.conn);
+                let result = proto::StmtResult {
+                    cols: vec![],
+                    rows: vec![],
+                    affected_row_count,
+                    last_insert_rowid,
+                    replication_index: None,
+                    rows_read: execute.stmt.rows_read(),
+                    rows_written: execute.stmt.rows_written(),
+                    query_duration_ms: execute.start.elapsed().as_secs_f64() * 1000.0,
+                };
+                self.writer.end_execute_result(&result, buf)?;
+            }
+            Err(err) => {
+                // If none of the result has been sent, we replace it with the
+                // error like in a buffered response.
+                let Some(offset) = execute.offset else {
+                    return Err(err);
+                };
+                log::debug!("Stream request failed: {}", err);
+                buf.truncate(offset);
+                let result = proto::StreamResult::Error {
+                    error: executor::to_proto_error(&err),
+                };
+                self.writer.write_result(result, buf)?;
+            }
+        }
+        self.manager.update_stream(self.stream_id);
+        Ok(())
+    }
+
+    fn finish(&mut self, buf: &mut BytesMut) -> Result<()> {
+        self.done = true;
+        self.manager.update_stream(self.stream_id);
+        let baton = self.manager.next_baton(self.stream_id);
+        self.writer.end(baton, buf)
+    }
+}
+
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        self.manager.end_response(self.stream_id);
+        if !self.done {
+            // The body was aborted before the client received the new baton,
+            // so nobody can continue the stream. Closing it right away rolls
+            // back its transaction instead of holding the write lock until
+            // the stream expires.
+            self.execute = None;
+            let _ = self.manager.drop_stream(&self.db_name, self.stream_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::manager::StreamTimeouts;
+    use crate::test_util::{execute, temp_manager, temp_manager_with_timeouts};
+    use prost::Message;
+    use std::time::Duration;
+
+    fn open(
+        manager: &Rc<ResourceManager>,
+        baton: Option<String>,
+        requests: Vec<proto::StreamRequest>,
+        encoding: proto::Encoding,
+    ) -> Pipeline {
+        let req = Request {
+            database: "test".to_string(),
+            version: proto::Version::Hrana3,
+            req: proto::PipelineReqBody { baton, requests },
+        };
+        Pipeline::open(manager.clone(), req, encoding).unwrap()
+    }
+
+    /// Write the body in pieces of at least `limit` bytes.
+    fn write_body(pipeline: &mut Pipeline, limit: usize) -> Result<(Vec<u8>, usize)> {
+        let mut body = Vec::new();
+        let mut pieces = 0;
+        loop {
+            let mut buf = BytesMut::new();
+            let done = pipeline.write_body(&mut buf, limit)?;
+            body.extend_from_slice(&buf);
+            pieces += 1;
+            if done {
+                return Ok((body, pieces));
+            }
+        }
+    }
+
+    fn rows(result: &proto::StreamResult) -> Vec<i64> {
+        match result {
+            proto::StreamResult::Ok {
+                response: proto::StreamResponse::Execute(resp),
+            } => resp
+                .result
+                .rows
+                .iter()
+                .map(|row| match row.values[..] {
+                    [proto::Value::Integer { value }] => value,
+                    _ => panic!("unexpected row {:?}", row),
+                })
+                .collect(),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn stream_rows_in_pieces() {
+        let manager = temp_manager();
+        let requests = vec![
+            execute("CREATE TABLE t (x INTEGER)"),
+            execute(
+                "WITH RECURSIVE s(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM s WHERE x < 1000) \
+                 INSERT INTO t SELECT x FROM s",
+            ),
+            execute("SELECT x FROM t"),
+            execute("SELECT * FROM no_such_table"),
+            execute("SELECT count(*) FROM t"),
+        ];
+        let mut pipeline = open(&manager, None, requests, proto::Encoding::Json);
+        let (body, pieces) = write_body(&mut pipeline, 1).unwrap();
+        // Every row of the result is a piece of its own.
+        assert!(pieces > 1000);
+        let resp: proto::PipelineRespBody = serde_json::from_slice(&body).unwrap();
+        assert_eq!(resp.results.len(), 5);
+        assert_eq!(rows(&resp.results[2]), (1..=1000).collect::<Vec<_>>());
+        assert!(matches!(resp.results[3], proto::StreamResult::Error { .. }));
+        assert_eq!(rows(&resp.results[4]), vec![1000]);
+
+        // The baton at the end of the body continues the stream, and rows
+        // that Protobuf cannot stream are written with their result.
+        let requests = vec![execute("SELECT x FROM t WHERE x <= 3")];
+        let mut pipeline = open(&manager, resp.baton, requests, proto::Encoding::Protobuf);
+        let (body, _) = write_body(&mut pipeline, 1).unwrap();
+        let resp = proto::PipelineRespBody::decode(&body[..]).unwrap();
+        assert_eq!(rows(&resp.results[0]), vec![1, 2, 3]);
+        assert!(resp.baton.is_some());
+    }
+
+    #[test]
+    fn report_statement_errors() {
+        let manager = temp_manager();
+        let requests = vec![
+            execute("CREATE TABLE t (x INTEGER)"),
+            execute("INSERT INTO t VALUES (1), (2), (3)"),
+        ];
+        let mut pipeline = open(&manager, None, requests, proto::Encoding::Json);
+        write_body(&mut pipeline, usize::MAX).unwrap();
+        // Integer overflow fails the statement at the third row.
+        let failing = || execute("SELECT iif(x = 3, abs(-9223372036854775808), x) FROM t");
+
+        // An error before any of the result has been sent replaces the result.
+        let mut pipeline = open(&manager, None, vec![failing()], proto::Encoding::Json);
+        let (body, pieces) = write_body(&mut pipeline, usize::MAX).unwrap();
+        assert_eq!(pieces, 1);
+        let resp: proto::PipelineRespBody = serde_json::from_slice(&body).unwrap();
+        assert!(matches!(resp.results[0], proto::StreamResult::Error { .. }));
+
+        // An error after some rows have been sent aborts the body.
+        let mut pipeline = open(&manager, None, vec![failing()], proto::Encoding::Json);
+        assert!(write_body(&mut pipeline, 1).is_err());
+    }
+
+    #[test]
+    fn close_streams_of_aborted_bodies() {
+        let manager = temp_manager();
+        let requests = vec![
+            execute("CREATE TABLE t (x INTEGER)"),
+            execute("INSERT INTO t VALUES (1), (2), (3)"),
+        ];
+        let mut pipeline = open(&manager, None, requests, proto::Encoding::Json);
+        write_body(&mut pipeline, usize::MAX).unwrap();
+
+        let requests = vec![
+            execute("BEGIN"),
+            execute("INSERT INTO t VALUES (4)"),
+            execute("SELECT iif(x = 3, abs(-9223372036854775808), x) FROM t"),
+        ];
+        let mut pipeline = open(&manager, None, requests, proto::Encoding::Json);
+        let stream_id = pipeline.stream_id;
+        assert!(write_body(&mut pipeline, 1).is_err());
+        assert!(manager.has_other_writer("test", None));
+        drop(pipeline);
+
+        // The transaction is rolled back and the write lock released.
+        assert!(manager.get_stream("test", stream_id).is_err());
+        assert!(!manager.has_other_writer("test", None));
+        let requests = vec![
+            execute("INSERT INTO t VALUES (5)"),
+            execute("SELECT x FROM t"),
+        ];
+        let mut pipeline = open(&manager, None, requests, proto::Encoding::Json);
+        let (body, _) = write_body(&mut pipeline, usize::MAX).unwrap();
+        let resp: proto::PipelineRespBody = serde_json::from_slice(&body).unwrap();
+        assert_eq!(rows(&resp.results[1]), vec![1, 2, 3, 5]);
+    }
+
+    #[test]
+    fn keep_streams_alive_while_responding() {
+        let manager = temp_manager_with_timeouts(StreamTimeouts {
+            idle: Duration::ZERO,
+            transaction: Duration::ZERO,
+            ..StreamTimeouts::default()
+        });
+        let requests = vec![
+            execute("BEGIN"),
+            execute("CREATE TABLE t (x INTEGER)"),
+            execute("INSERT INTO t VALUES (1), (2), (3)"),
+            execute("SELECT x FROM t"),
+        ];
+        let mut pipeline = open(&manager, None, requests, proto::Encoding::Json);
+        let mut body = BytesMut::new();
+        loop {
+            let limit = body.len() + 1;
+            if pipeline.write_body(&mut body, limit).unwrap() {
+                break;
+            }
+            manager.expire_streams();
+        }
+        let resp: proto::PipelineRespBody = serde_json::from_slice(&body).unwrap();
+        assert_eq!(rows(&resp.results[3]), vec![1, 2, 3]);
+
+        // The stream expires once the response has been sent.
+        drop(pipeline);
+        manager.expire_streams();
+        let err = manager
+            .resume_stream("test", &resp.baton.unwrap())
+            .unwrap_err();
+        assert_eq!(executor::to_proto_error(&err).code, "STREAM_EXPIRED");
+    }
+}
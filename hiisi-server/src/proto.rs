@@ -1,13 +1,62 @@
 //! libSQL remote SQL execution protocol ("hrana").
 
 use crate::Result;
-use bytes::Bytes;
+use bytes::{BufMut, Bytes, BytesMut};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
+mod protobuf;
+pub mod v1;
+pub mod ws;
+
+/// Encoding of the messages, which the client selects with the `Content-Type`
+/// header of the request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    Protobuf,
+}
+
+impl Encoding {
+    /// Returns the encoding for a `Content-Type` header value. Anything other
+    /// than Protobuf is treated as JSON, which is what clients that do not
+    /// set the header send.
+    pub fn from_content_type(content_type: Option<&str>) -> Self {
+        let mime = content_type
+            .and_then(|content_type| content_type.split(';').next())
+            .map(|mime| mime.trim());
+        match mime {
+            Some(mime) if mime.eq_ignore_ascii_case("application/x-protobuf") => Encoding::Protobuf,
+            _ => Encoding::Json,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Encoding::Json => "application/json",
+            Encoding::Protobuf => "application/x-protobuf",
+        }
+    }
+}
+
 /// Parse a client request message.
-pub fn parse_client_req(msg: &[u8]) -> Result<PipelineReqBody> {
-    let msg: PipelineReqBody = serde_json::from_slice(msg)?;
+pub fn parse_client_req(msg: &[u8], encoding: Encoding) -> Result<PipelineReqBody> {
+    parse_msg(msg, encoding)
+}
+
+/// Parse a client cursor request message.
+pub fn parse_cursor_req(msg: &[u8], encoding: Encoding) -> Result<CursorReqBody> {
+    parse_msg(msg, encoding)
+}
+
+fn parse_msg<T>(msg: &[u8], encoding: Encoding) -> Result<T>
+where
+    T: for<'de> Deserialize<'de> + prost::Message + Default,
+{
+    let msg = match encoding {
+        Encoding::Json => serde_json::from_slice(msg)?,
+        Encoding::Protobuf => T::decode(msg)?,
+    };
     Ok(msg)
 }
 
@@ -17,13 +66,222 @@ pub fn format_msg<T: Serialize>(msg: &T) -> Result<Bytes> {
     Ok(Bytes::from(msg))
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Format a client response message in the given encoding.
+pub fn encode_msg<T: Serialize + prost::Message>(msg: &T, encoding: Encoding) -> Result<Bytes> {
+    let mut buf = BytesMut::new();
+    write_msg(msg, encoding, &mut buf)?;
+    Ok(buf.freeze())
+}
+
+/// Append a client response message in the given encoding to a buffer.
+pub fn write_msg<T: Serialize + prost::Message>(
+    msg: &T,
+    encoding: Encoding,
+    buf: &mut BytesMut,
+) -> Result<()> {
+    match encoding {
+        Encoding::Json => write_json_msg(msg, buf)?,
+        Encoding::Protobuf => {
+            buf.reserve(msg.encoded_len());
+            // Encoding fails only if the buffer has too little capacity.
+            msg.encode(buf).unwrap();
+        }
+    }
+    Ok(())
+}
+
+/// Append a client response message in JSON to a buffer.
+pub fn write_json_msg<T: Serialize>(msg: &T, buf: &mut BytesMut) -> Result<()> {
+    serde_json::ser::to_writer(buf.writer(), msg)?;
+    Ok(())
+}
+
+/// Append a message of a cursor response to a buffer. The messages are
+/// separated by newlines in JSON and length-delimited in Protobuf.
+pub fn encode_cursor_msg<T: Serialize + prost::Message>(
+    msg: &T,
+    encoding: Encoding,
+    buf: &mut BytesMut,
+) -> Result<()> {
+    match encoding {
+        Encoding::Json => {
+            serde_json::ser::to_writer(buf.writer(), msg)?;
+            buf.extend_from_slice(b"\n");
+        }
+        Encoding::Protobuf => {
+            buf.reserve(msg.encoded_len() + 10);
+            // Encoding fails only if the buffer has too little capacity.
+            msg.encode_length_delimited(buf).unwrap();
+        }
+    }
+    Ok(())
+}
+
+/// Writes the body of a pipeline response one result at a time, so that the
+/// results do not have to be collected before the body is sent.
+///
+/// The results are written before the baton, because the baton is only known
+/// once the whole pipeline has been executed. In JSON, the rows of an execute
+/// result can also be written one at a time; in Protobuf, a result is
+/// preceded by its length, so it must be written whole.
+pub struct PipelineRespWriter {
+    encoding: Encoding,
+    /// The number of results written so far.
+    results: usize,
+    /// The number of rows of the current execute result written so far.
+    rows: usize,
+}
+
+/// The fields of a statement result that follow its rows.
+#[derive(Serialize)]
+struct StmtResultTail {
+    affected_row_count: u64,
+    #[serde(with = "option_i64_as_str")]
+    last_insert_rowid: Option<i64>,
+    #[serde(with = "option_u64_as_str")]
+    replication_index: Option<u64>,
+    rows_read: u64,
+    rows_written: u64,
+    query_duration_ms: f64,
+}
+
+impl PipelineRespWriter {
+    pub fn new(encoding: Encoding) -> Self {
+        Self {
+            encoding,
+            results: 0,
+            rows: 0,
+        }
+    }
+
+    /// Returns `true` if the rows of execute results can be written one at a
+    /// time.
+    pub fn streams_rows(&self) -> bool {
+        self.encoding == Encoding::Json
+    }
+
+    /// Write the start of the body.
+    pub fn begin(&mut self, buf: &mut BytesMut) {
+        if self.encoding == Encoding::Json {
+            buf.extend_from_slice(b"{\"results\":[");
+        }
+    }
+
+    /// Write a whole result.
+    pub fn write_result(&mut self, result: StreamResult, buf: &mut BytesMut) -> Result<()> {
+        match self.encoding {
+            Encoding::Json => {
+                self.write_separator(buf);
+                serde_json::ser::to_writer(buf.writer(), &result)?;
+            }
+            Encoding::Protobuf => {
+                // Concatenated Protobuf messages are merged, which appends
+                // the elements of repeated fields.
+                let msg = PipelineRespBody {
+                    results: vec![result],
+                    ..Default::default()
+                };
+                write_msg(&msg, Encoding::Protobuf, buf)?;
+            }
+        }
+        self.results += 1;
+        Ok(())
+    }
+
+    /// Write the start of an execute result whose rows are written one at a
+    /// time. Only JSON supports this.
+    pub fn begin_execute_result(&mut self, cols: &[Col], buf: &mut BytesMut) -> Result<()> {
+        debug_assert!(self.streams_rows());
+        self.write_separator(buf);
+        buf.extend_from_slice(b"{\"type\":\"ok\",\"response\":{\"type\":\"execute\",\"result\":");
+        buf.extend_from_slice(b"{\"cols\":");
+        serde_json::ser::to_writer(buf.writer(), cols)?;
+        buf.extend_from_slice(b",\"rows\":[");
+        self.rows = 0;
+        Ok(())
+    }
+
+    /// Write a row of the current execute result.
+    pub fn write_row(&mut self, row: &Row, buf: &mut BytesMut) -> Result<()> {
+        if self.rows > 0 {
+            buf.extend_from_slice(b",");
+        }
+        serde_json::ser::to_writer(buf.writer(), row)?;
+        self.rows += 1;
+        Ok(())
+    }
+
+    /// Write the end of the current execute result. The columns and rows of
+    /// `result` are ignored, because they have been written already.
+    pub fn end_execute_result(&mut self, result: &StmtResult, buf: &mut BytesMut) -> Result<()> {
+        let tail = StmtResultTail {
+            affected_row_count: result.affected_row_count,
+            last_insert_rowid: result.last_insert_rowid,
+            replication_index: result.replication_index,
+            rows_read: result.rows_read,
+            rows_written: result.rows_written,
+            query_duration_ms: result.query_duration_ms,
+        };
+        let tail = serde_json::ser::to_vec(&tail)?;
+        // The fields of the tail continue the object that the rows are in.
+        buf.extend_from_slice(b"],");
+        buf.extend_from_slice(&tail[1..]);
+        buf.extend_from_slice(b"}}");
+        self.results += 1;
+        Ok(())
+    }
+
+    /// Write the end of the body with the baton for the next request.
+    pub fn end(&mut self, baton: Option<String>, buf: &mut BytesMut) -> Result<()> {
+        match self.encoding {
+            Encoding::Json => {
+                buf.extend_from_slice(b"],\"baton\":");
+                serde_json::ser::to_writer(buf.writer(), &baton)?;
+                buf.extend_from_slice(b",\"base_url\":null}");
+            }
+            Encoding::Protobuf => {
+                let msg = PipelineRespBody {
+                    baton,
+                    ..Default::default()
+                };
+                write_msg(&msg, Encoding::Protobuf, buf)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_separator(&self, buf: &mut BytesMut) {
+        if self.results > 0 {
+            buf.extend_from_slice(b",");
+        }
+    }
+}
+
+/// Version of the Hrana protocol that a client speaks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Version {
+    Hrana1,
+    Hrana2,
+    Hrana3,
+}
+
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Version::Hrana1 => write!(f, "Hrana 1"),
+            Version::Hrana2 => write!(f, "Hrana 2"),
+            Version::Hrana3 => write!(f, "Hrana 3"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct PipelineReqBody {
     pub baton: Option<String>,
     pub requests: Vec<StreamRequest>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct PipelineRespBody {
     pub baton: Option<String>,
     pub base_url: Option<String>,
@@ -43,13 +301,13 @@ pub enum StreamResult {
     },
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct CursorReqBody {
     pub baton: Option<String>,
     pub batch: Batch,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct CursorRespBody {
     pub baton: Option<String>,
     pub base_url: Option<String>,
@@ -71,9 +329,12 @@ pub enum StreamRequest {
     GetAutocommit(GetAutocommitStreamReq),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum StreamResponse {
+    #[serde(skip_deserializing)]
+    #[default]
+    None,
     Close(CloseStreamResp),
     Execute(ExecuteStreamResp),
     Batch(BatchStreamResp),
@@ -84,33 +345,33 @@ pub enum StreamResponse {
     GetAutocommit(GetAutocommitStreamResp),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct CloseStreamReq {}
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct CloseStreamResp {}
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct ExecuteStreamReq {
     pub stmt: Stmt,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct ExecuteStreamResp {
     pub result: StmtResult,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct BatchStreamReq {
     pub batch: Batch,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct BatchStreamResp {
     pub result: BatchResult,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct SequenceStreamReq {
     #[serde(default)]
     pub sql: Option<String>,
@@ -120,10 +381,10 @@ pub struct SequenceStreamReq {
     pub replication_index: Option<u64>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct SequenceStreamResp {}
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct DescribeStreamReq {
     #[serde(default)]
     pub sql: Option<String>,
@@ -133,43 +394,43 @@ pub struct DescribeStreamReq {
     pub replication_index: Option<u64>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct DescribeStreamResp {
     pub result: DescribeResult,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct StoreSqlStreamReq {
     pub sql_id: i32,
     pub sql: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct StoreSqlStreamResp {}
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct CloseSqlStreamReq {
     pub sql_id: i32,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct CloseSqlStreamResp {}
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct GetAutocommitStreamReq {}
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct GetAutocommitStreamResp {
     pub is_autocommit: bool,
 }
 
-#[derive(Clone, Deserialize, Serialize, Debug)]
+#[derive(Clone, Deserialize, Serialize, Debug, Default)]
 pub struct Error {
     pub message: String,
     pub code: String,
 }
 
-#[derive(Clone, Deserialize, Serialize, Debug)]
+#[derive(Clone, Deserialize, Serialize, Debug, Default)]
 pub struct Stmt {
     #[serde(default)]
     pub sql: Option<String>,
@@ -206,13 +467,13 @@ impl Stmt {
     }
 }
 
-#[derive(Clone, Deserialize, Serialize, Debug)]
+#[derive(Clone, Deserialize, Serialize, Debug, Default)]
 pub struct NamedArg {
     pub name: String,
     pub value: Value,
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
 pub struct StmtResult {
     pub cols: Vec<Col>,
     pub rows: Vec<Row>,
@@ -229,19 +490,19 @@ pub struct StmtResult {
     pub query_duration_ms: f64,
 }
 
-#[derive(Clone, Deserialize, Serialize, Debug)]
+#[derive(Clone, Deserialize, Serialize, Debug, Default)]
 pub struct Col {
     pub name: Option<String>,
     pub decltype: Option<String>,
 }
 
-#[derive(Clone, Deserialize, Serialize, Debug)]
+#[derive(Clone, Deserialize, Serialize, Debug, Default)]
 #[serde(transparent)]
 pub struct Row {
     pub values: Vec<Value>,
 }
 
-#[derive(Clone, Deserialize, Serialize, Debug)]
+#[derive(Clone, Deserialize, Serialize, Debug, Default)]
 pub struct Batch {
     pub steps: Vec<BatchStep>,
     #[serde(default, with = "option_u64_as_str")]
@@ -307,7 +568,7 @@ impl FromIterator<Stmt> for Batch {
     }
 }
 
-#[derive(Clone, Deserialize, Serialize, Debug)]
+#[derive(Clone, Deserialize, Serialize, Debug, Default)]
 pub struct BatchStep {
     #[serde(default)]
     pub condition: Option<BatchCond>,
@@ -342,7 +603,7 @@ pub enum BatchCond {
     IsAutocommit {},
 }
 
-#[derive(Clone, Deserialize, Serialize, Debug)]
+#[derive(Clone, Deserialize, Serialize, Debug, Default)]
 pub struct BatchCondList {
     pub conds: Vec<BatchCond>,
 }
@@ -367,25 +628,26 @@ pub enum CursorEntry {
     },
 }
 
-#[derive(Clone, Deserialize, Serialize, Debug)]
+#[derive(Clone, Deserialize, Serialize, Debug, Default)]
 pub struct StepBeginEntry {
     pub step: u32,
     pub cols: Vec<Col>,
 }
 
-#[derive(Clone, Deserialize, Serialize, Debug)]
+#[derive(Clone, Deserialize, Serialize, Debug, Default)]
 pub struct StepEndEntry {
     pub affected_row_count: u64,
+    #[serde(with = "option_i64_as_str")]
     pub last_insert_rowid: Option<i64>,
 }
 
-#[derive(Clone, Deserialize, Serialize, Debug)]
+#[derive(Clone, Deserialize, Serialize, Debug, Default)]
 pub struct StepErrorEntry {
     pub step: u32,
     pub error: Error,
 }
 
-#[derive(Clone, Deserialize, Serialize, Debug)]
+#[derive(Clone, Deserialize, Serialize, Debug, Default)]
 pub struct DescribeResult {
     pub params: Vec<DescribeParam>,
     pub cols: Vec<DescribeCol>,
@@ -393,12 +655,12 @@ pub struct DescribeResult {
     pub is_readonly: bool,
 }
 
-#[derive(Clone, Deserialize, Serialize, Debug)]
+#[derive(Clone, Deserialize, Serialize, Debug, Default)]
 pub struct DescribeParam {
     pub name: Option<String>,
 }
 
-#[derive(Clone, Deserialize, Serialize, Debug)]
+#[derive(Clone, Deserialize, Serialize, Debug, Default)]
 pub struct DescribeCol {
     pub name: String,
     pub decltype: Option<String>,
@@ -428,11 +690,12 @@ pub enum Value {
 }
 
 mod i64_as_str {
+    use serde::de::Error as _;
     use serde::{de, ser};
-    use serde::{de::Error as _, Serialize as _};
 
     pub fn serialize<S: ser::Serializer>(value: &i64, ser: S) -> Result<S::Ok, S::Error> {
-        value.to_string().serialize(ser)
+        // The value is formatted straight into the output.
+        ser.collect_str(value)
     }
 
     pub fn deserialize<'de, D: de::Deserializer<'de>>(de: D) -> Result<i64, D::Error> {
@@ -448,10 +711,13 @@ mod i64_as_str {
 
 mod option_i64_as_str {
     use serde::de::{Error, Visitor};
-    use serde::{ser, Deserializer, Serialize as _};
+    use serde::{ser, Deserializer};
 
     pub fn serialize<S: ser::Serializer>(value: &Option<i64>, ser: S) -> Result<S::Ok, S::Error> {
-        value.map(|v| v.to_string()).serialize(ser)
+        match value {
+            Some(value) => ser.collect_str(value),
+            None => ser.serialize_none(),
+        }
     }
 
     pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<i64>, D::Error> {
@@ -506,10 +772,13 @@ mod option_i64_as_str {
 
 pub mod option_u64_as_str {
     use serde::de::Error;
-    use serde::{de::Visitor, ser, Deserializer, Serialize as _};
+    use serde::{de::Visitor, ser, Deserializer};
 
     pub fn serialize<S: ser::Serializer>(value: &Option<u64>, ser: S) -> Result<S::Ok, S::Error> {
-        value.map(|v| v.to_string()).serialize(ser)
+        match value {
+            Some(value) => ser.collect_str(value),
+            None => ser.serialize_none(),
+        }
     }
 
     pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u64>, D::Error> {
@@ -589,13 +858,14 @@ pub mod option_u64_as_str {
 }
 
 mod bytes_as_base64 {
+    use base64::display::Base64Display;
     use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine as _};
     use bytes::Bytes;
+    use serde::de::Error as _;
     use serde::{de, ser};
-    use serde::{de::Error as _, Serialize as _};
 
     pub fn serialize<S: ser::Serializer>(value: &Bytes, ser: S) -> Result<S::Ok, S::Error> {
-        STANDARD_NO_PAD.encode(value).serialize(ser)
+        ser.collect_str(&Base64Display::new(value, &STANDARD_NO_PAD))
     }
 
     pub fn deserialize<'de, D: de::Deserializer<'de>>(de: D) -> Result<Bytes, D::Error> {
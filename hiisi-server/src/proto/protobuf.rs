@@ -0,0 +1,791 @@
+//! Protobuf encoding of the Hrana messages.
+//!
+//! The messages are encoded according to the `hrana.proto` schema of Hrana 3.
+//! We implement `prost::Message` by hand instead of generating the types, so
+//! that the same types can be used for both the JSON and Protobuf encodings.
+
+use ::bytes::{Buf, BufMut, Bytes};
+use prost::encoding::{
+    bytes, encode_key, encode_varint, encoded_len_varint, key_len, message, sint64, skip_field,
+    string, uint32, DecodeContext, WireType,
+};
+use prost::{DecodeError, Message};
+use std::collections::BTreeMap;
+use std::result::Result;
+use std::sync::Arc;
+
+use super::*;
+
+macro_rules! encode_field {
+    (required $kind:ident, $tag:expr, $value:expr, $buf:expr) => {
+        prost::encoding::$kind::encode($tag, $value, $buf)
+    };
+    (optional $kind:ident, $tag:expr, $value:expr, $buf:expr) => {
+        if let Some(value) = $value {
+            prost::encoding::$kind::encode($tag, value, $buf)
+        }
+    };
+    (repeated $kind:ident, $tag:expr, $value:expr, $buf:expr) => {
+        prost::encoding::$kind::encode_repeated($tag, $value, $buf)
+    };
+}
+
+macro_rules! merge_field {
+    (required $kind:ident, $wire_type:expr, $value:expr, $buf:expr, $ctx:expr) => {
+        prost::encoding::$kind::merge($wire_type, $value, $buf, $ctx)
+    };
+    (optional $kind:ident, $wire_type:expr, $value:expr, $buf:expr, $ctx:expr) => {
+        prost::encoding::$kind::merge(
+            $wire_type,
+            $value.get_or_insert_with(Default::default),
+            $buf,
+            $ctx,
+        )
+    };
+    (repeated $kind:ident, $wire_type:expr, $value:expr, $buf:expr, $ctx:expr) => {
+        prost::encoding::$kind::merge_repeated($wire_type, $value, $buf, $ctx)
+    };
+}
+
+macro_rules! encoded_len_field {
+    (required $kind:ident, $tag:expr, $value:expr) => {
+        prost::encoding::$kind::encoded_len($tag, $value)
+    };
+    (optional $kind:ident, $tag:expr, $value:expr) => {
+        $value
+            .as_ref()
+            .map_or(0, |value| prost::encoding::$kind::encoded_len($tag, value))
+    };
+    (repeated $kind:ident, $tag:expr, $value:expr) => {
+        prost::encoding::$kind::encoded_len_repeated($tag, $value)
+    };
+}
+
+/// Implement `prost::Message` for a struct whose fields map directly to the
+/// fields of a Protobuf message.
+macro_rules! impl_message {
+    ($ty:ident { $($tag:literal => $field:ident: $label:ident $kind:ident,)* }) => {
+        impl Message for $ty {
+            #[allow(unused_variables)]
+            fn encode_raw<B: BufMut>(&self, buf: &mut B) {
+                $(encode_field!($label $kind, $tag, &self.$field, buf);)*
+            }
+
+            fn merge_field<B: Buf>(
+                &mut self,
+                tag: u32,
+                wire_type: WireType,
+                buf: &mut B,
+                ctx: DecodeContext,
+            ) -> Result<(), DecodeError> {
+                match tag {
+                    $($tag => merge_field!($label $kind, wire_type, &mut self.$field, buf, ctx),)*
+                    _ => skip_field(wire_type, tag, buf, ctx),
+                }
+            }
+
+            fn encoded_len(&self) -> usize {
+                0 $(+ encoded_len_field!($label $kind, $tag, &self.$field))*
+            }
+
+            fn clear(&mut self) {
+                $(self.$field = Default::default();)*
+            }
+        }
+    };
+}
+
+/// Implement `prost::Message` for an enum that maps to a Protobuf message
+/// with a single `oneof` of messages.
+macro_rules! impl_oneof {
+    ($ty:ident { $($tag:literal => $variant:ident($inner:ty),)* }) => {
+        impl Message for $ty {
+            fn encode_raw<B: BufMut>(&self, buf: &mut B) {
+                match self {
+                    $ty::None => {}
+                    $($ty::$variant(msg) => message::encode($tag, msg, buf),)*
+                }
+            }
+
+            fn merge_field<B: Buf>(
+                &mut self,
+                tag: u32,
+                wire_type: WireType,
+                buf: &mut B,
+                ctx: DecodeContext,
+            ) -> Result<(), DecodeError> {
+                match tag {
+                    $($tag => {
+                        let mut msg = <$inner>::default();
+                        message::merge(wire_type, &mut msg, buf, ctx)?;
+                        *self = $ty::$variant(msg);
+                        Ok(())
+                    })*
+                    _ => skip_field(wire_type, tag, buf, ctx),
+                }
+            }
+
+            fn encoded_len(&self) -> usize {
+                match self {
+                    $ty::None => 0,
+                    $($ty::$variant(msg) => message::encoded_len($tag, msg),)*
+                }
+            }
+
+            fn clear(&mut self) {
+                *self = $ty::None;
+            }
+        }
+    };
+}
+
+impl_message!(PipelineReqBody {
+    1 => baton: optional string,
+    2 => requests: repeated message,
+});
+
+impl_message!(PipelineRespBody {
+    1 => baton: optional string,
+    2 => base_url: optional string,
+    3 => results: repeated message,
+});
+
+impl_message!(CursorReqBody {
+    1 => baton: optional string,
+    2 => batch: required message,
+});
+
+impl_message!(CursorRespBody {
+    1 => baton: optional string,
+    2 => base_url: optional string,
+});
+
+impl Message for StreamResult {
+    fn encode_raw<B: BufMut>(&self, buf: &mut B) {
+        match self {
+            StreamResult::None => {}
+            StreamResult::Ok { response } => message::encode(1, response, buf),
+            StreamResult::Error { error } => message::encode(2, error, buf),
+        }
+    }
+
+    fn merge_field<B: Buf>(
+        &mut self,
+        tag: u32,
+        wire_type: WireType,
+        buf: &mut B,
+        ctx: DecodeContext,
+    ) -> Result<(), DecodeError> {
+        match tag {
+            1 => {
+                let mut response = StreamResponse::default();
+                message::merge(wire_type, &mut response, buf, ctx)?;
+                *self = StreamResult::Ok { response };
+            }
+            2 => {
+                let mut error = Error::default();
+                message::merge(wire_type, &mut error, buf, ctx)?;
+                *self = StreamResult::Error { error };
+            }
+            _ => skip_field(wire_type, tag, buf, ctx)?,
+        }
+        Ok(())
+    }
+
+    fn encoded_len(&self) -> usize {
+        match self {
+            StreamResult::None => 0,
+            StreamResult::Ok { response } => message::encoded_len(1, response),
+            StreamResult::Error { error } => message::encoded_len(2, error),
+        }
+    }
+
+    fn clear(&mut self) {
+        *self = StreamResult::None;
+    }
+}
+
+impl_oneof!(StreamRequest {
+    1 => Close(CloseStreamReq),
+    2 => Execute(ExecuteStreamReq),
+    3 => Batch(BatchStreamReq),
+    4 => Sequence(SequenceStreamReq),
+    5 => Describe(DescribeStreamReq),
+    6 => StoreSql(StoreSqlStreamReq),
+    7 => CloseSql(CloseSqlStreamReq),
+    8 => GetAutocommit(GetAutocommitStreamReq),
+});
+
+impl_oneof!(StreamResponse {
+    1 => Close(CloseStreamResp),
+    2 => Execute(ExecuteStreamResp),
+    3 => Batch(BatchStreamResp),
+    4 => Sequence(SequenceStreamResp),
+    5 => Describe(DescribeStreamResp),
+    6 => StoreSql(StoreSqlStreamResp),
+    7 => CloseSql(CloseSqlStreamResp),
+    8 => GetAutocommit(GetAutocommitStreamResp),
+});
+
+impl_message!(CloseStreamReq {});
+
+impl_message!(CloseStreamResp {});
+
+impl_message!(ExecuteStreamReq {
+    1 => stmt: required message,
+});
+
+impl_message!(ExecuteStreamResp {
+    1 => result: required message,
+});
+
+impl_message!(BatchStreamReq {
+    1 => batch: required message,
+});
+
+impl_message!(BatchStreamResp {
+    1 => result: required message,
+});
+
+impl_message!(SequenceStreamReq {
+    1 => sql: optional string,
+    2 => sql_id: optional int32,
+    3 => replication_index: optional uint64,
+});
+
+impl_message!(SequenceStreamResp {});
+
+impl_message!(DescribeStreamReq {
+    1 => sql: optional string,
+    2 => sql_id: optional int32,
+    3 => replication_index: optional uint64,
+});
+
+impl_message!(DescribeStreamResp {
+    1 => result: required message,
+});
+
+impl_message!(StoreSqlStreamReq {
+    1 => sql_id: required int32,
+    2 => sql: required string,
+});
+
+impl_message!(StoreSqlStreamResp {});
+
+impl_message!(CloseSqlStreamReq {
+    1 => sql_id: required int32,
+});
+
+impl_message!(CloseSqlStreamResp {});
+
+impl_message!(GetAutocommitStreamReq {});
+
+impl_message!(GetAutocommitStreamResp {
+    1 => is_autocommit: required bool,
+});
+
+impl_message!(Error {
+    1 => message: required string,
+    2 => code: required string,
+});
+
+impl_message!(Stmt {
+    1 => sql: optional string,
+    2 => sql_id: optional int32,
+    3 => args: repeated message,
+    4 => named_args: repeated message,
+    5 => want_rows: optional bool,
+    6 => replication_index: optional uint64,
+});
+
+impl_message!(NamedArg {
+    1 => name: required string,
+    2 => value: required message,
+});
+
+impl_message!(StmtResult {
+    1 => cols: repeated message,
+    2 => rows: repeated message,
+    3 => affected_row_count: required uint64,
+    4 => last_insert_rowid: optional sint64,
+    5 => replication_index: optional uint64,
+    6 => rows_read: required uint64,
+    7 => rows_written: required uint64,
+    8 => query_duration_ms: required double,
+});
+
+impl_message!(Col {
+    1 => name: optional string,
+    2 => decltype: optional string,
+});
+
+impl_message!(Row {
+    1 => values: repeated message,
+});
+
+impl_message!(Batch {
+    1 => steps: repeated message,
+    2 => replication_index: optional uint64,
+});
+
+impl_message!(BatchStep {
+    1 => condition: optional message,
+    2 => stmt: required message,
+});
+
+impl Message for BatchCond {
+    fn encode_raw<B: BufMut>(&self, buf: &mut B) {
+        match self {
+            BatchCond::None => {}
+            BatchCond::Ok { step } => uint32::encode(1, step, buf),
+            BatchCond::Error { step } => uint32::encode(2, step, buf),
+            BatchCond::Not { cond } => message::encode(3, &**cond, buf),
+            BatchCond::And(list) => message::encode(4, list, buf),
+            BatchCond::Or(list) => message::encode(5, list, buf),
+            BatchCond::IsAutocommit {} => message::encode(6, &(), buf),
+        }
+    }
+
+    fn merge_field<B: Buf>(
+        &mut self,
+        tag: u32,
+        wire_type: WireType,
+        buf: &mut B,
+        ctx: DecodeContext,
+    ) -> Result<(), DecodeError> {
+        match tag {
+            1 | 2 => {
+                let mut step = 0;
+                uint32::merge(wire_type, &mut step, buf, ctx)?;
+                *self = if tag == 1 {
+                    BatchCond::Ok { step }
+                } else {
+                    BatchCond::Error { step }
+                };
+            }
+            3 => {
+                let mut cond = BatchCond::default();
+                message::merge(wire_type, &mut cond, buf, ctx)?;
+                *self = BatchCond::Not {
+                    cond: Box::new(cond),
+                };
+            }
+            4 | 5 => {
+                let mut list = BatchCondList::default();
+                message::merge(wire_type, &mut list, buf, ctx)?;
+                *self = if tag == 4 {
+                    BatchCond::And(list)
+                } else {
+                    BatchCond::Or(list)
+                };
+            }
+            6 => {
+                message::merge(wire_type, &mut (), buf, ctx)?;
+                *self = BatchCond::IsAutocommit {};
+            }
+            _ => skip_field(wire_type, tag, buf, ctx)?,
+        }
+        Ok(())
+    }
+
+    fn encoded_len(&self) -> usize {
+        match self {
+            BatchCond::None => 0,
+            BatchCond::Ok { step } => uint32::encoded_len(1, step),
+            BatchCond::Error { step } => uint32::encoded_len(2, step),
+            BatchCond::Not { cond } => message::encoded_len(3, &**cond),
+            BatchCond::And(list) => message::encoded_len(4, list),
+            BatchCond::Or(list) => message::encoded_len(5, list),
+            BatchCond::IsAutocommit {} => message::encoded_len(6, &()),
+        }
+    }
+
+    fn clear(&mut self) {
+        *self = BatchCond::None;
+    }
+}
+
+impl_message!(BatchCondList {
+    1 => conds: repeated message,
+});
+
+// The step results and errors are encoded as `map<uint32, ...>` fields, which
+// omit the steps that have no result or error.
+impl Message for BatchResult {
+    fn encode_raw<B: BufMut>(&self, buf: &mut B) {
+        for (step, result) in self.step_results.iter().enumerate() {
+            if let Some(result) = result {
+                encode_map_entry(1, step as u32, result, buf);
+            }
+        }
+        for (step, error) in self.step_errors.iter().enumerate() {
+            if let Some(error) = error {
+                encode_map_entry(2, step as u32, error, buf);
+            }
+        }
+        if let Some(replication_index) = &self.replication_index {
+            prost::encoding::uint64::encode(3, replication_index, buf);
+        }
+    }
+
+    fn merge_field<B: Buf>(
+        &mut self,
+        tag: u32,
+        wire_type: WireType,
+        buf: &mut B,
+        ctx: DecodeContext,
+    ) -> Result<(), DecodeError> {
+        match tag {
+            1 => merge_map_entry(wire_type, &mut self.step_results, buf, ctx),
+            2 => merge_map_entry(wire_type, &mut self.step_errors, buf, ctx),
+            3 => prost::encoding::uint64::merge(
+                wire_type,
+                self.replication_index.get_or_insert(0),
+                buf,
+                ctx,
+            ),
+            _ => skip_field(wire_type, tag, buf, ctx),
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        let step_results: usize = (self.step_results.iter().enumerate())
+            .filter_map(|(step, result)| Some(map_entry_len(1, step as u32, result.as_ref()?)))
+            .sum();
+        let step_errors: usize = (self.step_errors.iter().enumerate())
+            .filter_map(|(step, error)| Some(map_entry_len(2, step as u32, error.as_ref()?)))
+            .sum();
+        let replication_index = self
+            .replication_index
+            .as_ref()
+            .map_or(0, |index| prost::encoding::uint64::encoded_len(3, index));
+        step_results + step_errors + replication_index
+    }
+
+    fn clear(&mut self) {
+        self.step_results.clear();
+        self.step_errors.clear();
+        self.replication_index = None;
+    }
+}
+
+fn encode_map_entry<M: Message, B: BufMut>(tag: u32, key: u32, value: &M, buf: &mut B) {
+    encode_key(tag, WireType::LengthDelimited, buf);
+    encode_varint(map_entry_body_len(key, value) as u64, buf);
+    uint32::encode(1, &key, buf);
+    message::encode(2, value, buf);
+}
+
+fn map_entry_len<M: Message>(tag: u32, key: u32, value: &M) -> usize {
+    let len = map_entry_body_len(key, value);
+    key_len(tag) + encoded_len_varint(len as u64) + len
+}
+
+fn map_entry_body_len<M: Message>(key: u32, value: &M) -> usize {
+    uint32::encoded_len(1, &key) + message::encoded_len(2, value)
+}
+
+fn merge_map_entry<M: Message + Default, B: Buf>(
+    wire_type: WireType,
+    values: &mut Vec<Option<M>>,
+    buf: &mut B,
+    ctx: DecodeContext,
+) -> Result<(), DecodeError> {
+    prost::encoding::check_wire_type(WireType::LengthDelimited, wire_type)?;
+    let mut entry = BTreeMap::new();
+    prost::encoding::btree_map::merge(uint32::merge, message::merge, &mut entry, buf, ctx)?;
+    for (step, value) in entry {
+        let step = step as usize;
+        if values.len() <= step {
+            values.resize_with(step + 1, || None);
+        }
+        values[step] = Some(value);
+    }
+    Ok(())
+}
+
+impl Message for CursorEntry {
+    fn encode_raw<B: BufMut>(&self, buf: &mut B) {
+        match self {
+            CursorEntry::None => {}
+            CursorEntry::StepBegin(entry) => message::encode(1, entry, buf),
+            CursorEntry::StepEnd(entry) => message::encode(2, entry, buf),
+            CursorEntry::StepError(entry) => message::encode(3, entry, buf),
+            CursorEntry::Row { row } => message::encode(4, row, buf),
+            CursorEntry::Error { error } => message::encode(5, error, buf),
+            CursorEntry::ReplicationIndex { replication_index } => {
+                let entry = ReplicationIndexEntry {
+                    replication_index: *replication_index,
+                };
+                message::encode(6, &entry, buf)
+            }
+        }
+    }
+
+    fn merge_field<B: Buf>(
+        &mut self,
+        tag: u32,
+        wire_type: WireType,
+        buf: &mut B,
+        ctx: DecodeContext,
+    ) -> Result<(), DecodeError> {
+        match tag {
+            1 => {
+                let mut entry = StepBeginEntry::default();
+                message::merge(wire_type, &mut entry, buf, ctx)?;
+                *self = CursorEntry::StepBegin(entry);
+            }
+            2 => {
+                let mut entry = StepEndEntry::default();
+                message::merge(wire_type, &mut entry, buf, ctx)?;
+                *self = CursorEntry::StepEnd(entry);
+            }
+            3 => {
+                let mut entry = StepErrorEntry::default();
+                message::merge(wire_type, &mut entry, buf, ctx)?;
+                *self = CursorEntry::StepError(entry);
+            }
+            4 => {
+                let mut row = Row::default();
+                message::merge(wire_type, &mut row, buf, ctx)?;
+                *self = CursorEntry::Row { row };
+            }
+            5 => {
+                let mut error = Error::default();
+                message::merge(wire_type, &mut error, buf, ctx)?;
+                *self = CursorEntry::Error { error };
+            }
+            6 => {
+                let mut entry = ReplicationIndexEntry::default();
+                message::merge(wire_type, &mut entry, buf, ctx)?;
+                *self = CursorEntry::ReplicationIndex {
+                    replication_index: entry.replication_index,
+                };
+            }
+            _ => skip_field(wire_type, tag, buf, ctx)?,
+        }
+        Ok(())
+    }
+
+    fn encoded_len(&self) -> usize {
+        match self {
+            CursorEntry::None => 0,
+            CursorEntry::StepBegin(entry) => message::encoded_len(1, entry),
+            CursorEntry::StepEnd(entry) => message::encoded_len(2, entry),
+            CursorEntry::StepError(entry) => message::encoded_len(3, entry),
+            CursorEntry::Row { row } => message::encoded_len(4, row),
+            CursorEntry::Error { error } => message::encoded_len(5, error),
+            CursorEntry::ReplicationIndex { replication_index } => {
+                let entry = ReplicationIndexEntry {
+                    replication_index: *replication_index,
+                };
+                message::encoded_len(6, &entry)
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        *self = CursorEntry::None;
+    }
+}
+
+#[derive(Debug, Default)]
+struct ReplicationIndexEntry {
+    replication_index: Option<u64>,
+}
+
+impl_message!(ReplicationIndexEntry {
+    1 => replication_index: optional uint64,
+});
+
+impl_message!(StepBeginEntry {
+    1 => step: required uint32,
+    2 => cols: repeated message,
+});
+
+impl_message!(StepEndEntry {
+    1 => affected_row_count: required uint64,
+    2 => last_insert_rowid: optional sint64,
+});
+
+impl_message!(StepErrorEntry {
+    1 => step: required uint32,
+    2 => error: required message,
+});
+
+impl_message!(DescribeResult {
+    1 => params: repeated message,
+    2 => cols: repeated message,
+    3 => is_explain: required bool,
+    4 => is_readonly: required bool,
+});
+
+impl_message!(DescribeParam {
+    1 => name: optional string,
+});
+
+impl_message!(DescribeCol {
+    1 => name: required string,
+    2 => decltype: optional string,
+});
+
+impl Message for Value {
+    fn encode_raw<B: BufMut>(&self, buf: &mut B) {
+        match self {
+            Value::None => {}
+            Value::Null => message::encode(1, &(), buf),
+            Value::Integer { value } => sint64::encode(2, value, buf),
+            Value::Float { value } => prost::encoding::double::encode(3, value, buf),
+            Value::Text { value } => {
+                // `string::encode()` only accepts a `String`.
+                encode_key(4, WireType::LengthDelimited, buf);
+                encode_varint(value.len() as u64, buf);
+                buf.put_slice(value.as_bytes());
+            }
+            Value::Blob { value } => bytes::encode(5, value, buf),
+        }
+    }
+
+    fn merge_field<B: Buf>(
+        &mut self,
+        tag: u32,
+        wire_type: WireType,
+        buf: &mut B,
+        ctx: DecodeContext,
+    ) -> Result<(), DecodeError> {
+        match tag {
+            1 => {
+                message::merge(wire_type, &mut (), buf, ctx)?;
+                *self = Value::Null;
+            }
+            2 => {
+                let mut value = 0;
+                sint64::merge(wire_type, &mut value, buf, ctx)?;
+                *self = Value::Integer { value };
+            }
+            3 => {
+                let mut value = 0.;
+                prost::encoding::double::merge(wire_type, &mut value, buf, ctx)?;
+                *self = Value::Float { value };
+            }
+            4 => {
+                let mut value = String::new();
+                string::merge(wire_type, &mut value, buf, ctx)?;
+                *self = Value::Text {
+                    value: Arc::from(value),
+                };
+            }
+            5 => {
+                let mut value = Bytes::new();
+                bytes::merge(wire_type, &mut value, buf, ctx)?;
+                *self = Value::Blob { value };
+            }
+            _ => skip_field(wire_type, tag, buf, ctx)?,
+        }
+        Ok(())
+    }
+
+    fn encoded_len(&self) -> usize {
+        match self {
+            Value::None => 0,
+            Value::Null => message::encoded_len(1, &()),
+            Value::Integer { value } => sint64::encoded_len(2, value),
+            Value::Float { value } => prost::encoding::double::encoded_len(3, value),
+            Value::Text { value } => {
+                key_len(4) + encoded_len_varint(value.len() as u64) + value.len()
+            }
+            Value::Blob { value } => bytes::encoded_len(5, value),
+        }
+    }
+
+    fn clear(&mut self) {
+        *self = Value::None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_pipeline_req() {
+        // A pipeline with one `execute` request of `SELECT 1`.
+        let mut msg = vec![0x12, 0x0e, 0x12, 0x0c, 0x0a, 0x0a, 0x0a, 0x08];
+        msg.extend_from_slice(b"SELECT 1");
+        let req = PipelineReqBody::decode(&msg[..]).unwrap();
+        assert!(req.baton.is_none());
+        assert_eq!(req.requests.len(), 1);
+        match &req.requests[0] {
+            StreamRequest::Execute(req) => assert_eq!(req.stmt.sql.as_deref(), Some("SELECT 1")),
+            req => panic!("unexpected request {:?}", req),
+        }
+    }
+
+    #[test]
+    fn encode_cursor_entry() {
+        let entry = CursorEntry::Row {
+            row: Row {
+                values: vec![Value::Integer { value: 1 }],
+            },
+        };
+        assert_eq!(entry.encode_to_vec(), [0x22, 0x04, 0x0a, 0x02, 0x10, 0x02]);
+    }
+
+    #[test]
+    fn roundtrip_batch() {
+        let mut stmt = Stmt::new("SELECT ?, :x", true);
+        stmt.bind(Value::Text {
+            value: Arc::from("hello"),
+        });
+        stmt.bind_named(
+            ":x".to_string(),
+            Value::Blob {
+                value: Bytes::from_static(b"\x00\x01"),
+            },
+        );
+        let mut batch = Batch::transactional([stmt]);
+        batch.steps[1].condition = Some(BatchCond::And(BatchCondList {
+            conds: vec![BatchCond::Ok { step: 0 }, BatchCond::IsAutocommit {}],
+        }));
+        let req = CursorReqBody {
+            baton: Some("baton".to_string()),
+            batch,
+        };
+        let decoded = CursorReqBody::decode(&req.encode_to_vec()[..]).unwrap();
+        assert_eq!(
+            serde_json::to_value(&decoded).unwrap(),
+            serde_json::to_value(&req).unwrap()
+        );
+    }
+
+    #[test]
+    fn roundtrip_batch_result() {
+        let result = BatchResult {
+            step_results: vec![None, Some(StmtResult::default())],
+            step_errors: vec![
+                Some(Error {
+                    message: "error".to_string(),
+                    code: "SQLITE_ERROR".to_string(),
+                }),
+                None,
+            ],
+            replication_index: None,
+        };
+        let decoded = BatchResult::decode(&result.encode_to_vec()[..]).unwrap();
+        assert!(decoded.step_results[0].is_none());
+        assert!(decoded.step_results[1].is_some());
+        assert_eq!(decoded.step_errors[0].as_ref().unwrap().message, "error");
+    }
+
+    #[test]
+    fn reject_deeply_nested_cond() {
+        let mut cond = BatchCond::IsAutocommit {};
+        for _ in 0..1000 {
+            cond = BatchCond::Not {
+                cond: Box::new(cond),
+            };
+        }
+        let msg = cond.encode_to_vec();
+        assert!(BatchCond::decode(&msg[..]).is_err());
+    }
+}
@@ -0,0 +1,97 @@
+//! Messages of the stateless Hrana 1 endpoints over HTTP.
+//!
+//! Hrana 1 over HTTP has no streams: every request executes a single
+//! statement or batch on its own connection. The results lack the fields that
+//! were added in later versions of the protocol.
+
+use serde::{Deserialize, Serialize};
+
+use super::{option_i64_as_str, Batch, Error, Row, Stmt};
+
+/// Parse the body of a `/v1/execute` request.
+pub fn parse_execute_req(msg: &[u8]) -> crate::Result<ExecuteReqBody> {
+    let msg: ExecuteReqBody = serde_json::from_slice(msg)?;
+    Ok(msg)
+}
+
+/// Parse the body of a `/v1/batch` request.
+pub fn parse_batch_req(msg: &[u8]) -> crate::Result<BatchReqBody> {
+    let msg: BatchReqBody = serde_json::from_slice(msg)?;
+    Ok(msg)
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExecuteReqBody {
+    pub stmt: Stmt,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExecuteRespBody {
+    pub result: StmtResult,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BatchReqBody {
+    pub batch: Batch,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BatchRespBody {
+    pub result: BatchResult,
+}
+
+/// The body of an error response.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ErrorRespBody {
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StmtResult {
+    pub cols: Vec<Col>,
+    pub rows: Vec<Row>,
+    pub affected_row_count: u64,
+    #[serde(with = "option_i64_as_str")]
+    pub last_insert_rowid: Option<i64>,
+}
+
+impl From<super::StmtResult> for StmtResult {
+    fn from(result: super::StmtResult) -> Self {
+        Self {
+            cols: result.cols.into_iter().map(Col::from).collect(),
+            rows: result.rows,
+            affected_row_count: result.affected_row_count,
+            last_insert_rowid: result.last_insert_rowid,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Col {
+    pub name: Option<String>,
+}
+
+impl From<super::Col> for Col {
+    fn from(col: super::Col) -> Self {
+        Self { name: col.name }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BatchResult {
+    pub step_results: Vec<Option<StmtResult>>,
+    pub step_errors: Vec<Option<Error>>,
+}
+
+impl From<super::BatchResult> for BatchResult {
+    fn from(result: super::BatchResult) -> Self {
+        Self {
+            step_results: result
+                .step_results
+                .into_iter()
+                .map(|result| result.map(StmtResult::from))
+                .collect(),
+            step_errors: result.step_errors,
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! Messages of the Hrana protocol over WebSocket.
+//!
+//! Over WebSocket, the client opens streams explicitly and identifies them
+//! with stream ids, and requests of all streams are multiplexed over a
+//! single connection.
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    Batch, BatchStreamResp, CloseSqlStreamResp, CursorEntry, DescribeStreamResp, Error,
+    ExecuteStreamResp, GetAutocommitStreamResp, SequenceStreamResp, Stmt, StoreSqlStreamResp,
+};
+
+/// Parse a client message.
+pub fn parse_client_msg(msg: &[u8]) -> crate::Result<ClientMsg> {
+    let msg: ClientMsg = serde_json::from_slice(msg)?;
+    Ok(msg)
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMsg {
+    #[serde(skip_deserializing)]
+    #[default]
+    None,
+    Hello {
+        #[serde(default)]
+        jwt: Option<String>,
+    },
+    Request {
+        request_id: i32,
+        request: Request,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMsg {
+    HelloOk {},
+    HelloError { error: Error },
+    ResponseOk { request_id: i32, response: Response },
+    ResponseError { request_id: i32, error: Error },
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Request {
+    #[serde(skip_deserializing)]
+    #[default]
+    None,
+    OpenStream(OpenStreamReq),
+    CloseStream(CloseStreamReq),
+    Execute(ExecuteReq),
+    Batch(BatchReq),
+    OpenCursor(OpenCursorReq),
+    CloseCursor(CloseCursorReq),
+    FetchCursor(FetchCursorReq),
+    Sequence(SequenceReq),
+    Describe(DescribeReq),
+    StoreSql(StoreSqlReq),
+    CloseSql(CloseSqlReq),
+    GetAutocommit(GetAutocommitReq),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    OpenStream(OpenStreamResp),
+    CloseStream(CloseStreamResp),
+    Execute(ExecuteStreamResp),
+    Batch(BatchStreamResp),
+    OpenCursor(OpenCursorResp),
+    CloseCursor(CloseCursorResp),
+    FetchCursor(FetchCursorResp),
+    Sequence(SequenceStreamResp),
+    Describe(DescribeStreamResp),
+    StoreSql(StoreSqlStreamResp),
+    CloseSql(CloseSqlStreamResp),
+    GetAutocommit(GetAutocommitStreamResp),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OpenStreamReq {
+    pub stream_id: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OpenStreamResp {}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CloseStreamReq {
+    pub stream_id: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CloseStreamResp {}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExecuteReq {
+    pub stream_id: i32,
+    pub stmt: Stmt,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BatchReq {
+    pub stream_id: i32,
+    pub batch: Batch,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OpenCursorReq {
+    pub stream_id: i32,
+    pub cursor_id: i32,
+    pub batch: Batch,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OpenCursorResp {}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CloseCursorReq {
+    pub cursor_id: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CloseCursorResp {}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FetchCursorReq {
+    pub cursor_id: i32,
+    pub max_count: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FetchCursorResp {
+    pub entries: Vec<CursorEntry>,
+    pub done: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SequenceReq {
+    pub stream_id: i32,
+    #[serde(default)]
+    pub sql: Option<String>,
+    #[serde(default)]
+    pub sql_id: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DescribeReq {
+    pub stream_id: i32,
+    #[serde(default)]
+    pub sql: Option<String>,
+    #[serde(default)]
+    pub sql_id: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StoreSqlReq {
+    pub sql_id: i32,
+    pub sql: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CloseSqlReq {
+    pub sql_id: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetAutocommitReq {
+    pub stream_id: i32,
+}
@@ -1,29 +1,217 @@
 use anyhow::Result;
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
+use serde::Serialize;
 use socket2::{SockAddr, Socket};
 
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::os::fd::AsRawFd;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
-use crate::executor::{self, Request};
+use crate::cursor::{Cursor, CursorRequest};
+use crate::database;
+use crate::executor::{self, PendingWrite, Request};
 use crate::http;
+use crate::pipeline::Pipeline;
+use crate::session::{self, Session, SessionSocket};
 use crate::ResourceManager;
 use crate::{proto, HiisiError};
 
 pub type IO<T> = crate::io::IO<Context<T>>;
 
+// Size of the body data to batch into one chunk of a streamed response.
+// Pipeline responses that fit in one chunk are sent whole instead.
+const RESPONSE_CHUNK_SIZE: usize = 16 * 1024;
+
+// Maximum interval between checks for timeouts.
+const MAX_TIMER_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a keep-alive connection can be idle between requests by default.
+pub const DEFAULT_KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(60);
+
 pub struct Context<T> {
     pub manager: Rc<ResourceManager>,
     pub user_data: T,
+
+    /// HTTP connections, keyed by socket.
+    connections: RefCell<HashMap<i32, HttpConnection>>,
+
+    /// Streamed responses that are being sent, keyed by socket.
+    responses: RefCell<HashMap<i32, StreamedResponse>>,
+
+    /// Hrana sessions of WebSocket connections, keyed by socket.
+    pub(crate) sessions: RefCell<HashMap<i32, SessionSocket>>,
+
+    /// Requests that wait for another stream to finish its write
+    /// transaction, in the order they were received.
+    write_queue: RefCell<VecDeque<QueuedRequest>>,
+
+    /// How long a keep-alive connection can be idle between requests before
+    /// we close it.
+    keep_alive_timeout: Duration,
 }
 
 impl<T> Context<T> {
     pub fn new(manager: Rc<ResourceManager>, user_data: T) -> Self {
-        Self { manager, user_data }
+        Self::with_keep_alive_timeout(manager, user_data, DEFAULT_KEEP_ALIVE_TIMEOUT)
+    }
+
+    pub fn with_keep_alive_timeout(
+        manager: Rc<ResourceManager>,
+        user_data: T,
+        keep_alive_timeout: Duration,
+    ) -> Self {
+        Self {
+            manager,
+            user_data,
+            connections: RefCell::new(HashMap::new()),
+            responses: RefCell::new(HashMap::new()),
+            sessions: RefCell::new(HashMap::new()),
+            write_queue: RefCell::new(VecDeque::new()),
+            keep_alive_timeout,
+        }
+    }
+}
+
+/// An HTTP connection of a client.
+///
+/// A connection serves requests one at a time: we parse the next request
+/// only after the response to the previous one has been sent, so responses to
+/// pipelined requests are sent in the order of the requests.
+struct HttpConnection {
+    sock: Rc<Socket>,
+    /// Received data that does not form a complete request yet.
+    recv_buf: BytesMut,
+    parser: http::RequestParser,
+    /// Whether the connection is kept open after the response to the current
+    /// request.
+    keep_alive: bool,
+    /// Whether a request is being served, in which case the connection is
+    /// not idle even though the client sends nothing.
+    serving: bool,
+    /// The data of the response that is currently being sent.
+    send_buf: Bytes,
+    /// The time the connection last received data or sent a response.
+    last_active: Instant,
+}
+
+impl HttpConnection {
+    fn new(sock: Rc<Socket>, now: Instant) -> Self {
+        Self {
+            sock,
+            recv_buf: BytesMut::new(),
+            parser: http::RequestParser::default(),
+            keep_alive: true,
+            serving: false,
+            send_buf: Bytes::new(),
+            last_active: now,
+        }
+    }
+
+    fn is_idle(&self, timeout: Duration, now: Instant) -> bool {
+        !self.serving && now.duration_since(self.last_active) >= timeout
+    }
+}
+
+/// A response that is streamed to the client one chunk at a time with
+/// chunked transfer encoding.
+///
+/// We produce the next chunk only when the previous one has been sent, so a
+/// slow client does not make us buffer the whole result.
+struct StreamedResponse {
+    body: ResponseBody,
+    /// The data of the chunk that is currently being sent.
+    buf: Bytes,
+    /// Whether `buf` is the last chunk of the response.
+    done: bool,
+}
+
+/// The producer of the body of a streamed response.
+enum ResponseBody {
+    Cursor(Cursor, proto::Encoding),
+    Pipeline(Pipeline),
+}
+
+impl ResponseBody {
+    /// Write the body to a buffer until the buffer holds at least `limit`
+    /// bytes, returning `true` if the body is complete.
+    fn write(&mut self, buf: &mut BytesMut, limit: usize) -> crate::Result<bool> {
+        match self {
+            ResponseBody::Cursor(cursor, encoding) => {
+                while buf.len() < limit {
+                    match cursor.next_entry() {
+                        Some(entry) => proto::encode_cursor_msg(&entry, *encoding, buf)?,
+                        None => return Ok(true),
+                    }
+                }
+                Ok(false)
+            }
+            ResponseBody::Pipeline(pipeline) => pipeline.write_body(buf, limit),
+        }
     }
 }
 
+/// A request that waits for another stream to finish its write transaction.
+///
+/// We stop receiving from the socket while the request waits, so that the
+/// event loop keeps serving other clients.
+struct QueuedRequest {
+    sock: Rc<Socket>,
+    req: ClientRequest,
+    write: PendingWrite,
+    /// The time after which the request fails with `SQLITE_BUSY`.
+    deadline: Instant,
+}
+
 pub fn serve<T>(io: &mut IO<T>, sock: Rc<Socket>, addr: SockAddr) {
     io.accept(sock, addr, on_accept);
+    io.timeout(expire_streams_interval(io), on_expire_streams);
+    let keep_alive_timeout = io.context().keep_alive_timeout;
+    io.timeout(
+        timer_interval(keep_alive_timeout),
+        on_close_idle_connections,
+    );
+}
+
+fn on_expire_streams<T>(io: &mut IO<T>) {
+    io.context().manager.expire_streams();
+    process_write_queue(io);
+    io.timeout(expire_streams_interval(io), on_expire_streams);
+}
+
+/// Returns the interval between checking streams for expiry, which is short
+/// enough that streams expire, and queued requests fail, soon after their
+/// timeouts.
+fn expire_streams_interval<T>(io: &IO<T>) -> Duration {
+    let timeouts = io.context().manager.timeouts();
+    timer_interval(timeouts.idle.min(timeouts.transaction).min(timeouts.busy))
+}
+
+/// Returns the interval between checks for a timeout, which is short enough
+/// that the check happens soon after the timeout elapses.
+fn timer_interval(timeout: Duration) -> Duration {
+    (timeout / 4).min(MAX_TIMER_INTERVAL)
+}
+
+fn on_close_idle_connections<T>(io: &mut IO<T>) {
+    let timeout = io.context().keep_alive_timeout;
+    let now = io.now();
+    let idle: Vec<Rc<Socket>> = io
+        .context()
+        .connections
+        .borrow()
+        .values()
+        .filter(|conn| conn.is_idle(timeout, now))
+        .map(|conn| conn.sock.clone())
+        .collect();
+    for sock in idle {
+        log::trace!("Closing idle connection");
+        // Shutting down the socket completes the pending receive with no
+        // data, which closes the connection.
+        io.shutdown(sock);
+    }
+    io.timeout(timer_interval(timeout), on_close_idle_connections);
 }
 
 fn on_accept<T>(
@@ -36,66 +224,547 @@ fn on_accept<T>(
     log::trace!("Server accepted connection from {:?}", sock_addr);
     conn_sock.set_nodelay(true).unwrap();
     io.accept(server_sock, server_addr, on_accept);
+    let conn = HttpConnection::new(conn_sock.clone(), io.now());
+    io.context()
+        .connections
+        .borrow_mut()
+        .insert(conn_sock.as_raw_fd(), conn);
     io.recv(conn_sock, on_recv);
 }
 
-fn execute_request<T>(io: &mut IO<T>, buf: &[u8]) -> Result<Bytes> {
-    let ctx = io.context();
-    let req = parse_request(&buf)?;
-    let resp = executor::execute_client_req(ctx.manager.clone(), req)?;
-    Ok(proto::format_msg(&resp)?)
-}
-
 fn on_recv<T>(io: &mut IO<T>, sock: Rc<Socket>, buf: &[u8], n: usize) {
     if n == 0 {
         log::trace!("Client closed connection");
-        io.close(sock);
+        return close_connection(io, sock);
+    }
+    let sockfd = sock.as_raw_fd();
+    let mut connections = io.context().connections.borrow_mut();
+    let conn = connections.get_mut(&sockfd).unwrap();
+    conn.recv_buf.extend_from_slice(&buf[..n]);
+    conn.last_active = io.now();
+    drop(connections);
+    serve_next_request(io, sock);
+}
+
+/// Serve the next request in the receive buffer of a connection, or receive
+/// more data if the buffer does not hold a complete request.
+fn serve_next_request<T>(io: &mut IO<T>, sock: Rc<Socket>) {
+    let sockfd = sock.as_raw_fd();
+    let mut connections = io.context().connections.borrow_mut();
+    let conn = connections.get_mut(&sockfd).unwrap();
+    // A request may arrive in many pieces, so we wait until we have received
+    // the whole request before parsing it.
+    let raw_req = match conn.parser.parse(&mut conn.recv_buf) {
+        Ok(Some(raw_req)) => raw_req,
+        Ok(None) => {
+            drop(connections);
+            io.recv(sock, on_recv);
+            return;
+        }
+        Err(err) => {
+            conn.keep_alive = false;
+            conn.serving = true;
+            drop(connections);
+            return send_framing_error(io, sock, err);
+        }
+    };
+    conn.keep_alive = raw_req.keep_alive;
+    conn.serving = true;
+    drop(connections);
+    let req = match parse_request(&raw_req) {
+        Ok(req) => req,
+        Err(x) => return send_error(io, sock, x),
+    };
+    handle_request(io, sock, req);
+}
+
+/// Send a response to the current request of a connection.
+fn send_response<T>(
+    io: &mut IO<T>,
+    sock: Rc<Socket>,
+    body: Bytes,
+    status: http::StatusCode,
+    content_type: &str,
+) {
+    let resp = http::format_response(body, status, content_type, keep_alive(io, &sock));
+    send_formatted_response(io, sock, resp);
+}
+
+/// Send a response whose body has been written to a buffer from
+/// `http::response_buf`, which saves copying the body.
+fn send_response_buf<T>(
+    io: &mut IO<T>,
+    sock: Rc<Socket>,
+    buf: BytesMut,
+    status: http::StatusCode,
+    content_type: &str,
+) {
+    let resp = http::format_response_in_place(buf, status, content_type, keep_alive(io, &sock));
+    send_formatted_response(io, sock, resp);
+}
+
+fn send_formatted_response<T>(io: &mut IO<T>, sock: Rc<Socket>, resp: Bytes) {
+    let sockfd = sock.as_raw_fd();
+    let mut connections = io.context().connections.borrow_mut();
+    let conn = connections.get_mut(&sockfd).unwrap();
+    conn.send_buf = resp.clone();
+    drop(connections);
+    let n = resp.len();
+    io.send(sock, resp, n, on_send);
+}
+
+fn on_send<T>(io: &mut IO<T>, sock: Rc<Socket>, n: usize) {
+    if n == 0 {
+        log::trace!("Failed to send response, closing connection");
+        return close_connection(io, sock);
+    }
+    let sockfd = sock.as_raw_fd();
+    let mut connections = io.context().connections.borrow_mut();
+    let conn = connections.get_mut(&sockfd).unwrap();
+    if n < conn.send_buf.len() {
+        // Partial write, send the rest of the response.
+        conn.send_buf = conn.send_buf.slice(n..);
+        let buf = conn.send_buf.clone();
+        drop(connections);
+        let n = buf.len();
+        io.send(sock, buf, n, on_send);
         return;
     }
-    // When receiving POST request with chunked encoding,
-    // the end marker consist of those bytes - [13, 10, 48, 13, 10, 13, 10]
-    // and we don't recv them from the socket in one go.
-    if n == 7 && is_complete_chunked_encoding_mark(buf) {
+    conn.send_buf = Bytes::new();
+    drop(connections);
+    finish_response(io, sock);
+}
+
+/// Finish serving the current request of a connection after its response
+/// has been sent, and serve the next one unless the connection is closed.
+fn finish_response<T>(io: &mut IO<T>, sock: Rc<Socket>) {
+    let sockfd = sock.as_raw_fd();
+    let mut connections = io.context().connections.borrow_mut();
+    let conn = connections.get_mut(&sockfd).unwrap();
+    conn.serving = false;
+    conn.last_active = io.now();
+    let keep_alive = conn.keep_alive;
+    drop(connections);
+    if !keep_alive {
+        return close_connection(io, sock);
+    }
+    // The client may have sent the next request before this response.
+    serve_next_request(io, sock);
+}
+
+/// Returns `true` if the connection is kept open after the response to the
+/// current request.
+fn keep_alive<T>(io: &IO<T>, sock: &Socket) -> bool {
+    let connections = io.context().connections.borrow();
+    connections
+        .get(&sock.as_raw_fd())
+        .is_some_and(|conn| conn.keep_alive)
+}
+
+fn close_connection<T>(io: &mut IO<T>, sock: Rc<Socket>) {
+    let sockfd = sock.as_raw_fd();
+    io.context().connections.borrow_mut().remove(&sockfd);
+    io.close(sock);
+}
+
+/// Execute a request, or queue it if it must wait for another stream to
+/// finish its write transaction.
+fn handle_request<T>(io: &mut IO<T>, sock: Rc<Socket>, req: ClientRequest) {
+    let manager = io.context().manager.clone();
+    if let Some(write) = req.pending_write(&manager) {
+        log::trace!("Queueing write until the write transaction finishes");
+        let deadline = io.now() + manager.timeouts().busy;
+        io.context()
+            .write_queue
+            .borrow_mut()
+            .push_back(QueuedRequest {
+                sock,
+                req,
+                write,
+                deadline,
+            });
         return;
     }
-    let resp = match execute_request(io, &buf[..n]) {
-        Ok(resp) => http::format_response(resp, http::StatusCode::OK),
-        Err(x) => http::format_response(
-            format!("{}", x).into(),
-            http::StatusCode::BAD_REQUEST,
-        ),
+    dispatch_request(io, sock, req);
+    process_write_queue(io);
+}
+
+/// Execute the queued requests that no longer have to wait, and fail the
+/// ones that have waited for longer than the busy timeout. This includes the
+/// requests that wait in WebSocket sessions.
+pub(crate) fn process_write_queue<T>(io: &mut IO<T>) {
+    session::process_waiting(io);
+    let queue = std::mem::take(&mut *io.context().write_queue.borrow_mut());
+    if queue.is_empty() {
+        return;
+    }
+    let manager = io.context().manager.clone();
+    let now = io.now();
+    for queued in queue {
+        if !queued.write.must_wait(&manager) {
+            dispatch_request(io, queued.sock, queued.req);
+        } else if now >= queued.deadline {
+            send_busy_error(io, queued.sock, queued.req);
+        } else {
+            io.context().write_queue.borrow_mut().push_back(queued);
+        }
+    }
+}
+
+fn dispatch_request<T>(io: &mut IO<T>, sock: Rc<Socket>, req: ClientRequest) {
+    let (resp, content_type) = match req {
+        // An empty response tells the client that we support the version.
+        ClientRequest::VersionProbe => (Bytes::new(), http::TEXT_CONTENT_TYPE),
+        ClientRequest::Pipeline(req, encoding) => {
+            let manager = io.context().manager.clone();
+            let mut pipeline = match Pipeline::open(manager, req, encoding) {
+                Ok(pipeline) => pipeline,
+                Err(x) => return send_hrana_error(io, sock, x, encoding),
+            };
+            // Small responses are sent whole, and only a response that does
+            // not fit in one chunk is streamed.
+            let mut data = http::response_buf();
+            let limit = http::RESPONSE_HEAD_SPACE + RESPONSE_CHUNK_SIZE;
+            return match pipeline.write_body(&mut data, limit) {
+                Ok(true) => {
+                    let status = http::StatusCode::OK;
+                    send_response_buf(io, sock, data, status, encoding.content_type())
+                }
+                Ok(false) => {
+                    let data = data.split_off(http::RESPONSE_HEAD_SPACE);
+                    let body = ResponseBody::Pipeline(pipeline);
+                    send_streamed(io, sock, body, data, encoding.content_type())
+                }
+                Err(x) => send_hrana_error(io, sock, x, encoding),
+            };
+        }
+        ClientRequest::Cursor(req, encoding) => {
+            let manager = io.context().manager.clone();
+            let cursor = match Cursor::open(manager, req) {
+                Ok(cursor) => cursor,
+                Err(x) => return send_hrana_error(io, sock, x, encoding),
+            };
+            let mut data = BytesMut::new();
+            if let Err(x) = proto::encode_cursor_msg(&cursor.resp_body(), encoding, &mut data) {
+                return send_error(io, sock, x.into());
+            }
+            let body = ResponseBody::Cursor(cursor, encoding);
+            return send_streamed(io, sock, body, data, encoding.content_type());
+        }
+        ClientRequest::V1Execute(database, req) => {
+            let manager = io.context().manager.clone();
+            let resp = executor::execute_v1_stmt(manager, &database, req);
+            return send_v1_response(io, sock, resp);
+        }
+        ClientRequest::V1Batch(database, req) => {
+            let manager = io.context().manager.clone();
+            let resp = executor::execute_v1_batch(manager, &database, req);
+            return send_v1_response(io, sock, resp);
+        }
+        ClientRequest::WebSocket(upgrade) => {
+            // Data that the client sent after the upgrade request consists of
+            // WebSocket frames.
+            let sockfd = sock.as_raw_fd();
+            let conn = io.context().connections.borrow_mut().remove(&sockfd);
+            let recv_buf = conn.map(|conn| conn.recv_buf).unwrap_or_default();
+            let manager = io.context().manager.clone();
+            let session = Session::new(manager, upgrade.database, upgrade.version);
+            return session::serve(io, sock, session, &upgrade.key, recv_buf);
+        }
     };
+    send_response(io, sock, resp, http::StatusCode::OK, content_type);
+}
 
-    let n = resp.len();
-    io.send(sock, resp.into(), n, on_send);
+fn send_error<T>(io: &mut IO<T>, sock: Rc<Socket>, err: anyhow::Error) {
+    log::debug!("Failed to execute request: {}", err);
+    let body = format!("{}", err).into();
+    let status = http::StatusCode::BAD_REQUEST;
+    send_response(io, sock, body, status, http::TEXT_CONTENT_TYPE);
 }
 
-fn is_complete_chunked_encoding_mark(buf: &[u8]) -> bool {
-    buf == b"\r\n0\r\n\r\n" 
+/// Send an error in the framing of a request. The connection is closed after
+/// the response, because we cannot tell where the next request starts.
+fn send_framing_error<T>(io: &mut IO<T>, sock: Rc<Socket>, err: http::FramingError) {
+    log::debug!("Closing connection: {}", err.message);
+    let body = err.message.into();
+    send_response(io, sock, body, err.status, http::TEXT_CONTENT_TYPE);
+}
+
+/// Send an error that fails a whole Hrana request, such as an invalid baton,
+/// as a Hrana error in the encoding of the request.
+fn send_hrana_error<T>(
+    io: &mut IO<T>,
+    sock: Rc<Socket>,
+    err: HiisiError,
+    encoding: proto::Encoding,
+) {
+    log::debug!("Failed to execute request: {}", err);
+    let error = executor::to_proto_error(&err);
+    let mut buf = http::response_buf();
+    match proto::write_msg(&error, encoding, &mut buf) {
+        Ok(()) => send_response_buf(io, sock, buf, error_status(&err), encoding.content_type()),
+        Err(x) => send_error(io, sock, x.into()),
+    }
+}
+
+/// Fail a request that has waited for longer than the busy timeout for
+/// another stream to finish its write transaction.
+fn send_busy_error<T>(io: &mut IO<T>, sock: Rc<Socket>, req: ClientRequest) {
+    let err = database::busy_error();
+    match req {
+        ClientRequest::Pipeline(_, encoding) | ClientRequest::Cursor(_, encoding) => {
+            send_hrana_error(io, sock, err, encoding)
+        }
+        _ => send_v1_response::<T, ()>(io, sock, Err(err)),
+    }
+}
+
+/// Returns the HTTP status of a response to a failed request. Errors that the
+/// client can retry are reported as service unavailable.
+fn error_status(err: &HiisiError) -> http::StatusCode {
+    match err {
+        HiisiError::ResourcesExhausted(_) => http::StatusCode::SERVICE_UNAVAILABLE,
+        HiisiError::SqliteError(err) if err.is_busy() => http::StatusCode::SERVICE_UNAVAILABLE,
+        _ => http::StatusCode::BAD_REQUEST,
+    }
+}
+
+/// Send the response of a stateless Hrana 1 request. Unlike in later
+/// versions, errors are reported with an HTTP error status and a JSON body.
+fn send_v1_response<T, R: Serialize>(io: &mut IO<T>, sock: Rc<Socket>, resp: crate::Result<R>) {
+    let content_type = proto::Encoding::Json.content_type();
+    let mut buf = http::response_buf();
+    match resp.and_then(|resp| proto::write_json_msg(&resp, &mut buf)) {
+        Ok(()) => send_response_buf(io, sock, buf, http::StatusCode::OK, content_type),
+        Err(err) => {
+            log::debug!("Failed to execute request: {}", err);
+            let body = proto::v1::ErrorRespBody {
+                message: executor::to_proto_error(&err).message,
+            };
+            // Serializing a string to JSON does not fail.
+            let mut buf = http::response_buf();
+            proto::write_json_msg(&body, &mut buf).unwrap();
+            send_response_buf(io, sock, buf, error_status(&err), content_type);
+        }
+    }
+}
+
+/// Send a streamed response whose body starts with the given data.
+fn send_streamed<T>(
+    io: &mut IO<T>,
+    sock: Rc<Socket>,
+    body: ResponseBody,
+    data: BytesMut,
+    content_type: &str,
+) {
+    let mut buf = http::format_chunked_response_head(
+        http::StatusCode::OK,
+        content_type,
+        keep_alive(io, &sock),
+    );
+    http::format_chunk(&mut buf, &data);
+    let buf: Bytes = buf.into();
+    let resp = StreamedResponse {
+        body,
+        buf: buf.clone(),
+        done: false,
+    };
+    io.context()
+        .responses
+        .borrow_mut()
+        .insert(sock.as_raw_fd(), resp);
+    let n = buf.len();
+    io.send(sock, buf, n, on_send_streamed);
+}
+
+fn on_send_streamed<T>(io: &mut IO<T>, sock: Rc<Socket>, n: usize) {
+    let sockfd = sock.as_raw_fd();
+    if n == 0 {
+        log::trace!("Failed to send response, closing connection");
+        // Dropping the body of the response may close its stream, which may
+        // have held the write transaction.
+        io.context().responses.borrow_mut().remove(&sockfd);
+        close_connection(io, sock);
+        process_write_queue(io);
+        return;
+    }
+    let mut responses = io.context().responses.borrow_mut();
+    let resp = responses.get_mut(&sockfd).unwrap();
+    if n < resp.buf.len() {
+        // Partial write, send the rest of the chunk.
+        resp.buf = resp.buf.slice(n..);
+    } else if resp.done {
+        responses.remove(&sockfd);
+        drop(responses);
+        // The body may have finished a write transaction.
+        process_write_queue(io);
+        return finish_response(io, sock);
+    } else {
+        match next_chunk(&mut resp.body) {
+            Ok((buf, done)) => {
+                resp.buf = buf;
+                resp.done = done;
+            }
+            Err(x) => {
+                // The response head has already been sent, so the only way
+                // to report the error is to abort the response.
+                log::error!("Failed to produce response body: {}", x);
+                responses.remove(&sockfd);
+                drop(responses);
+                close_connection(io, sock);
+                // Aborting the body closed its stream, which may have held
+                // the write transaction.
+                process_write_queue(io);
+                return;
+            }
+        }
+    }
+    let buf = resp.buf.clone();
+    drop(responses);
+    let n = buf.len();
+    io.send(sock, buf, n, on_send_streamed);
+}
+
+/// Format the next chunk of a streamed response, returning the chunk and
+/// whether it is the last one.
+fn next_chunk(body: &mut ResponseBody) -> crate::Result<(Bytes, bool)> {
+    let mut data = BytesMut::new();
+    let done = body.write(&mut data, RESPONSE_CHUNK_SIZE)?;
+    let mut buf = BytesMut::new();
+    http::format_chunk(&mut buf, &data);
+    if done {
+        http::format_last_chunk(&mut buf);
+    }
+    Ok((buf.into(), done))
 }
 
 enum Route {
-    // The `/v2/pipeline` route.
-    Pipeline,
+    // The `GET /v1`, `GET /v2` and `GET /v3` routes.
+    VersionProbe,
+    // The `POST /v1/execute` route.
+    V1Execute,
+    // The `POST /v1/batch` route.
+    V1Batch,
+    // The `/v2/pipeline` and `/v3/pipeline` routes.
+    Pipeline(proto::Version),
+    // The `/v2/cursor` and `/v3/cursor` routes.
+    Cursor(proto::Version),
 }
 
-fn parse_request(buf: &[u8]) -> Result<Request> {
-    let mut headers = [httparse::EMPTY_HEADER; 64];
+enum ClientRequest {
+    VersionProbe,
+    Pipeline(Request, proto::Encoding),
+    Cursor(CursorRequest, proto::Encoding),
+    V1Execute(String, proto::v1::ExecuteReqBody),
+    V1Batch(String, proto::v1::BatchReqBody),
+    WebSocket(Upgrade),
+}
+
+impl ClientRequest {
+    /// Returns the write that the request waits with if it may write to a
+    /// database whose write transaction is held by another stream.
+    fn pending_write(&self, manager: &ResourceManager) -> Option<PendingWrite> {
+        match self {
+            ClientRequest::Pipeline(req, _) => req.pending_write(manager),
+            ClientRequest::Cursor(req, _) => req.pending_write(manager),
+            ClientRequest::V1Execute(database, req) => {
+                let sqls = std::iter::once((&req.stmt.sql, req.stmt.sql_id));
+                executor::pending_write(manager, database, None, sqls)
+            }
+            ClientRequest::V1Batch(database, req) => {
+                let sqls = executor::batch_sqls(&req.batch);
+                executor::pending_write(manager, database, None, sqls)
+            }
+            ClientRequest::VersionProbe | ClientRequest::WebSocket(_) => None,
+        }
+    }
+}
+
+/// A request to upgrade the connection to WebSocket.
+struct Upgrade {
+    database: String,
+    version: proto::Version,
+    /// The `Sec-WebSocket-Key` of the request.
+    key: Vec<u8>,
+}
+
+fn parse_request(raw_req: &http::RawRequest) -> Result<ClientRequest> {
+    let mut headers = [httparse::EMPTY_HEADER; http::MAX_HEADERS];
     let mut req = httparse::Request::new(&mut headers);
-    let body_off = req.parse(buf)?.unwrap();
-    let database = parse_database(&mut req)?;
-    match parse_route(req.path.unwrap()) {
-        Some(Route::Pipeline) => {
-            let req = proto::parse_client_req(&buf[body_off..])?;
-            Ok(Request {
-                database: database.to_owned(),
+    req.parse(&raw_req.head)?;
+    let body = &raw_req.body[..];
+    if is_websocket_upgrade(&req) {
+        return parse_upgrade(&mut req).map(ClientRequest::WebSocket);
+    }
+    let route = parse_route(req.method.unwrap(), req.path.unwrap());
+    match route {
+        Some(Route::VersionProbe) => Ok(ClientRequest::VersionProbe),
+        Some(Route::V1Execute) => {
+            let database = parse_database(&mut req)?;
+            let req = proto::v1::parse_execute_req(body)?;
+            Ok(ClientRequest::V1Execute(database, req))
+        }
+        Some(Route::V1Batch) => {
+            let database = parse_database(&mut req)?;
+            let req = proto::v1::parse_batch_req(body)?;
+            Ok(ClientRequest::V1Batch(database, req))
+        }
+        Some(Route::Pipeline(version)) => {
+            let database = parse_database(&mut req)?;
+            let encoding = parse_encoding(&req, version)?;
+            let req = proto::parse_client_req(body, encoding)?;
+            let req = Request {
+                database,
+                version,
                 req,
-            })
+            };
+            Ok(ClientRequest::Pipeline(req, encoding))
+        }
+        Some(Route::Cursor(version)) => {
+            let database = parse_database(&mut req)?;
+            let encoding = parse_encoding(&req, version)?;
+            let req = proto::parse_cursor_req(body, encoding)?;
+            let req = CursorRequest {
+                database,
+                version,
+                req,
+            };
+            Ok(ClientRequest::Cursor(req, encoding))
         }
         None => Err(HiisiError::ProtocolError("Invalid path".to_owned()).into()),
     }
 }
 
+fn is_websocket_upgrade(req: &httparse::Request) -> bool {
+    let upgrade = http::find_header(req, "Upgrade").unwrap_or_default();
+    req.method == Some("GET") && upgrade.eq_ignore_ascii_case(b"websocket")
+}
+
+fn parse_upgrade(req: &mut httparse::Request) -> Result<Upgrade> {
+    let database = parse_database(req)?;
+    let key = http::find_header(req, "Sec-WebSocket-Key")
+        .ok_or_else(|| HiisiError::ProtocolError("Missing Sec-WebSocket-Key".to_owned()))?;
+    // The client lists the subprotocols it supports, and we pick the newest
+    // version of Hrana among them.
+    let protocols =
+        std::str::from_utf8(http::find_header(req, "Sec-WebSocket-Protocol").unwrap_or_default())?;
+    let protocols: Vec<&str> = protocols
+        .split(',')
+        .map(|protocol| protocol.trim())
+        .collect();
+    let version = [proto::Version::Hrana3, proto::Version::Hrana2]
+        .into_iter()
+        .find(|version| protocols.contains(&session::subprotocol(*version)))
+        .ok_or_else(|| {
+            HiisiError::ProtocolError("No supported WebSocket subprotocol".to_owned())
+        })?;
+    Ok(Upgrade {
+        database,
+        version,
+        key: key.to_vec(),
+    })
+}
+
 const DEFAULT_DATABASE: &'static str = "default";
 
 fn parse_database(req: &mut httparse::Request) -> Result<String> {
@@ -119,13 +788,350 @@ fn parse_database(req: &mut httparse::Request) -> Result<String> {
     }
 }
 
-fn parse_route(path: &str) -> Option<Route> {
-    match path {
-        "/v2/pipeline" => Some(Route::Pipeline),
+/// Select the encoding of the request and response by the `Content-Type` of
+/// the request. Only Hrana 3 supports the Protobuf encoding.
+fn parse_encoding(req: &httparse::Request, version: proto::Version) -> Result<proto::Encoding> {
+    let mut content_type: Option<&str> = None;
+    for header in req.headers.iter() {
+        if header.name.eq_ignore_ascii_case("Content-Type") {
+            content_type = Some(std::str::from_utf8(header.value)?);
+            break;
+        }
+    }
+    let encoding = proto::Encoding::from_content_type(content_type);
+    if encoding == proto::Encoding::Protobuf && version < proto::Version::Hrana3 {
+        return Err(HiisiError::ProtocolError(format!(
+            "Protobuf encoding is not supported in {}",
+            version
+        ))
+        .into());
+    }
+    Ok(encoding)
+}
+
+fn parse_route(method: &str, path: &str) -> Option<Route> {
+    match (method, path) {
+        ("GET", "/v1") | ("GET", "/v2") | ("GET", "/v3") => Some(Route::VersionProbe),
+        ("POST", "/v1/execute") => Some(Route::V1Execute),
+        ("POST", "/v1/batch") => Some(Route::V1Batch),
+        (_, "/v2/pipeline") => Some(Route::Pipeline(proto::Version::Hrana2)),
+        (_, "/v2/cursor") => Some(Route::Cursor(proto::Version::Hrana2)),
+        (_, "/v3/pipeline") => Some(Route::Pipeline(proto::Version::Hrana3)),
+        (_, "/v3/cursor") => Some(Route::Cursor(proto::Version::Hrana3)),
         _ => None,
     }
 }
 
-fn on_send<T>(io: &mut IO<T>, sock: Rc<Socket>, _n: usize) {
-    io.recv(sock, on_recv)
+#[cfg(all(test, feature = "simulation"))]
+mod test {
+    use super::*;
+    use crate::io::Clock;
+    use crate::manager::StreamTimeouts;
+    use crate::test_util::{temp_manager_with_clock, TempManager};
+    use socket2::{Domain, Type};
+
+    const SERVER_ADDR: &str = "127.0.0.1:8080";
+
+    const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// The data that a client of a test sends and receives.
+    #[derive(Default)]
+    struct Client {
+        /// The part of the data being sent that has not been sent yet.
+        send_buf: Bytes,
+        received: Vec<u8>,
+        /// Whether the server has closed the connection.
+        closed: bool,
+    }
+
+    /// The clients of a test, keyed by their socket.
+    type Clients = RefCell<HashMap<i32, Client>>;
+
+    type TestIO = IO<Clients>;
+
+    /// A server on the simulated network, which clients connect to.
+    struct Test {
+        io: TestIO,
+        _manager: TempManager,
+    }
+
+    impl Test {
+        fn new() -> Self {
+            let clock = Clock::default();
+            let manager = temp_manager_with_clock(StreamTimeouts::default(), clock.clone());
+            let ctx = Context::with_keep_alive_timeout(
+                (*manager).clone(),
+                RefCell::new(HashMap::new()),
+                KEEP_ALIVE_TIMEOUT,
+            );
+            let mut io = IO::with_clock(ctx, clock);
+            let server_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+            serve(&mut io, server_sock, server_addr());
+            Self {
+                io,
+                _manager: manager,
+            }
+        }
+
+        /// Connect a new client to the server.
+        fn connect(&mut self) -> Rc<Socket> {
+            let sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+            let clients = &self.io.context().user_data;
+            clients
+                .borrow_mut()
+                .insert(sock.as_raw_fd(), Client::default());
+            self.io
+                .connect(sock.clone(), server_addr(), on_client_connect);
+            sock
+        }
+
+        fn send(&mut self, sock: &Rc<Socket>, data: &str) {
+            let buf = Bytes::copy_from_slice(data.as_bytes());
+            client(&self.io, sock).send_buf = buf.clone();
+            let n = buf.len();
+            self.io.send(sock.clone(), buf, n, on_client_send);
+        }
+
+        /// Run the simulation until nothing is left to do.
+        fn run(&mut self) {
+            for _ in 0..10_000 {
+                self.io.run_once();
+            }
+        }
+
+        /// Advance the clock and run the simulation.
+        fn advance(&mut self, duration: Duration) {
+            self.io.clock().advance(duration);
+            self.run();
+        }
+
+        fn responses(&self, sock: &Socket) -> Vec<(u16, serde_json::Value)> {
+            parse_responses(&client(&self.io, sock).received)
+        }
+
+        fn closed(&self, sock: &Socket) -> bool {
+            client(&self.io, sock).closed
+        }
+    }
+
+    fn server_addr() -> SockAddr {
+        let addr: std::net::SocketAddr = SERVER_ADDR.parse().unwrap();
+        addr.into()
+    }
+
+    fn client<'a>(io: &'a TestIO, sock: &Socket) -> std::cell::RefMut<'a, Client> {
+        std::cell::RefMut::map(io.context().user_data.borrow_mut(), |clients| {
+            clients.get_mut(&sock.as_raw_fd()).unwrap()
+        })
+    }
+
+    fn on_client_connect(io: &mut TestIO, sock: Rc<Socket>, _addr: SockAddr) {
+        io.recv(sock, on_client_recv);
+    }
+
+    fn on_client_send(io: &mut TestIO, sock: Rc<Socket>, n: usize) {
+        let mut client = client(io, &sock);
+        client.send_buf = client.send_buf.slice(n..);
+        if client.send_buf.is_empty() {
+            return;
+        }
+        let buf = client.send_buf.clone();
+        drop(client);
+        let n = buf.len();
+        io.send(sock, buf, n, on_client_send);
+    }
+
+    fn on_client_recv(io: &mut TestIO, sock: Rc<Socket>, buf: &[u8], n: usize) {
+        let mut client = client(io, &sock);
+        if n == 0 {
+            client.closed = true;
+            return;
+        }
+        client.received.extend_from_slice(&buf[..n]);
+        drop(client);
+        io.recv(sock, on_client_recv);
+    }
+
+    fn pipeline_request(version: &str, headers: &str, sql: &str) -> String {
+        let body = serde_json::json!({
+            "requests": [{"type": "execute", "stmt": {"sql": sql}}],
+        });
+        http_request(version, headers, &body)
+    }
+
+    /// Returns a request that executes SQL on the stream of a baton.
+    fn baton_request(baton: &serde_json::Value, sql: &str) -> String {
+        let body = serde_json::json!({
+            "baton": baton,
+            "requests": [{"type": "execute", "stmt": {"sql": sql}}],
+        });
+        http_request("1.1", "", &body)
+    }
+
+    fn http_request(version: &str, headers: &str, body: &serde_json::Value) -> String {
+        let body = body.to_string();
+        format!(
+            "POST /v2/pipeline HTTP/{}\r\nHost: test.localhost\r\n{}Content-Length: {}\r\n\r\n{}",
+            version,
+            headers,
+            body.len(),
+            body
+        )
+    }
+
+    /// Parse the responses in the data that the client received, returning
+    /// their status codes and bodies.
+    fn parse_responses(mut data: &[u8]) -> Vec<(u16, serde_json::Value)> {
+        let mut responses = Vec::new();
+        while !data.is_empty() {
+            let mut headers = [httparse::EMPTY_HEADER; 16];
+            let mut resp = httparse::Response::new(&mut headers);
+            let head_len = resp.parse(data).unwrap().unwrap();
+            let content_length = resp
+                .headers
+                .iter()
+                .find(|header| header.name.eq_ignore_ascii_case("Content-Length"))
+                .map(|header| std::str::from_utf8(header.value).unwrap().parse().unwrap());
+            let (body, len) = match content_length {
+                Some(len) => (data[head_len..head_len + len].to_vec(), len),
+                None => decode_chunked(&data[head_len..]),
+            };
+            responses.push((resp.code.unwrap(), serde_json::from_slice(&body).unwrap()));
+            data = &data[head_len + len..];
+        }
+        responses
+    }
+
+    /// Decode a chunked body, returning the body and the length of its
+    /// encoding.
+    fn decode_chunked(data: &[u8]) -> (Vec<u8>, usize) {
+        let mut body = Vec::new();
+        let mut pos = 0;
+        loop {
+            let line_len = data[pos..].windows(2).position(|w| w == b"\r\n").unwrap();
+            let size = std::str::from_utf8(&data[pos..pos + line_len]).unwrap();
+            let size = usize::from_str_radix(size, 16).unwrap();
+            pos += line_len + 2;
+            if size == 0 {
+                return (body, pos + 2);
+            }
+            body.extend_from_slice(&data[pos..pos + size]);
+            pos += size + 2;
+        }
+    }
+
+    fn rows(body: &serde_json::Value) -> Vec<String> {
+        body["results"][0]["response"]["result"]["rows"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|row| row[0]["value"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    fn result_type(body: &serde_json::Value) -> &str {
+        body["results"][0]["type"].as_str().unwrap()
+    }
+
+    #[test]
+    fn answer_pipelined_requests_in_order() {
+        let mut test = Test::new();
+        let sock = test.connect();
+        let first = pipeline_request("1.1", "", "SELECT 1");
+        let second = pipeline_request("1.1", "", "SELECT 2");
+        test.send(&sock, &(first + &second));
+        test.run();
+        let responses = test.responses(&sock);
+        assert_eq!(responses.len(), 2);
+        assert_eq!(rows(&responses[0].1), vec!["1"]);
+        assert_eq!(rows(&responses[1].1), vec!["2"]);
+        assert!(!test.closed(&sock));
+    }
+
+    #[test]
+    fn close_connections_without_keep_alive() {
+        for (version, headers, closed) in [
+            ("1.1", "", false),
+            ("1.1", "Connection: close\r\n", true),
+            ("1.0", "", true),
+            ("1.0", "Connection: keep-alive\r\n", false),
+        ] {
+            let mut test = Test::new();
+            let sock = test.connect();
+            test.send(&sock, &pipeline_request(version, headers, "SELECT 1"));
+            test.run();
+            assert_eq!(test.responses(&sock).len(), 1);
+            assert_eq!(test.closed(&sock), closed, "HTTP/{} {:?}", version, headers);
+        }
+    }
+
+    #[test]
+    fn close_idle_connections() {
+        let mut test = Test::new();
+        let sock = test.connect();
+        test.send(&sock, &pipeline_request("1.1", "", "SELECT 1"));
+        test.run();
+        assert_eq!(test.responses(&sock).len(), 1);
+        test.advance(KEEP_ALIVE_TIMEOUT - Duration::from_secs(1));
+        assert!(!test.closed(&sock));
+        // The connection is closed at the next check after the timeout.
+        test.advance(Duration::from_secs(1) + MAX_TIMER_INTERVAL);
+        assert!(test.closed(&sock));
+    }
+
+    #[test]
+    fn send_responses_in_pieces() {
+        let mut test = Test::new();
+        let sock = test.connect();
+        // A small response is sent whole.
+        test.io.set_max_send_size(7);
+        test.send(&sock, &pipeline_request("1.1", "", "SELECT 1"));
+        test.run();
+        // A response large enough to be streamed in chunks.
+        test.io.set_max_send_size(1000);
+        test.send(
+            &sock,
+            &pipeline_request(
+                "1.1",
+                "",
+                "WITH RECURSIVE s(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM s WHERE x < 2000) \
+                 SELECT x FROM s",
+            ),
+        );
+        test.run();
+        let responses = test.responses(&sock);
+        assert_eq!(responses.len(), 2);
+        assert_eq!(rows(&responses[0].1), vec!["1"]);
+        let expected: Vec<String> = (1..=2000).map(|x| x.to_string()).collect();
+        assert_eq!(rows(&responses[1].1), expected);
+    }
+
+    #[test]
+    fn queue_write_transactions_without_blocking() {
+        let mut test = Test::new();
+        let writer = test.connect();
+        test.send(&writer, &pipeline_request("1.1", "", "BEGIN IMMEDIATE"));
+        test.run();
+        let baton = test.responses(&writer)[0].1["baton"].clone();
+
+        let queued = test.connect();
+        test.send(&queued, &pipeline_request("1.1", "", "BEGIN IMMEDIATE"));
+        let start = Instant::now();
+        test.run();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(test.responses(&queued).is_empty());
+        // Other clients are served while the transaction waits.
+        let reader = test.connect();
+        test.send(&reader, &pipeline_request("1.1", "", "SELECT 1"));
+        test.run();
+        assert_eq!(rows(&test.responses(&reader)[0].1), vec!["1"]);
+        assert!(test.responses(&queued).is_empty());
+
+        test.send(&writer, &baton_request(&baton, "COMMIT"));
+        test.run();
+        assert_eq!(result_type(&test.responses(&writer)[1].1), "ok");
+        let responses = test.responses(&queued);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(result_type(&responses[0].1), "ok");
+    }
 }
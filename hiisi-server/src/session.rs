@@ -0,0 +1,774 @@
+//! Hrana sessions over WebSocket.
+//!
+//! A session is the Hrana protocol state of a WebSocket connection. The
+//! client opens streams in the session explicitly, and each stream has its
+//! own SQLite connection. Unlike HTTP streams, the streams of a session are
+//! not identified by batons and do not outlive the WebSocket connection.
+
+use bytes::{Bytes, BytesMut};
+use socket2::Socket;
+
+use std::collections::HashMap;
+use std::os::fd::AsRawFd;
+use std::rc::Rc;
+use std::time::Instant;
+
+use crate::baton::StreamId;
+use crate::cursor::Cursor;
+use crate::database;
+use crate::executor::{self, PendingWrite};
+use crate::manager::ResourceManager;
+use crate::proto::{self, ws};
+use crate::server::{self, IO};
+use crate::stream::{SqlStore, Stream};
+use crate::websocket::{self, Opcode};
+use crate::{HiisiError, Result};
+
+// Maximum number of streams a session can have open.
+const MAX_STREAMS: usize = 100;
+
+// Maximum number of cursors a session can have open.
+const MAX_CURSORS: usize = 100;
+
+// Maximum number of entries returned by one `fetch_cursor` request.
+const MAX_FETCH_COUNT: u32 = 1024;
+
+// Maximum size of a message received from the client in bytes.
+const MAX_MESSAGE_SIZE: usize = 10 * 1024 * 1024;
+
+pub struct Session {
+    manager: Rc<ResourceManager>,
+    database: String,
+    version: proto::Version,
+    /// Whether the client has sent the `hello` message.
+    hello_received: bool,
+    /// The streams of the session, with the ids that the manager tracks
+    /// their write transactions by.
+    streams: HashMap<i32, (StreamId, Rc<Stream>)>,
+    cursors: HashMap<i32, Cursor>,
+    /// SQL texts stored in the session, which are shared by all streams.
+    sqls: Rc<SqlStore>,
+}
+
+impl Session {
+    pub fn new(manager: Rc<ResourceManager>, database: String, version: proto::Version) -> Self {
+        Self {
+            manager,
+            database,
+            version,
+            hello_received: false,
+            streams: HashMap::new(),
+            cursors: HashMap::new(),
+            sqls: Rc::new(SqlStore::default()),
+        }
+    }
+
+    /// Handle a message from the client and return the message to reply
+    /// with. An error means that the client violated the protocol and the
+    /// connection must be closed.
+    pub fn handle_msg(&mut self, msg: ws::ClientMsg) -> Result<ws::ServerMsg> {
+        match msg {
+            ws::ClientMsg::None => Err(HiisiError::ProtocolError(
+                "Unknown client message".to_string(),
+            )),
+            // We don't authenticate clients, so the JWT is ignored. The
+            // client may send `hello` again to refresh its JWT.
+            ws::ClientMsg::Hello { .. } => {
+                self.hello_received = true;
+                Ok(ws::ServerMsg::HelloOk {})
+            }
+            ws::ClientMsg::Request {
+                request_id,
+                request,
+            } => {
+                if !self.hello_received {
+                    return Err(HiisiError::ProtocolError(
+                        "Received a request before hello".to_string(),
+                    ));
+                }
+                let result = self.handle_request(request);
+                // The request may have started or finished a write
+                // transaction.
+                self.update_writers();
+                match result {
+                    Ok(response) => Ok(ws::ServerMsg::ResponseOk {
+                        request_id,
+                        response,
+                    }),
+                    Err(err) => {
+                        log::debug!("WebSocket request failed: {}", err);
+                        Ok(ws::ServerMsg::ResponseError {
+                            request_id,
+                            error: executor::to_proto_error(&err),
+                        })
+                    }
+                }
+            }
+        }
+    }
+
+    fn handle_request(&mut self, req: ws::Request) -> Result<ws::Response> {
+        let resp = match req {
+            ws::Request::None => {
+                return Err(HiisiError::ProtocolError("Unknown request".to_string()))
+            }
+            ws::Request::OpenStream(req) => {
+                if self.streams.contains_key(&req.stream_id) {
+                    return Err(HiisiError::ProtocolError(format!(
+                        "Stream {} is already open",
+                        req.stream_id
+                    )));
+                }
+                if self.streams.len() >= MAX_STREAMS {
+                    return Err(HiisiError::ProtocolError(format!(
+                        "At most {} streams can be open per connection",
+                        MAX_STREAMS
+                    )));
+                }
+                let conn = self.manager.connect(&self.database)?;
+                let stream = Stream::with_sqls(conn, self.sqls.clone());
+                let stream_id = self.manager.allocate_stream_id();
+                self.streams
+                    .insert(req.stream_id, (stream_id, Rc::new(stream)));
+                ws::Response::OpenStream(ws::OpenStreamResp {})
+            }
+            ws::Request::CloseStream(req) => {
+                if let Some((stream_id, stream)) = self.streams.remove(&req.stream_id) {
+                    // A cursor may still hold the stream, so we roll back
+                    // explicitly instead of relying on the connection being
+                    // closed.
+                    if let Err(err) = stream.rollback() {
+                        log::error!("Failed to roll back closed stream: {}", err);
+                    }
+                    self.manager.release_writer(&self.database, stream_id);
+                }
+                ws::Response::CloseStream(ws::CloseStreamResp {})
+            }
+            ws::Request::Execute(req) => {
+                let stream = self.stream(req.stream_id)?;
+                let result = executor::execute_stmt(&stream, &req.stmt)?;
+                ws::Response::Execute(proto::ExecuteStreamResp { result })
+            }
+            ws::Request::Batch(req) => {
+                executor::check_batch(&req.batch, self.version)?;
+                let stream = self.stream(req.stream_id)?;
+                let result = executor::execute_batch(&stream, &req.batch)?;
+                ws::Response::Batch(proto::BatchStreamResp { result })
+            }
+            ws::Request::OpenCursor(req) => {
+                self.check_version(proto::Version::Hrana3, "open_cursor request")?;
+                executor::check_batch(&req.batch, self.version)?;
+                if self.cursors.contains_key(&req.cursor_id) {
+                    return Err(HiisiError::ProtocolError(format!(
+                        "Cursor {} is already open",
+                        req.cursor_id
+                    )));
+                }
+                if self.cursors.len() >= MAX_CURSORS {
+                    return Err(HiisiError::ProtocolError(format!(
+                        "At most {} cursors can be open per connection",
+                        MAX_CURSORS
+                    )));
+                }
+                let stream = self.stream(req.stream_id)?;
+                let cursor = Cursor::new(stream, req.batch);
+                self.cursors.insert(req.cursor_id, cursor);
+                ws::Response::OpenCursor(ws::OpenCursorResp {})
+            }
+            ws::Request::CloseCursor(req) => {
+                self.check_version(proto::Version::Hrana3, "close_cursor request")?;
+                self.cursors.remove(&req.cursor_id);
+                ws::Response::CloseCursor(ws::CloseCursorResp {})
+            }
+            ws::Request::FetchCursor(req) => {
+                self.check_version(proto::Version::Hrana3, "fetch_cursor request")?;
+                let cursor = self.cursors.get_mut(&req.cursor_id).ok_or_else(|| {
+                    HiisiError::ProtocolError(format!("Cursor {} is not open", req.cursor_id))
+                })?;
+                let max_count = req.max_count.min(MAX_FETCH_COUNT) as usize;
+                let mut entries = Vec::with_capacity(max_count);
+                let mut done = false;
+                while entries.len() < max_count {
+                    match cursor.next_entry() {
+                        Some(entry) => entries.push(entry),
+                        None => {
+                            done = true;
+                            break;
+                        }
+                    }
+                }
+                ws::Response::FetchCursor(ws::FetchCursorResp { entries, done })
+            }
+            ws::Request::Sequence(req) => {
+                let stream = self.stream(req.stream_id)?;
+                let sql = executor::resolve_sql(&stream, &req.sql, req.sql_id)?;
+                executor::execute_sequence(&stream.conn, &sql)?;
+                ws::Response::Sequence(proto::SequenceStreamResp {})
+            }
+            ws::Request::Describe(req) => {
+                let stream = self.stream(req.stream_id)?;
+                let sql = executor::resolve_sql(&stream, &req.sql, req.sql_id)?;
+                let result = executor::describe_stmt(&stream.conn.prepare(&sql)?)?;
+                ws::Response::Describe(proto::DescribeStreamResp { result })
+            }
+            ws::Request::StoreSql(req) => {
+                self.sqls.store_sql(req.sql_id, req.sql)?;
+                ws::Response::StoreSql(proto::StoreSqlStreamResp {})
+            }
+            ws::Request::CloseSql(req) => {
+                self.sqls.close_sql(req.sql_id);
+                ws::Response::CloseSql(proto::CloseSqlStreamResp {})
+            }
+            ws::Request::GetAutocommit(req) => {
+                self.check_version(proto::Version::Hrana3, "get_autocommit request")?;
+                let stream = self.stream(req.stream_id)?;
+                ws::Response::GetAutocommit(proto::GetAutocommitStreamResp {
+                    is_autocommit: stream.is_autocommit(),
+                })
+            }
+        };
+        Ok(resp)
+    }
+
+    fn stream(&self, stream_id: i32) -> Result<Rc<Stream>> {
+        self.streams
+            .get(&stream_id)
+            .map(|(_, stream)| stream.clone())
+            .ok_or_else(|| HiisiError::ProtocolError(format!("Stream {} is not open", stream_id)))
+    }
+
+    fn check_version(&self, required: proto::Version, what: &str) -> Result<()> {
+        executor::check_version(self.version, required, what)
+    }
+
+    /// Returns the write that a request waits with if it must wait for
+    /// another stream to finish its write transaction before it is handled.
+    fn pending_write(&self, req: &ws::Request) -> Option<PendingWrite> {
+        let (stream_id, sqls): (i32, Vec<_>) = match req {
+            ws::Request::Execute(req) => (req.stream_id, vec![(&req.stmt.sql, req.stmt.sql_id)]),
+            ws::Request::Batch(req) => (req.stream_id, executor::batch_sqls(&req.batch).collect()),
+            ws::Request::OpenCursor(req) => {
+                (req.stream_id, executor::batch_sqls(&req.batch).collect())
+            }
+            ws::Request::Sequence(req) => (req.stream_id, vec![(&req.sql, req.sql_id)]),
+            _ => return None,
+        };
+        let (stream_id, stream) = self.streams.get(&stream_id)?;
+        let write = PendingWrite::new(&self.database, Some(*stream_id));
+        (write.must_wait(&self.manager) && executor::any_may_write(stream, sqls.into_iter()))
+            .then_some(write)
+    }
+
+    /// Record which streams of the session hold the write transaction of the
+    /// database.
+    fn update_writers(&self) {
+        for (stream_id, stream) in self.streams.values() {
+            self.manager
+                .update_writer(&self.database, *stream_id, stream);
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        for (stream_id, _) in self.streams.values() {
+            self.manager.release_writer(&self.database, *stream_id);
+        }
+    }
+}
+
+/// Returns the WebSocket subprotocol of a Hrana version.
+pub fn subprotocol(version: proto::Version) -> &'static str {
+    match version {
+        proto::Version::Hrana1 => "hrana1",
+        proto::Version::Hrana2 => "hrana2",
+        proto::Version::Hrana3 => "hrana3",
+    }
+}
+
+/// A WebSocket connection that carries a session.
+pub(crate) struct SessionSocket {
+    session: Session,
+    /// Received data that does not form a complete frame yet.
+    recv_buf: BytesMut,
+    /// The opcode and data of a fragmented message that is being received.
+    message: Option<(Opcode, BytesMut)>,
+    /// The data that is currently being sent.
+    send_buf: Bytes,
+    /// Whether the connection is closed after `send_buf` has been sent.
+    closing: bool,
+    /// A request that waits for another stream to finish its write
+    /// transaction. The frames after it are processed once it is handled.
+    waiting: Option<WaitingRequest>,
+}
+
+/// A request of a session that waits for another stream to finish its write
+/// transaction.
+///
+/// We stop receiving from the socket while the request waits, like for the
+/// requests that wait over HTTP.
+struct WaitingRequest {
+    sock: Rc<Socket>,
+    request_id: i32,
+    request: ws::Request,
+    write: PendingWrite,
+    /// The time after which the request fails with `SQLITE_BUSY`.
+    deadline: Instant,
+}
+
+impl SessionSocket {
+    /// Process the complete frames in the receive buffer, appending the
+    /// frames to send in reply to `out`.
+    fn process_frames(&mut self, sock: &Rc<Socket>, out: &mut BytesMut) -> Result<()> {
+        self.handle_waiting(out)?;
+        while !self.closing && self.waiting.is_none() {
+            let frame = match websocket::parse_frame(&mut self.recv_buf, MAX_MESSAGE_SIZE)? {
+                Some(frame) => frame,
+                None => break,
+            };
+            match frame.opcode {
+                Opcode::Ping => websocket::format_frame(out, Opcode::Pong, &frame.payload),
+                Opcode::Pong => {}
+                Opcode::Close => {
+                    // Echo the status code of the client.
+                    let code = frame.payload.get(..2).unwrap_or_default();
+                    websocket::format_frame(out, Opcode::Close, code);
+                    self.closing = true;
+                }
+                Opcode::Text | Opcode::Binary => {
+                    if self.message.is_some() {
+                        return Err(HiisiError::ProtocolError(
+                            "Expected a continuation frame".to_string(),
+                        ));
+                    }
+                    if frame.fin {
+                        self.handle_message(sock, frame.opcode, &frame.payload, out)?;
+                    } else {
+                        self.message = Some((frame.opcode, BytesMut::from(&frame.payload[..])));
+                    }
+                }
+                Opcode::Continuation => {
+                    let (opcode, mut data) = self.message.take().ok_or_else(|| {
+                        HiisiError::ProtocolError("Unexpected continuation frame".to_string())
+                    })?;
+                    if data.len() + frame.payload.len() > MAX_MESSAGE_SIZE {
+                        return Err(HiisiError::ProtocolError(format!(
+                            "WebSocket message exceeds the limit of {} bytes",
+                            MAX_MESSAGE_SIZE
+                        )));
+                    }
+                    data.extend_from_slice(&frame.payload);
+                    if frame.fin {
+                        self.handle_message(sock, opcode, &data, out)?;
+                    } else {
+                        self.message = Some((opcode, data));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_message(
+        &mut self,
+        sock: &Rc<Socket>,
+        opcode: Opcode,
+        data: &[u8],
+        out: &mut BytesMut,
+    ) -> Result<()> {
+        if opcode != Opcode::Text {
+            return Err(HiisiError::ProtocolError(
+                "Only text messages are supported".to_string(),
+            ));
+        }
+        let msg = ws::parse_client_msg(data)?;
+        let write = match &msg {
+            ws::ClientMsg::Request { request, .. } => self.session.pending_write(request),
+            _ => None,
+        };
+        match (msg, write) {
+            (
+                ws::ClientMsg::Request {
+                    request_id,
+                    request,
+                },
+                Some(write),
+            ) => {
+                log::trace!("Queueing write until the write transaction finishes");
+                let manager = &self.session.manager;
+                let deadline = manager.now() + manager.timeouts().busy;
+                self.waiting = Some(WaitingRequest {
+                    sock: sock.clone(),
+                    request_id,
+                    request,
+                    write,
+                    deadline,
+                });
+                Ok(())
+            }
+            (msg, _) => reply(out, &self.session.handle_msg(msg)?),
+        }
+    }
+
+    /// Handle the waiting request if it no longer has to wait, or fail it if
+    /// it has waited for longer than the busy timeout.
+    fn handle_waiting(&mut self, out: &mut BytesMut) -> Result<()> {
+        let waiting = match self.waiting.take() {
+            Some(waiting) => waiting,
+            None => return Ok(()),
+        };
+        let msg = if !waiting.write.must_wait(&self.session.manager) {
+            self.session.handle_msg(ws::ClientMsg::Request {
+                request_id: waiting.request_id,
+                request: waiting.request,
+            })?
+        } else if self.session.manager.now() >= waiting.deadline {
+            ws::ServerMsg::ResponseError {
+                request_id: waiting.request_id,
+                error: executor::to_proto_error(&database::busy_error()),
+            }
+        } else {
+            self.waiting = Some(waiting);
+            return Ok(());
+        };
+        reply(out, &msg)
+    }
+
+    /// Returns the socket of the waiting request if the request can be
+    /// handled now, which is when no replies are being sent.
+    fn resumable(&self) -> Option<Rc<Socket>> {
+        match &self.waiting {
+            Some(waiting) if self.send_buf.is_empty() => Some(waiting.sock.clone()),
+            _ => None,
+        }
+    }
+}
+
+fn reply(out: &mut BytesMut, msg: &ws::ServerMsg) -> Result<()> {
+    websocket::format_frame(out, Opcode::Text, &proto::format_msg(msg)?);
+    Ok(())
+}
+
+/// Accept a WebSocket upgrade request and start serving a session on the
+/// connection. The receive buffer holds the data that the client sent after
+/// the upgrade request.
+pub(crate) fn serve<T>(
+    io: &mut IO<T>,
+    sock: Rc<Socket>,
+    session: Session,
+    key: &[u8],
+    recv_buf: BytesMut,
+) {
+    let handshake = websocket::format_handshake_response(key, subprotocol(session.version));
+    let socket = SessionSocket {
+        session,
+        recv_buf,
+        message: None,
+        send_buf: handshake.clone(),
+        closing: false,
+        waiting: None,
+    };
+    io.context()
+        .sessions
+        .borrow_mut()
+        .insert(sock.as_raw_fd(), socket);
+    let n = handshake.len();
+    io.send(sock, handshake, n, on_send);
+}
+
+fn on_recv<T>(io: &mut IO<T>, sock: Rc<Socket>, buf: &[u8], n: usize) {
+    let sockfd = sock.as_raw_fd();
+    if n == 0 {
+        log::trace!("Client closed WebSocket connection");
+        io.context().sessions.borrow_mut().remove(&sockfd);
+        io.close(sock);
+        return;
+    }
+    let mut sessions = io.context().sessions.borrow_mut();
+    let socket = sessions.get_mut(&sockfd).unwrap();
+    socket.recv_buf.extend_from_slice(&buf[..n]);
+    drop(sessions);
+    process(io, sock);
+}
+
+/// Process the received frames and send the replies, if any, or wait for
+/// more data from the client.
+fn process<T>(io: &mut IO<T>, sock: Rc<Socket>) {
+    let sockfd = sock.as_raw_fd();
+    let mut sessions = io.context().sessions.borrow_mut();
+    let socket = sessions.get_mut(&sockfd).unwrap();
+    let mut out = BytesMut::new();
+    if let Err(err) = socket.process_frames(&sock, &mut out) {
+        log::debug!("Closing WebSocket connection: {}", err);
+        websocket::format_close_frame(&mut out, websocket::CLOSE_PROTOCOL_ERROR, &err.to_string());
+        socket.closing = true;
+    }
+    if out.is_empty() {
+        let waiting = socket.waiting.is_some();
+        drop(sessions);
+        // A waiting request is resumed by `process_waiting`.
+        if !waiting {
+            io.recv(sock, on_recv);
+        }
+        return;
+    }
+    socket.send_buf = out.freeze();
+    let buf = socket.send_buf.clone();
+    drop(sessions);
+    let n = buf.len();
+    io.send(sock, buf, n, on_send);
+    // The requests may have finished a write transaction that other requests
+    // wait for.
+    server::process_write_queue(io);
+}
+
+/// Handle the waiting requests of sessions that no longer have to wait, and
+/// fail the ones that have waited for longer than the busy timeout.
+pub(crate) fn process_waiting<T>(io: &mut IO<T>) {
+    let socks: Vec<Rc<Socket>> = io
+        .context()
+        .sessions
+        .borrow()
+        .values()
+        .filter_map(SessionSocket::resumable)
+        .collect();
+    for sock in socks {
+        // Handling a request may have resumed another session already.
+        let resumable = io
+            .context()
+            .sessions
+            .borrow()
+            .get(&sock.as_raw_fd())
+            .and_then(SessionSocket::resumable)
+            .is_some();
+        if resumable {
+            process(io, sock);
+        }
+    }
+}
+
+fn on_send<T>(io: &mut IO<T>, sock: Rc<Socket>, n: usize) {
+    let sockfd = sock.as_raw_fd();
+    if n == 0 {
+        log::trace!("Failed to send to WebSocket connection, closing it");
+        io.context().sessions.borrow_mut().remove(&sockfd);
+        io.close(sock);
+        // Closing the session released the write transactions of its
+        // streams.
+        server::process_write_queue(io);
+        return;
+    }
+    let mut sessions = io.context().sessions.borrow_mut();
+    let socket = sessions.get_mut(&sockfd).unwrap();
+    if n < socket.send_buf.len() {
+        // Partial write, send the rest.
+        socket.send_buf = socket.send_buf.slice(n..);
+        let buf = socket.send_buf.clone();
+        drop(sessions);
+        let n = buf.len();
+        io.send(sock, buf, n, on_send);
+        return;
+    }
+    if socket.closing {
+        sessions.remove(&sockfd);
+        drop(sessions);
+        io.close(sock);
+        return;
+    }
+    socket.send_buf = Bytes::new();
+    drop(sessions);
+    // Frames that were received together with the ones we just replied to
+    // may still be waiting in the receive buffer.
+    process(io, sock);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{temp_manager, TempManager};
+
+    fn request(
+        session: &mut Session,
+        request_id: i32,
+        request: serde_json::Value,
+    ) -> ws::ServerMsg {
+        let msg = serde_json::json!({
+            "type": "request",
+            "request_id": request_id,
+            "request": request,
+        });
+        let msg = ws::parse_client_msg(msg.to_string().as_bytes()).unwrap();
+        session.handle_msg(msg).unwrap()
+    }
+
+    /// Returns a session along with the manager that owns its database, which
+    /// must be kept alive for as long as the session is used.
+    fn temp_session(version: proto::Version) -> (TempManager, Session) {
+        let manager = temp_manager();
+        let session = Session::new(manager.clone(), "test".to_string(), version);
+        (manager, session)
+    }
+
+    #[test]
+    fn requires_hello() {
+        let (_manager, mut session) = temp_session(proto::Version::Hrana3);
+        let msg = ws::parse_client_msg(
+            br#"{"type":"request","request_id":1,"request":{"type":"open_stream","stream_id":1}}"#,
+        )
+        .unwrap();
+        assert!(session.handle_msg(msg).is_err());
+    }
+
+    #[test]
+    fn multiplexed_streams() {
+        let (_manager, mut session) = temp_session(proto::Version::Hrana3);
+        let hello = ws::parse_client_msg(br#"{"type":"hello","jwt":null}"#).unwrap();
+        assert!(matches!(
+            session.handle_msg(hello).unwrap(),
+            ws::ServerMsg::HelloOk {}
+        ));
+        for stream_id in [1, 2] {
+            let resp = request(
+                &mut session,
+                stream_id,
+                serde_json::json!({"type": "open_stream", "stream_id": stream_id}),
+            );
+            assert!(matches!(resp, ws::ServerMsg::ResponseOk { .. }));
+        }
+        request(
+            &mut session,
+            3,
+            serde_json::json!({"type": "store_sql", "sql_id": 1, "sql": "SELECT 42"}),
+        );
+        request(
+            &mut session,
+            4,
+            serde_json::json!({"type": "execute", "stream_id": 1, "stmt": {"sql": "BEGIN"}}),
+        );
+
+        // Stored SQL texts are shared by the streams, but transactions are not.
+        let resp = request(
+            &mut session,
+            5,
+            serde_json::json!({"type": "execute", "stream_id": 2, "stmt": {"sql_id": 1}}),
+        );
+        match resp {
+            ws::ServerMsg::ResponseOk {
+                request_id: 5,
+                response: ws::Response::Execute(resp),
+            } => assert_eq!(resp.result.rows.len(), 1),
+            resp => panic!("unexpected response {:?}", resp),
+        }
+        for (stream_id, is_autocommit) in [(1, false), (2, true)] {
+            let resp = request(
+                &mut session,
+                6,
+                serde_json::json!({"type": "get_autocommit", "stream_id": stream_id}),
+            );
+            match resp {
+                ws::ServerMsg::ResponseOk {
+                    response: ws::Response::GetAutocommit(resp),
+                    ..
+                } => assert_eq!(resp.is_autocommit, is_autocommit),
+                resp => panic!("unexpected response {:?}", resp),
+            }
+        }
+
+        request(
+            &mut session,
+            7,
+            serde_json::json!({"type": "close_stream", "stream_id": 1}),
+        );
+        let resp = request(
+            &mut session,
+            8,
+            serde_json::json!({"type": "execute", "stream_id": 1, "stmt": {"sql": "SELECT 1"}}),
+        );
+        assert!(matches!(
+            resp,
+            ws::ServerMsg::ResponseError { request_id: 8, .. }
+        ));
+    }
+
+    #[test]
+    fn fetch_cursor() {
+        let (_manager, mut session) = temp_session(proto::Version::Hrana3);
+        session.hello_received = true;
+        request(
+            &mut session,
+            1,
+            serde_json::json!({"type": "open_stream", "stream_id": 1}),
+        );
+        let batch =
+            serde_json::json!({"steps": [{"stmt": {"sql": "SELECT 1 UNION ALL SELECT 2"}}]});
+        request(
+            &mut session,
+            2,
+            serde_json::json!({"type": "open_cursor", "stream_id": 1, "cursor_id": 1, "batch": batch}),
+        );
+        let mut entry_count = 0;
+        loop {
+            let resp = request(
+                &mut session,
+                3,
+                serde_json::json!({"type": "fetch_cursor", "cursor_id": 1, "max_count": 2}),
+            );
+            let resp = match resp {
+                ws::ServerMsg::ResponseOk {
+                    response: ws::Response::FetchCursor(resp),
+                    ..
+                } => resp,
+                resp => panic!("unexpected response {:?}", resp),
+            };
+            entry_count += resp.entries.len();
+            if resp.done {
+                break;
+            }
+        }
+        // step_begin, two rows and step_end.
+        assert_eq!(entry_count, 4);
+    }
+
+    #[test]
+    fn track_writers() {
+        let (manager, mut session) = temp_session(proto::Version::Hrana3);
+        session.hello_received = true;
+        for stream_id in [1, 2] {
+            request(
+                &mut session,
+                stream_id,
+                serde_json::json!({"type": "open_stream", "stream_id": stream_id}),
+            );
+        }
+        let execute = |stream_id, sql| serde_json::json!({"type": "execute", "stream_id": stream_id, "stmt": {"sql": sql}});
+        request(&mut session, 3, execute(1, "CREATE TABLE t (x)"));
+        request(&mut session, 4, execute(1, "BEGIN IMMEDIATE"));
+        assert!(manager.has_other_writer("test", None));
+
+        // Writes of other streams wait, but reads and the writer do not.
+        let must_wait = |session: &Session, stream_id, sql| {
+            let stmt = proto::Stmt::new(sql, true);
+            session
+                .pending_write(&ws::Request::Execute(ws::ExecuteReq { stream_id, stmt }))
+                .is_some()
+        };
+        assert!(must_wait(&session, 2, "INSERT INTO t VALUES (1)"));
+        assert!(!must_wait(&session, 2, "SELECT * FROM t"));
+        assert!(!must_wait(&session, 1, "INSERT INTO t VALUES (1)"));
+
+        // Closing the stream releases its write transaction.
+        request(
+            &mut session,
+            5,
+            serde_json::json!({"type": "close_stream", "stream_id": 1}),
+        );
+        assert!(!manager.has_other_writer("test", None));
+        assert!(!must_wait(&session, 2, "INSERT INTO t VALUES (1)"));
+
+        // So does closing the session.
+        request(&mut session, 6, execute(2, "BEGIN IMMEDIATE"));
+        assert!(manager.has_other_writer("test", None));
+        drop(session);
+        assert!(!manager.has_other_writer("test", None));
+    }
+}
@@ -0,0 +1,152 @@
+//! Client streams.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::database::Connection;
+use crate::{HiisiError, Result};
+
+// Maximum number of SQL texts a stream can store.
+const MAX_STORED_SQL_COUNT: usize = 150;
+
+// Maximum total size of SQL texts a stream can store in bytes.
+const MAX_STORED_SQL_SIZE: usize = 5 * 1024 * 1024;
+
+/// A stream is a session of a client with a database.
+///
+/// Requests on the same stream are executed on the same SQLite connection,
+/// and can refer to SQL texts that were previously stored on the stream.
+pub struct Stream {
+    pub conn: Connection,
+
+    /// SQL texts that requests on the stream can refer to.
+    sqls: Rc<SqlStore>,
+}
+
+impl Stream {
+    pub fn new(conn: Connection) -> Self {
+        Self::with_sqls(conn, Rc::new(SqlStore::default()))
+    }
+
+    /// Create a stream that shares stored SQL texts with other streams. Over
+    /// WebSocket, SQL texts are stored per connection instead of per stream.
+    pub fn with_sqls(conn: Connection, sqls: Rc<SqlStore>) -> Self {
+        Self { conn, sqls }
+    }
+
+    /// Returns `true` if there is no transaction open on the stream.
+    pub fn is_autocommit(&self) -> bool {
+        self.conn.is_autocommit()
+    }
+
+    /// Roll back the transaction that is open on the stream, if any.
+    pub fn rollback(&self) -> Result<()> {
+        if self.is_autocommit() {
+            return Ok(());
+        }
+        self.conn.prepare("ROLLBACK")?.step()?;
+        Ok(())
+    }
+
+    pub fn store_sql(&self, sql_id: i32, sql: String) -> Result<()> {
+        self.sqls.store_sql(sql_id, sql)
+    }
+
+    pub fn close_sql(&self, sql_id: i32) {
+        self.sqls.close_sql(sql_id)
+    }
+
+    pub fn get_sql(&self, sql_id: i32) -> Result<String> {
+        self.sqls.get_sql(sql_id)
+    }
+}
+
+/// SQL texts stored with `store_sql`, keyed by SQL id.
+#[derive(Default)]
+pub struct SqlStore {
+    sqls: RefCell<HashMap<i32, String>>,
+
+    /// Total size of stored SQL texts in bytes.
+    sqls_size: Cell<usize>,
+}
+
+impl SqlStore {
+    pub fn store_sql(&self, sql_id: i32, sql: String) -> Result<()> {
+        let mut sqls = self.sqls.borrow_mut();
+        if sqls.contains_key(&sql_id) {
+            return Err(HiisiError::ProtocolError(format!(
+                "SQL text {} is already stored",
+                sql_id
+            )));
+        }
+        if sqls.len() >= MAX_STORED_SQL_COUNT {
+            return Err(HiisiError::SqlStoreFull(format!(
+                "at most {} SQL texts can be stored per stream",
+                MAX_STORED_SQL_COUNT
+            )));
+        }
+        let sqls_size = self.sqls_size.get() + sql.len();
+        if sqls_size > MAX_STORED_SQL_SIZE {
+            return Err(HiisiError::SqlStoreFull(format!(
+                "at most {} bytes of SQL text can be stored per stream",
+                MAX_STORED_SQL_SIZE
+            )));
+        }
+        self.sqls_size.set(sqls_size);
+        sqls.insert(sql_id, sql);
+        Ok(())
+    }
+
+    /// Remove a stored SQL text. Closing a SQL text that is not stored is not
+    /// an error.
+    pub fn close_sql(&self, sql_id: i32) {
+        if let Some(sql) = self.sqls.borrow_mut().remove(&sql_id) {
+            self.sqls_size.set(self.sqls_size.get() - sql.len());
+        }
+    }
+
+    pub fn get_sql(&self, sql_id: i32) -> Result<String> {
+        self.sqls
+            .borrow()
+            .get(&sql_id)
+            .cloned()
+            .ok_or(HiisiError::SqlNotFound(sql_id))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::Path;
+
+    fn memory_stream() -> Stream {
+        Stream::new(Connection::open(Path::new(":memory:")).unwrap())
+    }
+
+    #[test]
+    fn store_sql_count_limit() {
+        let stream = memory_stream();
+        for sql_id in 0..MAX_STORED_SQL_COUNT {
+            stream
+                .store_sql(sql_id as i32, "SELECT 1".to_string())
+                .unwrap();
+        }
+        let err = stream.store_sql(-1, "SELECT 1".to_string()).unwrap_err();
+        assert!(matches!(err, HiisiError::SqlStoreFull(_)));
+        stream.close_sql(0);
+        stream.store_sql(-1, "SELECT 1".to_string()).unwrap();
+    }
+
+    #[test]
+    fn store_sql_size_limit() {
+        let stream = memory_stream();
+        let sql = "x".repeat(MAX_STORED_SQL_SIZE / 2);
+        stream.store_sql(1, sql.clone()).unwrap();
+        stream.store_sql(2, sql.clone()).unwrap();
+        let err = stream.store_sql(3, "x".to_string()).unwrap_err();
+        assert!(matches!(err, HiisiError::SqlStoreFull(_)));
+        stream.close_sql(2);
+        stream.store_sql(3, "x".to_string()).unwrap();
+    }
+}
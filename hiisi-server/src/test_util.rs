@@ -0,0 +1,70 @@
+//! Helpers that the tests of many modules share.
+
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use bytes::BytesMut;
+
+use crate::executor::Request;
+use crate::io::Clock;
+use crate::manager::{ResourceManager, StreamTimeouts};
+use crate::pipeline::Pipeline;
+use crate::proto;
+use crate::Result;
+
+/// A resource manager with a database named `test` in a temporary directory,
+/// which is removed when the manager is dropped.
+pub struct TempManager {
+    manager: Rc<ResourceManager>,
+    path: PathBuf,
+}
+
+impl Deref for TempManager {
+    type Target = Rc<ResourceManager>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.manager
+    }
+}
+
+impl Drop for TempManager {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+pub fn temp_manager() -> TempManager {
+    temp_manager_with_timeouts(StreamTimeouts::default())
+}
+
+pub fn temp_manager_with_timeouts(timeouts: StreamTimeouts) -> TempManager {
+    temp_manager_with_clock(timeouts, Clock::default())
+}
+
+pub fn temp_manager_with_clock(timeouts: StreamTimeouts, clock: Clock) -> TempManager {
+    let path = std::env::temp_dir().join(format!("hiisi-test-{}", uuid::Uuid::new_v4()));
+    let manager = ResourceManager::with_clock(&path, timeouts, clock);
+    manager.create_database("test").unwrap();
+    TempManager {
+        manager: Rc::new(manager),
+        path,
+    }
+}
+
+/// Returns a request that executes a statement and returns its rows.
+pub fn execute(sql: &str) -> proto::StreamRequest {
+    let stmt = proto::Stmt::new(sql, true);
+    proto::StreamRequest::Execute(proto::ExecuteStreamReq { stmt })
+}
+
+/// Executes a pipeline and returns its response body.
+pub fn execute_pipeline(
+    manager: &Rc<ResourceManager>,
+    req: Request,
+) -> Result<proto::PipelineRespBody> {
+    let mut pipeline = Pipeline::open(manager.clone(), req, proto::Encoding::Json)?;
+    let mut body = BytesMut::new();
+    pipeline.write_body(&mut body, usize::MAX)?;
+    Ok(serde_json::from_slice(&body).unwrap())
+}
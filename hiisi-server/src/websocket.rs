@@ -0,0 +1,225 @@
+//! WebSocket protocol (RFC 6455) handshake and framing.
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use bytes::{Buf, Bytes, BytesMut};
+use sha1::{Digest, Sha1};
+
+use crate::{HiisiError, Result};
+
+// The GUID that is appended to the client key to compute the accept key.
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl Opcode {
+    fn from_u8(opcode: u8) -> Option<Self> {
+        match opcode {
+            0x0 => Some(Opcode::Continuation),
+            0x1 => Some(Opcode::Text),
+            0x2 => Some(Opcode::Binary),
+            0x8 => Some(Opcode::Close),
+            0x9 => Some(Opcode::Ping),
+            0xa => Some(Opcode::Pong),
+            _ => None,
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            Opcode::Continuation => 0x0,
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xa,
+        }
+    }
+
+    pub fn is_control(self) -> bool {
+        matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+    }
+}
+
+/// The close status code for protocol violations.
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+
+#[derive(Debug)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: Opcode,
+    pub payload: Bytes,
+}
+
+/// Compute the `Sec-WebSocket-Accept` value for a `Sec-WebSocket-Key`.
+pub fn accept_key(key: &[u8]) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key);
+    hasher.update(WEBSOCKET_GUID.as_bytes());
+    STANDARD.encode(hasher.finalize())
+}
+
+/// Format the response that accepts a WebSocket upgrade request.
+pub fn format_handshake_response(key: &[u8], protocol: &str) -> Bytes {
+    format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\nSec-WebSocket-Protocol: {}\r\n\r\n",
+        accept_key(key),
+        protocol
+    )
+    .into()
+}
+
+/// Parse a frame sent by a client from the beginning of a buffer.
+///
+/// Returns `None` if the buffer does not contain a complete frame yet. A
+/// parsed frame is removed from the buffer, and its payload is unmasked.
+pub fn parse_frame(buf: &mut BytesMut, max_payload_len: usize) -> Result<Option<Frame>> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let fin = buf[0] & 0x80 != 0;
+    if buf[0] & 0x70 != 0 {
+        return Err(HiisiError::ProtocolError(
+            "WebSocket frame has reserved bits set".to_string(),
+        ));
+    }
+    let opcode = Opcode::from_u8(buf[0] & 0x0f).ok_or_else(|| {
+        HiisiError::ProtocolError(format!("Unknown WebSocket opcode {}", buf[0] & 0x0f))
+    })?;
+    if buf[1] & 0x80 == 0 {
+        return Err(HiisiError::ProtocolError(
+            "WebSocket frame from client is not masked".to_string(),
+        ));
+    }
+    let (payload_len, header_len) = match buf[1] & 0x7f {
+        126 if buf.len() < 4 => return Ok(None),
+        126 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+        127 if buf.len() < 10 => return Ok(None),
+        127 => (u64::from_be_bytes(buf[2..10].try_into().unwrap()), 10),
+        len => (len as u64, 2),
+    };
+    if opcode.is_control() && (!fin || payload_len > 125) {
+        return Err(HiisiError::ProtocolError(
+            "WebSocket control frame is fragmented or too long".to_string(),
+        ));
+    }
+    if payload_len > max_payload_len as u64 {
+        return Err(HiisiError::ProtocolError(format!(
+            "WebSocket frame of {} bytes exceeds the limit of {} bytes",
+            payload_len, max_payload_len
+        )));
+    }
+    let payload_len = payload_len as usize;
+    if buf.len() < header_len + 4 + payload_len {
+        return Ok(None);
+    }
+    let mask: [u8; 4] = buf[header_len..header_len + 4].try_into().unwrap();
+    buf.advance(header_len + 4);
+    let mut payload = buf.split_to(payload_len);
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Some(Frame {
+        fin,
+        opcode,
+        payload: payload.freeze(),
+    }))
+}
+
+/// Append an unfragmented frame sent by the server to a buffer.
+pub fn format_frame(buf: &mut BytesMut, opcode: Opcode, payload: &[u8]) {
+    buf.extend_from_slice(&[0x80 | opcode.as_u8()]);
+    let len = payload.len();
+    if len < 126 {
+        buf.extend_from_slice(&[len as u8]);
+    } else if len <= u16::MAX as usize {
+        buf.extend_from_slice(&[126]);
+        buf.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        buf.extend_from_slice(&[127]);
+        buf.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+    buf.extend_from_slice(payload);
+}
+
+/// Append a close frame to a buffer.
+pub fn format_close_frame(buf: &mut BytesMut, code: u16, reason: &str) {
+    let mut payload = Vec::with_capacity(2 + reason.len());
+    payload.extend_from_slice(&code.to_be_bytes());
+    // The payload of a control frame is at most 125 bytes.
+    let mut reason_len = reason.len().min(123);
+    while !reason.is_char_boundary(reason_len) {
+        reason_len -= 1;
+    }
+    payload.extend_from_slice(&reason.as_bytes()[..reason_len]);
+    format_frame(buf, Opcode::Close, &payload);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn client_frame(opcode: Opcode, payload: &[u8]) -> BytesMut {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut buf = BytesMut::new();
+        format_frame(&mut buf, opcode, payload);
+        let header_len = buf.len() - payload.len();
+        buf[1] |= 0x80;
+        let masked: Vec<u8> = buf
+            .split_off(header_len)
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| byte ^ mask[i % 4])
+            .collect();
+        buf.extend_from_slice(&mask);
+        buf.extend_from_slice(&masked);
+        buf
+    }
+
+    #[test]
+    fn accept_key_rfc_example() {
+        assert_eq!(
+            accept_key(b"dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn parse_frames() {
+        let long_payload = vec![b'x'; 1000];
+        let mut buf = client_frame(Opcode::Text, b"hello");
+        buf.extend_from_slice(&client_frame(Opcode::Binary, &long_payload));
+        let last = client_frame(Opcode::Ping, b"");
+
+        // The last frame arrives in two parts.
+        buf.extend_from_slice(&last[..1]);
+        let frame = parse_frame(&mut buf, 4096).unwrap().unwrap();
+        assert_eq!(frame.opcode, Opcode::Text);
+        assert_eq!(&frame.payload[..], b"hello");
+        let frame = parse_frame(&mut buf, 4096).unwrap().unwrap();
+        assert_eq!(frame.opcode, Opcode::Binary);
+        assert_eq!(&frame.payload[..], &long_payload[..]);
+        assert!(parse_frame(&mut buf, 4096).unwrap().is_none());
+        buf.extend_from_slice(&last[1..]);
+        let frame = parse_frame(&mut buf, 4096).unwrap().unwrap();
+        assert_eq!(frame.opcode, Opcode::Ping);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn reject_invalid_frames() {
+        let mut buf = BytesMut::new();
+        format_frame(&mut buf, Opcode::Text, b"unmasked");
+        assert!(parse_frame(&mut buf, 4096).is_err());
+
+        let mut buf = client_frame(Opcode::Text, &[0; 100]);
+        assert!(parse_frame(&mut buf, 10).is_err());
+    }
+}
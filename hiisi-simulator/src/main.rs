@@ -7,9 +7,11 @@ use socket2::{Domain, Socket, Type};
 use std::os::fd::AsRawFd;
 
 use std::path::Path;
+use std::time::Duration;
 
 const TEST_DATABASE_NAME: &str = "test";
 const TEST_DATABASE_HOST: &str = "test.localhost";
+const SERVER_ADDR: &str = "127.0.0.1:8080";
 
 pub struct UserData {
     rng: RefCell<ChaCha8Rng>,
@@ -33,13 +35,20 @@ pub fn main() {
     let user_data = UserData {
         rng: RefCell::new(rng),
     };
-    let manager = Rc::new(hiisi::manager::ResourceManager::new(Path::new("data")));
+    // The server and the IO loop share a virtual clock, which the simulation
+    // advances, so that timeouts depend only on the seed.
+    let clock = hiisi::io::Clock::default();
+    let manager = Rc::new(hiisi::manager::ResourceManager::with_clock(
+        Path::new("data"),
+        hiisi::manager::StreamTimeouts::default(),
+        clock.clone(),
+    ));
     // TODO: Use the admin interface to create the database as part of simulation.
     manager.create_database(TEST_DATABASE_NAME).unwrap();
     let ctx = Context::new(manager, user_data);
-    let mut io = hiisi::server::IO::new(ctx);
+    let mut io = hiisi::server::IO::with_clock(ctx, clock);
 
-    let server_addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+    let server_addr: std::net::SocketAddr = SERVER_ADDR.parse().unwrap();
     let server_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
     let client_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
 
@@ -52,9 +61,17 @@ pub fn main() {
     // Main simulation loop.
     loop {
         io.run_once();
+        advance_clock(&io);
     }
 }
 
+/// Advance the virtual clock by a random amount of time, as if the loop had
+/// taken that long to run.
+fn advance_clock(io: &IO) {
+    let micros = io.context().user_data.rng.borrow_mut().gen_range(0..=1000);
+    io.clock().advance(Duration::from_micros(micros));
+}
+
 fn on_client_connect(io: &mut IO, sock: Rc<socket2::Socket>, client_addr: socket2::SockAddr) {
     let sockfd = sock.as_raw_fd();
     log::trace!("Client is connected to {}", sockfd);
@@ -147,7 +164,12 @@ fn on_client_recv_fuzz(io: &mut IO, socket: Rc<socket2::Socket>, buf: &[u8], n:
         println!("Error: {:?} -> {}", resp, body);
         assert_eq!(resp.code.unwrap(), 400);
     }
-    perform_client_req(io, socket);
+    // The server closes the connection because it cannot tell where the next
+    // request starts, so the client reconnects.
+    io.close(socket);
+    let server_addr: std::net::SocketAddr = SERVER_ADDR.parse().unwrap();
+    let client_sock = Rc::new(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap());
+    io.connect(client_sock, server_addr.into(), on_client_connect);
 }
 
 fn init_logger() {